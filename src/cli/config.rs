@@ -0,0 +1,178 @@
+//! `--config` files: TOML holding defaults for `msl run`'s options, keyed by
+//! flag name, e.g.
+//!
+//! ```toml
+//! accept-language = "de-DE,de;q=0.9"
+//! allow-hosts = ["example.com", "*.cdn.example.com"]
+//! selector-retry-delay = 2.5
+//! ```
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use super::{parse_basic_auth, parse_resolve, parse_seconds, read_pem_file, RunOptions};
+
+/// Option values a config file may set. Relative paths are relative to the
+/// file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    dedupe: Option<bool>,
+    output_dir: Option<PathBuf>,
+    max_file_size: Option<u64>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    write_buffer_size: Option<usize>,
+    fsync: Option<bool>,
+    on_conflict: Option<String>,
+    mirror_paths: Option<bool>,
+    concurrency: Option<usize>,
+    max_downloads: Option<usize>,
+    adaptive_concurrency: Option<bool>,
+    parallel_groups: Option<usize>,
+    accept_language: Option<String>,
+    max_redirects: Option<usize>,
+    same_host_redirects: Option<bool>,
+    follow_meta_refresh: Option<bool>,
+    normalize_html: Option<bool>,
+    allow_hosts: Option<Vec<String>>,
+    deny_hosts: Option<Vec<String>>,
+    same_host: Option<bool>,
+    iframe_depth: Option<usize>,
+    fail_fast: Option<bool>,
+    basic_auth: Option<String>,
+    bearer: Option<String>,
+    include_url_regex: Option<Vec<String>>,
+    exclude_url_regex: Option<Vec<String>>,
+    sniff: Option<bool>,
+    checksum_verify: Option<bool>,
+    strict: Option<bool>,
+    dump_failures: Option<PathBuf>,
+    max_pages: Option<usize>,
+    selector_timeout_retries: Option<u32>,
+    selector_retry_delay: Option<f64>,
+    deadline_secs: Option<u64>,
+    name_template: Option<String>,
+    insecure: Option<bool>,
+    ca_cert: Option<Vec<PathBuf>>,
+    resolve: Option<Vec<String>>,
+    page_cache: Option<PathBuf>,
+    user_data_dir: Option<PathBuf>,
+    trace_http: Option<bool>,
+    html_cache_size: Option<usize>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config = Self::parse(&source).with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+        Ok(config)
+    }
+
+    fn parse(source: &str) -> Result<Self> {
+        Ok(toml::from_str(source)?)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        for path in [&mut self.output_dir, &mut self.page_cache, &mut self.user_data_dir, &mut self.dump_failures].into_iter().flatten() {
+            *path = dir.join(&*path);
+        }
+        for path in self.ca_cert.iter_mut().flatten() {
+            *path = dir.join(&*path);
+        }
+    }
+}
+
+impl RunOptions {
+    /// Fills in options from `config` that `matches`, the parsed `run`
+    /// arguments, don't give on the command line.
+    pub(super) fn apply_config(&mut self, config: ConfigFile, matches: &ArgMatches) -> Result<()> {
+        let from_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        macro_rules! apply {
+            ($key:ident => $field:ident, $convert:expr) => {
+                if let Some(value) = config.$key.filter(|_| !from_command_line(stringify!($field))) {
+                    self.$field = $convert(value)?;
+                }
+            };
+            ($key:ident, $convert:expr) => { apply!($key => $key, $convert) };
+            (as is: $($key:ident),* $(,)?) => {
+                $(if let Some(value) = config.$key.filter(|_| !from_command_line(stringify!($key))) {
+                    self.$key = value.into();
+                })*
+            };
+        }
+
+        apply!(as is:
+            dedupe, output_dir, max_file_size, min_width, min_height, write_buffer_size, fsync, mirror_paths, concurrency, max_downloads, adaptive_concurrency, parallel_groups, accept_language, max_redirects,
+            same_host_redirects, follow_meta_refresh, normalize_html, allow_hosts, deny_hosts, same_host, iframe_depth, fail_fast, bearer, sniff, checksum_verify, strict, dump_failures,
+            max_pages, selector_timeout_retries, deadline_secs, insecure, page_cache, user_data_dir,
+            trace_http, html_cache_size,
+        );
+        apply!(basic_auth, |value: String| parse_basic_auth(&value).map(Some).map_err(anyhow::Error::msg));
+        apply!(include_url_regex => include_urls, regexes);
+        apply!(exclude_url_regex => exclude_urls, regexes);
+        apply!(selector_retry_delay, |value: f64| parse_seconds(&value.to_string()).map_err(anyhow::Error::msg));
+        apply!(on_conflict, |value: String| super::OnConflict::from_str(&value, false).map_err(anyhow::Error::msg));
+        apply!(name_template, |value: String| super::NameTemplate::parse(&value).map(Some));
+        apply!(ca_cert => ca_certs, |paths: Vec<PathBuf>| {
+            paths.iter().map(|path| read_pem_file(&path.to_string_lossy()).map_err(anyhow::Error::msg)).collect::<Result<_>>()
+        });
+        apply!(resolve, |values: Vec<String>| {
+            values.iter().map(|value| parse_resolve(value).map_err(anyhow::Error::msg)).collect::<Result<_>>()
+        });
+        Ok(())
+    }
+}
+
+fn regexes(patterns: Vec<String>) -> Result<Vec<regex::Regex>> {
+    patterns.iter().map(|pattern| regex::Regex::new(pattern).map_err(Into::into)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let config = ConfigFile::parse(
+            r#"
+# Defaults for nightly runs
+accept-language = "de-DE,de;q=0.9"  # German first
+max-pages = 1_000
+selector-retry-delay = 2.5
+allow-hosts = [
+    "example.com",
+    '*.cdn.example.com',
+]
+same-host-redirects = true
+bearer = "tok\"en!"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.accept_language.as_deref(), Some("de-DE,de;q=0.9"));
+        assert_eq!(config.max_pages, Some(1000));
+        assert_eq!(config.selector_retry_delay, Some(2.5));
+        assert_eq!(config.allow_hosts.unwrap(), ["example.com", "*.cdn.example.com"]);
+        assert_eq!(config.same_host_redirects, Some(true));
+        assert_eq!(config.bearer.as_deref(), Some("tok\"en!"));
+        assert_eq!(config.fsync, None);
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        let error = |source: &str| format!("{:#}", ConfigFile::parse(source).unwrap_err());
+
+        assert!(error("max-page = 3").contains("unknown field `max-page`"), "{}", error("max-page = 3"));
+        assert!(error("max-pages = \"three\"").contains("invalid type"));
+        assert!(error("\nfsync = yes").contains("line 2"), "{}", error("\nfsync = yes"));
+        assert!(error("sniff = true\nsniff = false").contains("duplicate key"));
+        assert!(ConfigFile::parse("fsync = true true").is_err());
+        assert!(ConfigFile::parse("bearer = \"open").is_err());
+    }
+}
@@ -1,10 +1,21 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
-use tracing::{info, Level};
-use tracing_subscriber;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::io::{IsTerminal, Read};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn, Level, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
 
-use crate::{MslEngine, parse_script};
+use crate::parser::{parse_script_with_options, render_error, Credentials, MslCommand, ParseOptions};
+use crate::scraper::{RedirectPolicy, ScraperConfig, DEFAULT_ACCEPT_LANGUAGE};
+use crate::engine::{CommandSelection, ConflictPolicy, NameTemplate, RunManifest};
+use crate::{EngineConfig, MslEngine, MslScript};
+
+mod config;
+mod json_log;
+
+use config::ConfigFile;
 
 #[derive(Parser)]
 #[command(name = "msl")]
@@ -13,107 +24,1205 @@ use crate::{MslEngine, parse_script};
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Write logs as one JSON object per line
+    #[arg(long, global = true)]
+    json_logs: bool,
+
+    /// Only log warnings and errors, e.g. for cron jobs
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+impl Cli {
+    /// Most detailed level of logs to write.
+    fn log_level(&self) -> Level {
+        match self.command {
+            _ if self.quiet => Level::WARN,
+            Commands::Run { verbose: true, .. } => Level::DEBUG,
+            _ => Level::INFO,
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Run an MSL script file
     Run {
-        /// Path to the MSL script file
+        /// Path to the MSL script file, or `-` to read from stdin
         #[arg(value_name = "SCRIPT")]
         script: PathBuf,
         
         /// Enable verbose output
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "quiet")]
         verbose: bool,
+
+        /// Write a JSON manifest of completed downloads to FILE, including
+        /// when the run fails or is interrupted
+        #[arg(long, value_name = "FILE")]
+        manifest: Option<PathBuf>,
+
+        /// Skip downloads already listed in the manifest FILE from an
+        /// earlier run, and record this run's downloads in it too
+        #[arg(long, value_name = "FILE", conflicts_with = "manifest")]
+        resume: Option<PathBuf>,
+
+        /// Gzip the manifest, as a --manifest or --resume FILE ending in
+        /// .gz does. Gzipped manifests are read back either way
+        #[arg(long)]
+        compress: bool,
+
+        #[command(flatten)]
+        options: Box<RunOptions>,
     },
     
     /// Parse and validate an MSL script without executing
     Parse {
-        /// Path to the MSL script file
+        /// Path to the MSL script file, or `-` to read from stdin
         #[arg(value_name = "SCRIPT")]
         script: PathBuf,
+
+        /// Print the full parsed syntax tree as JSON instead of a summary
+        #[arg(long)]
+        json: bool,
+
+        /// List only the top-level commands, with a count of what each one
+        /// nests, instead of the whole indented tree
+        #[arg(long, conflicts_with = "json")]
+        flat: bool,
+    },
+
+    /// Rewrite an MSL script in canonical form
+    Fmt {
+        /// Path to the MSL script file, or `-` to read from stdin
+        #[arg(value_name = "SCRIPT")]
+        script: PathBuf,
+
+        /// Rewrite the file in place instead of printing to stdout
+        #[arg(short, long)]
+        write: bool,
+    },
+
+    /// Create a new MSL script from a commented example
+    New {
+        /// Path of the script file to create
+        #[arg(value_name = "SCRIPT")]
+        path: PathBuf,
+
+        /// Overwrite the file if it already exists
+        #[arg(short, long)]
+        force: bool,
     },
 }
 
-pub async fn run() -> Result<()> {
-    let cli = Cli::parse();
-    
-    // Initialize logging
-    let level = if matches!(cli.command, Commands::Run { verbose: true, .. }) {
-        Level::DEBUG
+/// Engine settings for `msl run`.
+#[derive(Args, Debug)]
+struct RunOptions {
+    /// Read defaults for these options from the TOML file FILE, keyed by
+    /// flag name, e.g. `accept-language = "de"`. Flags given here win
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Remove downloaded files whose content duplicates an earlier download
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Write relative save paths under DIR instead of the working directory
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// What relative save paths (and a relative --output-dir) start from
+    #[arg(long, value_enum, default_value_t = SaveBase::Cwd)]
+    base: SaveBase,
+
+    /// Skip downloads larger than BYTES
+    #[arg(long, value_name = "BYTES")]
+    max_file_size: Option<u64>,
+
+    /// Skip images narrower than PIXELS, measured before downloading them
+    #[arg(long, value_name = "PIXELS")]
+    min_width: Option<u32>,
+
+    /// Skip images shorter than PIXELS, measured before downloading them
+    #[arg(long, value_name = "PIXELS")]
+    min_height: Option<u32>,
+
+    /// Download up to N files at once from each host
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    concurrency: usize,
+
+    /// Run at most N downloads at once across all hosts
+    #[arg(long, value_name = "N")]
+    max_downloads: Option<usize>,
+
+    /// Start with one download at a time from each host and work up to
+    /// --concurrency while the host keeps up, backing off when it answers
+    /// 429 or 5xx
+    #[arg(long)]
+    adaptive_concurrency: bool,
+
+    /// Run up to N of the script's top-level `open` groups at once. Each
+    /// group gets its own page and its own copy of the variables
+    #[arg(long, value_name = "N", default_value_t = 1, alias = "parallel-scripts")]
+    parallel_groups: usize,
+
+    /// Save media under the directories of its URL's path, e.g.
+    /// /img/2023/photo.jpg as img/2023/photo.jpg in the save path
+    #[arg(long)]
+    mirror_paths: bool,
+
+    /// What to do when a download's file already exists
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = OnConflict::Rename)]
+    on_conflict: OnConflict,
+
+    /// Write downloads to disk BYTES at a time [default: 65536]
+    #[arg(long, value_name = "BYTES")]
+    write_buffer_size: Option<usize>,
+
+    /// Flush each download to disk (fsync) before moving on, so it survives
+    /// a crash. Slower
+    #[arg(long)]
+    fsync: bool,
+
+    /// Languages to request from sites, as an Accept-Language header value
+    #[arg(long, value_name = "LANGUAGES", default_value = DEFAULT_ACCEPT_LANGUAGE)]
+    accept_language: String,
+
+    /// Follow at most N redirects per request (0 disables redirects)
+    #[arg(long, value_name = "N")]
+    max_redirects: Option<usize>,
+
+    /// Refuse redirects that lead to a different host
+    #[arg(long, conflicts_with = "max_redirects")]
+    same_host_redirects: bool,
+
+    /// Follow pages that redirect with <meta http-equiv="refresh"> after
+    /// up to five seconds
+    #[arg(long)]
+    follow_meta_refresh: bool,
+
+    /// Clean up each page's HTML (close unclosed tags, untangle misnested
+    /// ones, drop comments) before reading or saving it
+    #[arg(long)]
+    normalize_html: bool,
+
+    /// Only fetch pages and media from HOSTS, a comma-separated list in
+    /// which `*.example.com` stands for any subdomain (repeatable)
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    allow_hosts: Vec<String>,
+
+    /// Never fetch pages or media from HOSTS, given like --allow-hosts
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    deny_hosts: Vec<String>,
+
+    /// Only fetch from the host of the first page opened, unless
+    /// --allow-hosts is given
+    #[arg(long)]
+    same_host: bool,
+
+    /// Also look for media and links in documents the page embeds with
+    /// <iframe>, and in their iframes, down to N levels (0 disables)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    iframe_depth: usize,
+
+    /// Stop at the first failed download
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Log failed downloads and keep going (the default)
+    #[arg(long, conflicts_with = "fail_fast")]
+    continue_on_error: bool,
+
+    /// Send HTTP basic auth credentials with requests to the first host
+    /// the script opens
+    #[arg(long, value_name = "USER:PASS", value_parser = parse_basic_auth, conflicts_with = "bearer")]
+    basic_auth: Option<Credentials>,
+
+    /// Send a bearer token with requests to the first host the script opens
+    #[arg(long, value_name = "TOKEN")]
+    bearer: Option<String>,
+
+    /// Only download media whose URL matches REGEX (repeatable; any match
+    /// is enough)
+    #[arg(long = "include-url-regex", value_name = "REGEX", value_parser = regex::Regex::new)]
+    include_urls: Vec<regex::Regex>,
+
+    /// Never download media whose URL matches REGEX (repeatable)
+    #[arg(long = "exclude-url-regex", value_name = "REGEX", value_parser = regex::Regex::new)]
+    exclude_urls: Vec<regex::Regex>,
+
+    /// Check downloads against the Content-MD5, Digest or Content-Digest
+    /// checksums servers send, downloading a file again when it doesn't
+    /// match
+    #[arg(long)]
+    checksum_verify: bool,
+
+    /// Fail the run when a set or extract finds nothing, instead of
+    /// warning and leaving the variable empty
+    #[arg(long)]
+    strict: bool,
+
+    /// Save the HTML of each page where a set, extract, media block or
+    /// failing expect found nothing to DIR, to see what changed on the site
+    #[arg(long, value_name = "DIR")]
+    dump_failures: Option<PathBuf>,
+
+    /// Detect each download's format from its first bytes and fix the file
+    /// extension when it doesn't match
+    #[arg(long)]
+    sniff: bool,
+
+    /// Fetch at most N pages over the whole run, then stop
+    #[arg(long, value_name = "N")]
+    max_pages: Option<usize>,
+
+    /// When a set that reads the page comes out empty or a media block finds
+    /// nothing, fetch the page again up to N times before moving on
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    selector_timeout_retries: u32,
+
+    /// Seconds to wait before each --selector-timeout-retries fetch
+    #[arg(long, value_name = "SECONDS", default_value = "1", value_parser = parse_seconds)]
+    selector_retry_delay: Duration,
+
+    /// Stop the run after SECONDS, keeping whatever it has downloaded so far
+    #[arg(long, value_name = "SECONDS")]
+    deadline_secs: Option<u64>,
+
+    /// Name downloads after TEMPLATE, e.g. "{index}-{host}-{basename}.{ext}".
+    /// Tokens: index, host, basename, ext, hash and any script variable
+    #[arg(long, value_name = "TEMPLATE", value_parser = NameTemplate::parse)]
+    name_template: Option<NameTemplate>,
+
+    /// Accept invalid TLS certificates, such as self-signed ones. Insecure
+    #[arg(long)]
+    insecure: bool,
+
+    /// Trust the PEM root certificate in FILE in addition to the system's
+    /// (repeatable)
+    #[arg(long = "ca-cert", value_name = "FILE", value_parser = read_pem_file)]
+    ca_certs: Vec<Vec<u8>>,
+
+    /// Connect to IP for HOST instead of looking it up in DNS (repeatable)
+    #[arg(long, value_name = "HOST:IP", value_parser = parse_resolve)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Cache fetched pages in FILE and, on later runs, reuse the cached copy
+    /// of any page the server reports unchanged
+    #[arg(long, value_name = "FILE")]
+    page_cache: Option<PathBuf>,
+
+    /// Log the headers of every request and response, with credentials and
+    /// cookies hidden, e.g. to find out why a site refuses the scraper
+    #[arg(long)]
+    trace_http: bool,
+
+    /// Keep cookies in DIR between runs, so a session such as a login
+    /// carries over to the next run given the same DIR
+    #[arg(long, value_name = "DIR")]
+    user_data_dir: Option<PathBuf>,
+
+    /// Keep up to N pages in memory so links leading back to a page already
+    /// fetched this run don't fetch it again (0 disables)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_HTML_CACHE_SIZE)]
+    html_cache_size: usize,
+
+    /// Run only the top-level commands in LIST, given by position (from 1)
+    /// or keyword, e.g. "2,3" or "media"
+    #[arg(long, value_name = "LIST", value_parser = CommandSelection::parse)]
+    only: Option<CommandSelection>,
+
+    /// Leave out the top-level commands in LIST, given like --only
+    #[arg(long, value_name = "LIST", value_parser = CommandSelection::parse)]
+    skip: Option<CommandSelection>,
+
+    /// Print the URLs of the media the script selects, one per line or as
+    /// a JSON array with `--list-media=json`, instead of downloading
+    /// anything
+    #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1, require_equals = true, default_missing_value = "text")]
+    list_media: Option<ListFormat>,
+}
+
+/// How `--list-media` prints media URLs.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ListFormat {
+    /// One URL per line
+    Text,
+    /// A JSON array of URLs
+    Json,
+}
+
+/// Where `--base` roots relative save paths.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SaveBase {
+    /// The working directory
+    Cwd,
+    /// The directory containing the script file
+    RelativeToScript,
+}
+
+/// `--on-conflict` policies; see [`ConflictPolicy`].
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnConflict {
+    /// Keep the existing file and don't download
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save as e.g. photo-1.jpg instead
+    Rename,
+}
+
+impl From<OnConflict> for ConflictPolicy {
+    fn from(policy: OnConflict) -> Self {
+        match policy {
+            OnConflict::Skip => ConflictPolicy::Skip,
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::Rename => ConflictPolicy::Rename,
+        }
+    }
+}
+
+/// Name of the cookie file in `--user-data-dir`.
+const COOKIE_FILE: &str = "cookies.json";
+
+/// Pages kept in memory by default for `--html-cache-size`.
+const DEFAULT_HTML_CACHE_SIZE: usize = 32;
+
+impl RunOptions {
+    fn engine_config(&self) -> EngineConfig {
+        let redirect_policy = match self.max_redirects {
+            _ if self.same_host_redirects => RedirectPolicy::SameHostOnly,
+            Some(0) => RedirectPolicy::None,
+            Some(max) => RedirectPolicy::Limited(max),
+            None => RedirectPolicy::default(),
+        };
+        
+        EngineConfig {
+            dedupe_content: self.dedupe,
+            output_dir: self.output_dir.clone(),
+            max_file_size: self.max_file_size,
+            min_width: self.min_width,
+            min_height: self.min_height,
+            write_buffer_size: self.write_buffer_size,
+            sync_downloads: self.fsync,
+            on_conflict: self.on_conflict.into(),
+            mirror_paths: self.mirror_paths,
+            concurrency: self.concurrency,
+            max_downloads: self.max_downloads,
+            adaptive_concurrency: self.adaptive_concurrency,
+            parallel_groups: self.parallel_groups,
+            allow_hosts: self.allow_hosts.clone(),
+            deny_hosts: self.deny_hosts.clone(),
+            same_host: self.same_host,
+            iframe_depth: self.iframe_depth,
+            scraper: ScraperConfig {
+                accept_language: self.accept_language.clone(),
+                redirect_policy,
+                auth: self.basic_auth.clone().or_else(|| {
+                    self.bearer.clone().map(|token| Credentials::Bearer { token })
+                }),
+                accept_invalid_certs: self.insecure,
+                root_certificates: self.ca_certs.clone(),
+                resolve: self.resolve.clone(),
+                page_cache: self.page_cache.clone(),
+                follow_meta_refresh: self.follow_meta_refresh,
+                normalize_html: self.normalize_html,
+                trace_http: self.trace_http,
+                cookie_file: self.user_data_dir.as_ref().map(|dir| dir.join(COOKIE_FILE)),
+                ..ScraperConfig::default()
+            },
+            include_urls: self.include_urls.clone(),
+            exclude_urls: self.exclude_urls.clone(),
+            fail_fast: self.fail_fast && !self.continue_on_error,
+            sniff_media_type: self.sniff,
+            verify_checksums: self.checksum_verify,
+            strict: self.strict,
+            dump_failures: self.dump_failures.clone(),
+            deadline: self.deadline_secs.map(Duration::from_secs),
+            name_template: self.name_template.clone(),
+            only: self.only.clone(),
+            skip: self.skip.clone(),
+            html_cache_size: self.html_cache_size,
+            list_media: self.list_media.is_some(),
+            max_pages: self.max_pages,
+            selector_retries: self.selector_timeout_retries,
+            selector_retry_delay: self.selector_retry_delay,
+        }
+    }
+
+    /// Fills in options not given on the command line from `--config`.
+    /// `matches` are the parsed `run` arguments.
+    fn load_config(&mut self, matches: &ArgMatches) -> Result<()> {
+        match &self.config {
+            Some(path) => self.apply_config(ConfigFile::read(path)?, matches),
+            None => Ok(()),
+        }
+    }
+
+    /// The engine settings for running the script at `script_path`, with
+    /// relative save paths rooted where `--base` says.
+    fn engine_config_for(&self, script_path: &Path) -> Result<EngineConfig> {
+        let mut config = self.engine_config();
+        if self.base == SaveBase::RelativeToScript {
+            if is_stdin(script_path) {
+                anyhow::bail!("--base relative-to-script needs a script file, not stdin");
+            }
+            let script_dir = script_path.parent().unwrap_or(Path::new(""));
+            config.output_dir = Some(match config.output_dir {
+                Some(output_dir) => script_dir.join(output_dir),
+                None => script_dir.to_path_buf(),
+            });
+        }
+        Ok(config)
+    }
+}
+
+/// Parses a script, rendering parse errors with the offending source line.
+/// Colors are used only when stderr is a terminal.
+fn parse_source(content: &str, options: &ParseOptions) -> Result<MslScript> {
+    parse_script_with_options(content, options)
+        .map_err(|e| anyhow::anyhow!(render_error(&e, content, std::io::stderr().is_terminal())))
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("expected a number of seconds, got '{}'", value))
+}
+
+fn read_pem_file(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))
+}
+
+fn parse_basic_auth(value: &str) -> Result<Credentials, String> {
+    let (username, password) = value
+        .split_once(':')
+        .ok_or_else(|| "expected USER:PASS".to_string())?;
+    Ok(Credentials::Basic {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Splits at the first colon, so IPv6 addresses need no brackets.
+fn parse_resolve(value: &str) -> Result<(String, IpAddr), String> {
+    let (host, ip) = value.split_once(':').ok_or_else(|| "expected HOST:IP".to_string())?;
+    let ip = ip.trim_matches(['[', ']']).parse().map_err(|_| format!("'{}' is not an IP address", ip))?;
+    Ok((host.to_ascii_lowercase(), ip))
+}
+
+fn log_subscriber<W>(level: Level, json: bool, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt().with_max_level(level).with_writer(writer);
+    if json {
+        Box::new(builder.event_format(json_log::JsonFormat).finish())
     } else {
-        Level::INFO
-    };
+        Box::new(builder.finish())
+    }
+}
+
+/// Starter script written by `msl new`.
+const EXAMPLE_SCRIPT: &str = r#"# MSL example script
+# Lines starting with '#' are comments.
+
+# Load the page to scrape
+open "https://example.com/users"
+
+# Follow the first link matching a CSS selector. Indented commands run
+# against the linked page.
+click ".user-card a"
+  set user = text
+
+# Download media found on the current page, filtered by source URL and
+# file extension
+media
+  image
+    where src ~ "cdn.example.com"
+    extensions jpg, png
+
+# Where downloaded files are written; {user} is replaced by the variable
+save to "./media/{user}"
+"#;
+
+pub async fn run() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     
-    tracing_subscriber::fmt()
-        .with_max_level(level)
-        .init();
+    // Logs go to stderr so that stdout carries only command output
+    tracing::subscriber::set_global_default(log_subscriber(cli.log_level(), cli.json_logs, std::io::stderr))?;
     
     match cli.command {
-        Commands::Run { script, .. } => {
-            run_script(script).await?;
+        Commands::Run { script, verbose, manifest, resume, compress, mut options } => {
+            if let Some(run_matches) = matches.subcommand_matches("run") {
+                options.load_config(run_matches)?;
+            }
+            let config = options.engine_config_for(&script)?;
+            run_script(script, config, verbose, manifest, resume, compress, options.list_media).await?;
+        }
+        Commands::Parse { script, json, flat } => {
+            parse_script_file(script, json, flat).await?;
+        }
+        Commands::Fmt { script, write } => {
+            format_script_file(script, write).await?;
         }
-        Commands::Parse { script } => {
-            parse_script_file(script).await?;
+        Commands::New { path, force } => {
+            create_script_file(path, force).await?;
         }
     }
     
     Ok(())
 }
 
-async fn run_script(script_path: PathBuf) -> Result<()> {
+/// How long in-flight work gets to finish after Ctrl-C.
+const INTERRUPT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+async fn run_script(
+    script_path: PathBuf,
+    config: EngineConfig,
+    verbose: bool,
+    manifest_path: Option<PathBuf>,
+    resume_path: Option<PathBuf>,
+    compress: bool,
+    list_format: Option<ListFormat>,
+) -> Result<()> {
     info!("Loading script from: {}", script_path.display());
     
-    let script_content = std::fs::read_to_string(&script_path)
-        .map_err(|e| anyhow::anyhow!("Failed to read script file: {}", e))?;
+    let script_content = read_script(&script_path, std::io::stdin().lock())?;
     
     info!("Parsing script...");
-    let script = parse_script(&script_content)?;
+    let script = parse_source(&script_content, &parse_options(&script_path))?;
+    if script.commands.is_empty() {
+        warn!("{} has no commands; nothing to run", script_path.display());
+    }
     
     info!("Executing script...");
-    let mut engine = MslEngine::new();
-    engine.execute(script).await?;
+    let mut engine = MslEngine::with_config(config)?;
+    if let Some(path) = resume_path.as_ref().filter(|path| path.exists()) {
+        let manifest = RunManifest::read_from(path)?;
+        info!("Resuming after {} downloads listed in {}", manifest.downloads.len(), path.display());
+        engine.resume(manifest);
+    }
+    // Listing media leaves the filesystem alone, manifests included
+    let manifest_path = manifest_path.or(resume_path).filter(|_| list_format.is_none());
+    let cancel = engine.cancellation_token();
+    let result = {
+        let run = engine.execute(script);
+        tokio::pin!(run);
+        tokio::select! {
+            result = &mut run => result,
+            _ = tokio::signal::ctrl_c() => {
+                warn!("Interrupted; letting in-flight work finish");
+                cancel.cancel();
+                tokio::time::timeout(INTERRUPT_GRACE_PERIOD, &mut run)
+                    .await
+                    .unwrap_or_else(|_| Err(crate::Cancelled.into()))
+            }
+        }
+    };
+    
+    if let Some(path) = &manifest_path {
+        if compress {
+            engine.manifest().write_compressed_to(path)?;
+        } else {
+            engine.manifest().write_to(path)?;
+        }
+        info!("Wrote manifest of {} downloads to {}", engine.manifest().downloads.len(), path.display());
+    }
+    // Losing the session only costs a login next run, so it doesn't fail
+    // this one
+    if let Err(e) = engine.save_session() {
+        warn!("Failed to save cookies: {:#}", e);
+    }
+    if let Err(e) = &result {
+        if e.is::<crate::DeadlineExceeded>() {
+            warn!("Deadline reached; partial results: {}", engine.stats());
+        }
+    }
+    result?;
+    
+    if let Some(format) = list_format {
+        print!("{}", media_list(engine.listed_media(), format)?);
+        return Ok(());
+    }
     
     info!("Script execution completed successfully!");
+    if verbose {
+        info!("Summary: {}", engine.stats());
+    }
     Ok(())
 }
 
-async fn parse_script_file(script_path: PathBuf) -> Result<()> {
+async fn parse_script_file(script_path: PathBuf, json: bool, flat: bool) -> Result<()> {
     info!("Loading script from: {}", script_path.display());
     
-    let script_content = std::fs::read_to_string(&script_path)
-        .map_err(|e| anyhow::anyhow!("Failed to read script file: {}", e))?;
+    let script_content = read_script(&script_path, std::io::stdin().lock())?;
     
     info!("Parsing script...");
-    let script = parse_script(&script_content)?;
+    let script = parse_source(&script_content, &parse_options(&script_path))?;
     
     info!("Script parsed successfully!");
-    println!("Script contains {} commands", script.commands.len());
     
-    // Print a summary of the script
+    if json {
+        println!("{}", script_to_json(&script)?);
+        return Ok(());
+    }
+    
+    if flat {
+        print!("{}", script_summary(&script));
+    } else {
+        print!("{}", script_tree(&script));
+    }
+    
+    Ok(())
+}
+
+/// One line describing `command`, without its body.
+fn command_label(command: &MslCommand) -> String {
+    match command {
+        MslCommand::Open { url } => format!("Open {}", url),
+        MslCommand::Click { selector, each, .. } => {
+            let each = if *each { "each " } else { "" };
+            format!("Click {}{}", each, selector)
+        }
+        MslCommand::Set { variable, value } => format!("Set {} = {}", variable, value),
+        MslCommand::Media { .. } => "Media".to_string(),
+        MslCommand::Save { path } => format!("Save to {}", path),
+        MslCommand::SavePage { path } => format!("Save page to {}", path),
+        MslCommand::Wait { seconds } => format!("Wait {} seconds", seconds),
+        MslCommand::WaitRandom { min, max } => format!("Wait {} to {} seconds", min, max),
+        MslCommand::Auth { credentials } => {
+            let scheme = match credentials {
+                Credentials::Basic { .. } => "basic",
+                Credentials::Bearer { .. } => "bearer",
+            };
+            format!("Auth {}", scheme)
+        }
+        MslCommand::Extract { path, target, .. } => format!("Extract {} into {:?}", path, target),
+        MslCommand::Expect { selector, operator, count } => format!("Expect {} count {} {}", selector, operator, count),
+        MslCommand::Include { path } => format!("Include {}", path),
+        MslCommand::Post { url, fields, json } => {
+            let encoding = if *json { "JSON" } else { "form" };
+            format!("Post {} {} fields to {}", fields.len(), encoding, url)
+        }
+        MslCommand::Foreach { variable, source, .. } => format!("Foreach {} in {}", variable, source),
+        MslCommand::Repeat { times, variable, .. } => format!("Repeat {} times as {}", times, variable),
+    }
+}
+
+/// The numbered top-level commands of `script`, with only a count of what
+/// each one nests.
+fn script_summary(script: &MslScript) -> String {
+    let mut summary = format!("Script contains {} commands\n", script.commands.len());
     for (i, command) in script.commands.iter().enumerate() {
+        let nested = match command {
+            MslCommand::Click { commands, .. }
+            | MslCommand::Foreach { commands, .. }
+            | MslCommand::Repeat { commands, .. } => format!(" ({} nested commands)", commands.len()),
+            MslCommand::Media { media_blocks } => format!(" ({} blocks)", media_blocks.len()),
+            _ => String::new(),
+        };
+        summary.push_str(&format!("  {}: {}{}\n", i + 1, command_label(command), nested));
+    }
+    summary
+}
+
+/// Every command of `script`, with the bodies of `click`, `foreach` and
+/// `repeat` and the blocks of `media` indented beneath them. Nested
+/// commands are numbered from their parent's number, e.g. `2.1`.
+fn script_tree(script: &MslScript) -> String {
+    let mut tree = format!("Script contains {} commands\n", script.commands.len());
+    write_tree(&mut tree, &script.commands, "", 1);
+    tree
+}
+
+fn write_tree(tree: &mut String, commands: &[MslCommand], prefix: &str, depth: usize) {
+    let indent = "  ".repeat(depth);
+    for (i, command) in commands.iter().enumerate() {
+        let number = format!("{}{}", prefix, i + 1);
+        tree.push_str(&format!("{}{}: {}\n", indent, number, command_label(command)));
         match command {
-            crate::parser::MslCommand::Open { url } => {
-                println!("  {}: Open {}", i + 1, url);
-            }
-            crate::parser::MslCommand::Click { selector, commands } => {
-                println!("  {}: Click {} ({} nested commands)", i + 1, selector, commands.len());
-            }
-            crate::parser::MslCommand::Set { variable, value } => {
-                println!("  {}: Set {} = {:?}", i + 1, variable, value);
-            }
-            crate::parser::MslCommand::Media { media_blocks } => {
-                println!("  {}: Media ({} blocks)", i + 1, media_blocks.len());
-            }
-            crate::parser::MslCommand::Save { path } => {
-                println!("  {}: Save to {}", i + 1, path);
-            }
-            crate::parser::MslCommand::Wait { seconds } => {
-                println!("  {}: Wait {} seconds", i + 1, seconds);
+            MslCommand::Click { commands, .. }
+            | MslCommand::Foreach { commands, .. }
+            | MslCommand::Repeat { commands, .. } => write_tree(tree, commands, &format!("{}.", number), depth + 1),
+            MslCommand::Media { media_blocks } => {
+                for block in media_blocks {
+                    let save = block.save_path.as_ref().map(|path| format!(", save to {}", path)).unwrap_or_default();
+                    tree.push_str(&format!("{}  {}{}\n", indent, block.media_type, save));
+                    let details = block.filters.iter().map(ToString::to_string)
+                        .chain(block.sort.map(|sort| sort.to_string()))
+                        .chain(block.limit.map(|limit| format!("limit {}", limit)));
+                    for detail in details {
+                        tree.push_str(&format!("{}    {}\n", indent, detail));
+                    }
+                }
             }
+            _ => {}
         }
     }
+}
+
+async fn format_script_file(script_path: PathBuf, write: bool) -> Result<()> {
+    if write && is_stdin(&script_path) {
+        anyhow::bail!("--write cannot be used when reading from stdin");
+    }
+    
+    let script_content = read_script(&script_path, std::io::stdin().lock())?;
+    
+    // Keep `include` lines as written rather than inlining the files
+    let options = ParseOptions {
+        resolve_includes: false,
+        ..parse_options(&script_path)
+    };
+    let script = parse_source(&script_content, &options)?;
+    let formatted = script.to_string();
+    
+    if write {
+        std::fs::write(&script_path, formatted)
+            .map_err(|e| anyhow::anyhow!("Failed to write script file: {}", e))?;
+        info!("Formatted {}", script_path.display());
+    } else {
+        print!("{}", formatted);
+    }
+    
+    Ok(())
+}
+
+/// The output of `--list-media`.
+fn media_list(urls: &[String], format: ListFormat) -> Result<String> {
+    match format {
+        ListFormat::Text => Ok(urls.iter().map(|url| format!("{}\n", url)).collect()),
+        ListFormat::Json => serde_json::to_string_pretty(urls)
+            .map(|json| json + "\n")
+            .map_err(|e| anyhow::anyhow!("Failed to serialize media list: {}", e)),
+    }
+}
+
+fn script_to_json(script: &MslScript) -> Result<String> {
+    serde_json::to_string_pretty(script)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize script: {}", e))
+}
+
+/// Path argument that selects stdin instead of a file.
+const STDIN_PATH: &str = "-";
+
+fn is_stdin(script_path: &Path) -> bool {
+    script_path.as_os_str() == STDIN_PATH
+}
+
+/// Parse options for the script at `script_path`, so that its includes
+/// resolve relative to the script's own directory.
+fn parse_options(script_path: &Path) -> ParseOptions {
+    ParseOptions {
+        source_path: (!is_stdin(script_path)).then(|| script_path.to_path_buf()),
+        ..ParseOptions::default()
+    }
+}
+
+/// Reads the script at `script_path`, or all of `stdin` when the path is `-`.
+fn read_script(script_path: &Path, mut stdin: impl Read) -> Result<String> {
+    if is_stdin(script_path) {
+        let mut script_content = String::new();
+        stdin.read_to_string(&mut script_content)
+            .map_err(|e| anyhow::anyhow!("Failed to read script from stdin: {}", e))?;
+        return Ok(script_content);
+    }
+    
+    std::fs::read_to_string(script_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read script file: {}", e))
+}
+
+async fn create_script_file(path: PathBuf, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!("{} already exists (use --force to overwrite)", path.display());
+    }
     
+    std::fs::write(&path, EXAMPLE_SCRIPT)
+        .map_err(|e| anyhow::anyhow!("Failed to write script file: {}", e))?;
+    
+    info!("Created {}", path.display());
     Ok(())
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_script;
+    use crate::testing::{LogBuffer, MockResponse, MockServer};
+
+    #[test]
+    fn test_json_logs() {
+        let logs = LogBuffer::default();
+        let subscriber = log_subscriber(Level::INFO, true, logs.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("command").entered();
+            info!(pages = 2, url = "https://example.com", "Loaded page");
+            tracing::debug!("filtered out");
+        });
+
+        let lines: Vec<serde_json::Value> = logs.contents().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields"]["message"], "Loaded page");
+        assert_eq!(lines[0]["fields"]["pages"], 2);
+        assert_eq!(lines[0]["fields"]["url"], "https://example.com");
+        assert_eq!(lines[0]["spans"], serde_json::json!(["command"]));
+        assert!(lines[0]["timestamp"].as_str().is_some_and(|timestamp| !timestamp.is_empty()));
+    }
+
+    #[test]
+    fn test_quiet_logs_only_warnings() {
+        let cli = Cli::try_parse_from(["msl", "run", "--quiet", "scrape.msl"]).unwrap();
+        assert_eq!(cli.log_level(), Level::WARN);
+        let cli = Cli::try_parse_from(["msl", "-q", "fmt", "scrape.msl"]).unwrap();
+        assert_eq!(cli.log_level(), Level::WARN);
+        assert!(Cli::try_parse_from(["msl", "run", "-q", "-v", "scrape.msl"]).is_err());
+
+        let logs = LogBuffer::default();
+        tracing::subscriber::with_default(log_subscriber(cli.log_level(), false, logs.clone()), || {
+            info!("Downloaded photo.jpg");
+            warn!("Skipped video.mp4");
+            tracing::error!("Failed to fetch page");
+        });
+
+        let contents = logs.contents();
+        assert!(!contents.contains("photo.jpg"), "{}", contents);
+        assert!(contents.contains("Skipped video.mp4"), "{}", contents);
+        assert!(contents.contains("Failed to fetch page"), "{}", contents);
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("scraper.toml");
+        std::fs::write(&config_path, r#"
+accept-language = "de"
+html-cache-size = 5
+max-pages = 3
+max-downloads = 4
+same-host = true
+output-dir = "media"
+include-url-regex = ["\\.jpg$"]
+"#).unwrap();
+        let matches = Cli::command()
+            .try_get_matches_from(["msl", "run", "--config", config_path.to_str().unwrap(), "--accept-language", "fr", "--max-pages", "10", "scrape.msl"])
+            .unwrap();
+        let Commands::Run { mut options, .. } = Cli::from_arg_matches(&matches).unwrap().command else {
+            panic!("expected run command");
+        };
+        options.load_config(matches.subcommand_matches("run").unwrap()).unwrap();
+        let config = options.engine_config();
+
+        assert_eq!(config.scraper.accept_language, "fr");
+        assert_eq!(config.max_pages, Some(10));
+        assert_eq!(config.max_downloads, Some(4));
+        assert_eq!(config.html_cache_size, 5);
+        assert!(config.same_host);
+        assert_eq!(config.output_dir, Some(dir.path().join("media")));
+        assert!(config.include_urls[0].is_match("https://example.com/a.jpg"));
+        assert_eq!(config.selector_retries, 0);
+    }
+
+    #[test]
+    fn test_example_script_parses() {
+        let script = parse_script(EXAMPLE_SCRIPT).unwrap();
+        assert!(!script.commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scrape.msl");
+        std::fs::write(&path, "open \"https://example.com\"").unwrap();
+
+        assert!(create_script_file(path.clone(), false).await.is_err());
+        create_script_file(path.clone(), true).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), EXAMPLE_SCRIPT);
+    }
+
+    #[test]
+    fn test_read_script_from_stdin() {
+        let cli = Cli::try_parse_from(["msl", "run", "-"]).unwrap();
+        let Commands::Run { script: script_path, .. } = cli.command else {
+            panic!("expected run command");
+        };
+
+        let stdin = std::io::Cursor::new("open \"https://example.com\"\nwait 1\n");
+        let script_content = read_script(&script_path, stdin).unwrap();
+        let script = parse_script(&script_content).unwrap();
+        assert_eq!(script.commands.len(), 2);
+    }
+
+    #[test]
+    fn test_accept_language_option() {
+        let cli = Cli::try_parse_from(["msl", "run", "scrape.msl"]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        assert_eq!(options.engine_config().scraper.accept_language, "en-US");
+
+        let cli = Cli::try_parse_from(["msl", "run", "--accept-language", "fr-FR", "scrape.msl"]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        assert_eq!(options.engine_config().scraper.accept_language, "fr-FR");
+    }
+
+    #[test]
+    fn test_redirect_options() {
+        let policy = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["msl", "run"].iter().chain(args).chain(&["scrape.msl"])).unwrap();
+            let Commands::Run { options, .. } = cli.command else {
+                panic!("expected run command");
+            };
+            options.engine_config().scraper.redirect_policy
+        };
+
+        assert_eq!(policy(&[]), RedirectPolicy::default());
+        assert_eq!(policy(&["--max-redirects", "3"]), RedirectPolicy::Limited(3));
+        assert_eq!(policy(&["--max-redirects", "0"]), RedirectPolicy::None);
+        assert_eq!(policy(&["--same-host-redirects"]), RedirectPolicy::SameHostOnly);
+        assert!(Cli::try_parse_from(["msl", "run", "--max-redirects", "3", "--same-host-redirects", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_url_regex_options() {
+        let cli = Cli::try_parse_from([
+            "msl", "run",
+            "--include-url-regex", r"\.jpe?g$",
+            "--include-url-regex", "cdn",
+            "--exclude-url-regex", "thumb",
+            "scrape.msl",
+        ])
+        .unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        let config = options.engine_config();
+        assert_eq!(config.include_urls.len(), 2);
+        assert_eq!(config.exclude_urls.len(), 1);
+
+        assert!(Cli::try_parse_from(["msl", "run", "--exclude-url-regex", "(", "scrape.msl"]).is_err());
+    }
+
+    #[test]
+    fn test_auth_options() {
+        let auth = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["msl", "run"].iter().chain(args).chain(&["scrape.msl"])).unwrap();
+            let Commands::Run { options, .. } = cli.command else {
+                panic!("expected run command");
+            };
+            options.engine_config().scraper.auth
+        };
+
+        assert_eq!(auth(&[]), None);
+        assert_eq!(
+            auth(&["--basic-auth", "alice:pa:ss"]),
+            Some(Credentials::Basic { username: "alice".to_string(), password: "pa:ss".to_string() })
+        );
+        assert_eq!(auth(&["--bearer", "abc"]), Some(Credentials::Bearer { token: "abc".to_string() }));
+        assert!(Cli::try_parse_from(["msl", "run", "--basic-auth", "alice", "scrape.msl"]).is_err());
+    }
+
+    #[test]
+    fn test_certificate_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let pem = dir.path().join("ca.pem");
+        std::fs::write(&pem, "-----BEGIN CERTIFICATE-----").unwrap();
+        let pem = pem.to_str().unwrap();
+
+        let cli = Cli::try_parse_from(["msl", "run", "--insecure", "--ca-cert", pem, "--ca-cert", pem, "scrape.msl"]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        let config = options.engine_config().scraper;
+        assert!(config.accept_invalid_certs);
+        assert_eq!(config.root_certificates, vec![b"-----BEGIN CERTIFICATE-----".to_vec(); 2]);
+
+        assert!(Cli::try_parse_from(["msl", "run", "--ca-cert", "missing.pem", "scrape.msl"]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_option() {
+        let cli = Cli::try_parse_from(["msl", "run", "--resolve", "Example.com:10.0.0.5", "--resolve", "cdn.example.com:[::1]", "scrape.msl"]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        let resolve = options.engine_config().scraper.resolve;
+        assert_eq!(resolve, [("example.com".to_string(), "10.0.0.5".parse().unwrap()), ("cdn.example.com".to_string(), "::1".parse().unwrap())]);
+
+        assert!(Cli::try_parse_from(["msl", "run", "--resolve", "example.com", "scrape.msl"]).is_err());
+        assert!(Cli::try_parse_from(["msl", "run", "--resolve", "example.com:localhost", "scrape.msl"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_and_skip_options() {
+        let server = MockServer::with_routes(vec![
+            ("/one", MockResponse::html("<p>one</p>")),
+            ("/two", MockResponse::html("<p>two</p>")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("scrape.msl");
+        std::fs::write(
+            &script,
+            format!("open \"{}\"\nwait 5\nopen \"{}\"\n", server.url("/one"), server.url("/two")),
+        )
+        .unwrap();
+        let script = script.to_str().unwrap();
+
+        let cli = Cli::try_parse_from(["msl", "run", "--only", "3", script]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        run_script(script.into(), options.engine_config(), false, None, None, false, None).await.unwrap();
+        assert_eq!(server.hits("/one"), 0);
+        assert_eq!(server.hits("/two"), 1);
+
+        let cli = Cli::try_parse_from(["msl", "run", "--skip", "wait,3", script]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        run_script(script.into(), options.engine_config(), false, None, None, false, None).await.unwrap();
+        assert_eq!(server.hits("/one"), 1);
+        assert_eq!(server.hits("/two"), 1);
+
+        assert!(Cli::try_parse_from(["msl", "run", "--only", "downlaod", script]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_base_relative_to_script() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/photo.jpg">"#)),
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let script_dir = dir.path().join("scripts");
+        std::fs::create_dir(&script_dir).unwrap();
+        let script = script_dir.join("scrape.msl");
+        std::fs::write(&script, format!("open \"{}\"\nmedia\n  image\nsave to \"./media\"\n", server.url("/"))).unwrap();
+
+        let cli = Cli::try_parse_from(["msl", "run", "--base", "relative-to-script", script.to_str().unwrap()]).unwrap();
+        let Commands::Run { script, options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        let config = options.engine_config_for(&script).unwrap();
+        assert_eq!(config.output_dir.as_deref(), Some(script_dir.as_path()));
+        run_script(script, config, false, None, None, false, None).await.unwrap();
+        assert_eq!(std::fs::read(script_dir.join("media/photo.jpg")).unwrap(), b"jpeg bytes");
+
+        let cli = Cli::try_parse_from(["msl", "run", "--base", "relative-to-script", "--output-dir", "out", "-"]).unwrap();
+        let Commands::Run { script, options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        assert!(options.engine_config_for(&script).is_err());
+
+        let cli = Cli::try_parse_from(["msl", "run", "scripts/scrape.msl"]).unwrap();
+        let Commands::Run { script, options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        assert_eq!(options.engine_config_for(&script).unwrap().output_dir, None);
+    }
+
+    #[tokio::test]
+    async fn test_list_media() {
+        let server = MockServer::with_routes(vec![(
+            "/",
+            MockResponse::html(r#"<img src="/a.jpg"><img src="/thumbs/b.jpg"><img src="/c.png"><img src="/d.jpg">"#),
+        )])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("scrape.msl");
+        std::fs::write(
+            &script,
+            format!("open \"{}\"\nmedia\n  image\n    extensions jpg\nsave to \"./media\"\nsave to \"page.html\"\n", server.url("/")),
+        )
+        .unwrap();
+        let script = script.to_str().unwrap();
+
+        let cli = Cli::try_parse_from(["msl", "run", "--list-media=json", "--exclude-url-regex", "thumbs", script]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        assert_eq!(options.list_media, Some(ListFormat::Json));
+        let mut config = options.engine_config();
+        config.output_dir = Some(dir.path().join("out"));
+        let mut engine = MslEngine::with_config(config).unwrap();
+        engine.execute(parse_script(&std::fs::read_to_string(script).unwrap()).unwrap()).await.unwrap();
+
+        let expected = vec![server.url("/a.jpg"), server.url("/d.jpg")];
+        let printed: Vec<String> = serde_json::from_str(&media_list(engine.listed_media(), ListFormat::Json).unwrap()).unwrap();
+        assert_eq!(printed, expected);
+        assert_eq!(media_list(engine.listed_media(), ListFormat::Text).unwrap(), format!("{}\n{}\n", expected[0], expected[1]));
+        assert_eq!(server.hits("/a.jpg"), 0);
+        assert!(!dir.path().join("out").exists());
+
+        let cli = Cli::try_parse_from(["msl", "run", "--list-media", script]).unwrap();
+        let Commands::Run { options, .. } = cli.command else {
+            panic!("expected run command");
+        };
+        assert_eq!(options.list_media, Some(ListFormat::Text));
+    }
+
+    #[test]
+    fn test_script_to_json() {
+        let script = parse_script(r#"
+open "https://example.com"
+media
+  image
+    where src ~ "cdn.example.com"
+    extensions jpg, png
+"#).unwrap();
+        let json = script_to_json(&script).unwrap();
+
+        assert!(json.contains("\"Open\""));
+        assert!(json.contains("\"url\": \"https://example.com\""));
+        assert!(json.contains("\"Media\""));
+        assert!(json.contains("\"Image\""));
+        assert!(json.contains("\"Where\""));
+        assert!(json.contains("\"Extensions\""));
+    }
+
+    #[test]
+    fn test_script_tree_indents_nested_commands() {
+        let script = parse_script(r#"
+open "https://example.com"
+click each ".card a"
+  set name = text | trim
+  foreach link in links
+    media
+      image
+        where src ~ "cdn"
+        limit 2
+      save to "{name}"
+wait 1
+"#).unwrap();
+
+        let tree = script_tree(&script);
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Script contains 3 commands",
+                "  1: Open https://example.com",
+                "  2: Click each .card a",
+                "    2.1: Set name = text | trim",
+                "    2.2: Foreach link in links",
+                "      2.2.1: Media",
+                "        image, save to {name}",
+                "          where src ~ \"cdn\"",
+                "          limit 2",
+                "  3: Wait 1 seconds",
+            ]
+        );
+
+        let summary = script_summary(&script);
+        assert!(summary.contains("  2: Click each .card a (2 nested commands)\n"));
+        assert!(!summary.contains("2.1"));
+    }
+}
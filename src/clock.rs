@@ -0,0 +1,114 @@
+//! Time as the engine and scraper wait on it, so tests can stand in a
+//! `MockClock`, available with the `test-util` feature, and skip the
+//! waiting.
+
+use futures_util::future::BoxFuture;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(any(test, feature = "test-util"))]
+use tokio::sync::watch;
+
+/// Source of the delays behind `wait`, run deadlines and rate limit
+/// backoff.
+pub trait Clock: Send + Sync {
+    /// Completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Real time, through Tokio's timer. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Time that only moves when [`MockClock::advance`] is called. Clones share
+/// the same time.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    /// Time advanced so far.
+    now: Arc<watch::Sender<Duration>>,
+    /// Every sleep asked for, in order.
+    sleeps: Arc<watch::Sender<Vec<Duration>>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(watch::Sender::new(Duration::ZERO)),
+            sleeps: Arc::new(watch::Sender::new(Vec::new())),
+        }
+    }
+
+    /// Moves time forward, waking every sleep that's due.
+    pub fn advance(&self, by: Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+
+    /// Time advanced since the clock was made.
+    pub fn elapsed(&self) -> Duration {
+        *self.now.borrow()
+    }
+
+    /// Durations of every sleep started so far, in the order they started.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.borrow().clone()
+    }
+
+    /// Waits until `count` sleeps have started in all, e.g. before advancing
+    /// past the one a task under test is about to take.
+    pub async fn wait_for_sleeps(&self, count: usize) {
+        let mut sleeps = self.sleeps.subscribe();
+        let _ = sleeps.wait_for(|sleeps| sleeps.len() >= count).await;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for MockClock {
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut now = self.now.subscribe();
+        let wake_at = *now.borrow() + duration;
+        self.sleeps.send_modify(|sleeps| sleeps.push(duration));
+        Box::pin(async move {
+            let _ = now.wait_for(|now| *now >= wake_at).await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[tokio::test]
+    async fn test_mock_clock_wakes_sleepers_once_advanced() {
+        let clock = MockClock::new();
+        let mut short = clock.sleep(Duration::from_secs(2));
+        let mut long = clock.clone().sleep(Duration::from_secs(5));
+        assert!((&mut short).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(3));
+        assert!((&mut short).now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(2));
+        assert!(long.now_or_never().is_some());
+        assert!(clock.sleep(Duration::ZERO).now_or_never().is_some());
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+        assert_eq!(clock.sleeps(), [Duration::from_secs(2), Duration::from_secs(5), Duration::ZERO]);
+        clock.wait_for_sleeps(3).await;
+    }
+}
@@ -0,0 +1,240 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256};
+
+/// Returned when a download's content doesn't match the checksum its
+/// response came with.
+#[derive(Debug, thiserror::Error)]
+#[error("{algorithm} checksum mismatch: expected {expected}, got {actual}")]
+pub(super) struct ChecksumMismatch {
+    algorithm: &'static str,
+    expected: String,
+    actual: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha-256",
+        }
+    }
+}
+
+/// Checks a body against the checksums in its response's `Content-MD5`,
+/// `Digest` (`MD5=..`, `SHA-256=..`) and `Content-Digest`
+/// (`sha-256=:..:`) headers as it streams past. Algorithms other than MD5
+/// and SHA-256 are ignored.
+pub(super) struct ChecksumVerifier {
+    expected: Vec<(Algorithm, Vec<u8>)>,
+    md5: Option<Md5>,
+    sha256: Option<Sha256>,
+}
+
+impl ChecksumVerifier {
+    /// A verifier for the checksums in `headers`; `None` when there are none
+    /// it understands.
+    pub(super) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut expected = Vec::new();
+        let values = |name: &str| headers.get_all(name).into_iter().filter_map(|value| value.to_str().ok()).collect::<Vec<_>>();
+        for value in values("content-md5") {
+            expected.extend(BASE64.decode(value.trim()).ok().map(|digest| (Algorithm::Md5, digest)));
+        }
+        for value in values("digest").iter().chain(&values("content-digest")).flat_map(|value| value.split(',')) {
+            let Some((algorithm, digest)) = value.split_once('=') else {
+                continue;
+            };
+            let algorithm = match algorithm.trim().to_ascii_lowercase().as_str() {
+                "md5" => Algorithm::Md5,
+                "sha-256" => Algorithm::Sha256,
+                _ => continue,
+            };
+            // Content-Digest wraps the value in colons
+            expected.extend(BASE64.decode(digest.trim().trim_matches(':')).ok().map(|digest| (algorithm, digest)));
+        }
+        if expected.is_empty() {
+            return None;
+        }
+        let wants = |algorithm| expected.iter().any(|(wanted, _)| *wanted == algorithm);
+        Some(Self {
+            md5: wants(Algorithm::Md5).then(Md5::new),
+            sha256: wants(Algorithm::Sha256).then(Sha256::new),
+            expected,
+        })
+    }
+
+    pub(super) fn update(&mut self, chunk: &[u8]) {
+        if let Some(md5) = self.md5.as_mut() {
+            md5.update(chunk);
+        }
+        if let Some(sha256) = self.sha256.as_mut() {
+            sha256.update(chunk);
+        }
+    }
+
+    /// Compares the body seen against every expected checksum, returning
+    /// the names of the algorithms checked.
+    pub(super) fn finish(self) -> Result<String, ChecksumMismatch> {
+        let md5 = self.md5.map(Md5::finalize);
+        let sha256 = self.sha256.map(|sha256| sha256.finalize().to_vec());
+        let mut checked: Vec<&str> = Vec::new();
+        for (algorithm, expected) in &self.expected {
+            let actual = match algorithm {
+                Algorithm::Md5 => md5.as_ref().map(|digest| digest.as_slice()),
+                Algorithm::Sha256 => sha256.as_deref(),
+            }
+            .unwrap_or_default();
+            if actual != expected.as_slice() {
+                return Err(ChecksumMismatch {
+                    algorithm: algorithm.name(),
+                    expected: BASE64.encode(expected),
+                    actual: BASE64.encode(actual),
+                });
+            }
+            if !checked.contains(&algorithm.name()) {
+                checked.push(algorithm.name());
+            }
+        }
+        Ok(checked.join(", "))
+    }
+}
+
+/// MD5 as specified in RFC 1321, for `Content-MD5`.
+struct Md5 {
+    state: [u32; 4],
+    buffer: Vec<u8>,
+    length: u64,
+}
+
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+const MD5_SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+impl Md5 {
+    fn new() -> Self {
+        Self { state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476], buffer: Vec::with_capacity(64), length: 0 }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        if !self.buffer.is_empty() {
+            let take = (64 - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() < 64 {
+                return;
+            }
+            let block: [u8; 64] = self.buffer[..].try_into().unwrap();
+            self.compress(&block);
+            self.buffer.clear();
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+
+    fn finalize(mut self) -> [u8; 16] {
+        let bits = self.length.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        // Pad to 8 bytes short of a whole block
+        padding.resize(1 + ((119 - self.length % 64) % 64) as usize, 0);
+        padding.extend_from_slice(&bits.to_le_bytes());
+        let length = self.length;
+        self.update(&padding);
+        self.length = length;
+
+        let mut digest = [0; 16];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let words: Vec<u32> = block.chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(MD5_CONSTANTS[i])
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[i]);
+            (a, d, c, b) = (d, c, b, b.wrapping_add(rotated));
+        }
+        for (state, word) in self.state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(word);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn md5_hex(data: &[u8]) -> String {
+        let mut md5 = Md5::new();
+        // Uneven pieces exercise the buffering
+        for piece in data.chunks(7) {
+            md5.update(piece);
+        }
+        md5.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_md5_vectors() {
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5_hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(md5_hex(b"message digest"), "f96b697d7cb7938d525a2f31aaf161d0");
+        assert_eq!(
+            md5_hex(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+        assert_eq!(md5_hex(&[b'a'; 60]), "cc7ed669cf88f201c3297c6a91e1d18d");
+    }
+
+    #[test]
+    fn test_verifier_reads_digest_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("digest", "SHA-256=ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=, unixsum=30637".parse().unwrap());
+        headers.insert("content-md5", "kAFQmDzST7DWlj99KOF/cg==".parse().unwrap());
+        let mut verifier = ChecksumVerifier::from_headers(&headers).unwrap();
+        verifier.update(b"ab");
+        verifier.update(b"c");
+        assert_eq!(verifier.finish().unwrap(), "md5, sha-256");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-digest", "sha-256=:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=:".parse().unwrap());
+        let mut verifier = ChecksumVerifier::from_headers(&headers).unwrap();
+        verifier.update(b"abd");
+        assert!(verifier.finish().unwrap_err().to_string().starts_with("sha-256 checksum mismatch"));
+
+        assert!(ChecksumVerifier::from_headers(&HeaderMap::new()).is_none());
+    }
+}
@@ -0,0 +1,72 @@
+use url::Url;
+
+/// Hosts a run may fetch pages and media from. A pattern is a host name,
+/// or `*.example.com` for any subdomain of `example.com`. Denied hosts win
+/// over allowed ones; with no allowed hosts, any host not denied is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct HostFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl HostFilter {
+    pub fn new(allow: &[String], deny: &[String]) -> Self {
+        let normalize = |patterns: &[String]| patterns.iter().map(|pattern| pattern.trim().to_ascii_lowercase()).collect();
+        Self { allow: normalize(allow), deny: normalize(deny) }
+    }
+
+    /// Whether `url` may be fetched. URLs without a host, such as `data:`
+    /// URLs, always may.
+    pub fn allows(&self, url: &str) -> bool {
+        let Some(host) = host_of(url) else {
+            return true;
+        };
+        let matches = |pattern: &String| match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.')),
+            None => host == *pattern,
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+
+    /// Restricts the run to the host of `url` unless allowed hosts were
+    /// already given.
+    pub fn allow_only_host_of(&mut self, url: &str) {
+        if !self.allow.is_empty() {
+            return;
+        }
+        if let Some(host) = host_of(url) {
+            self.allow.push(host);
+        }
+    }
+}
+
+/// Lowercased host name of `url`; `None` for URLs without one.
+pub(super) fn host_of(url: &str) -> Option<String> {
+    Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_patterns() {
+        let hosts = HostFilter::new(&["example.com".to_string(), "*.cdn.net".to_string()], &["ads.cdn.net".to_string()]);
+
+        assert!(hosts.allows("https://example.com/page"));
+        assert!(hosts.allows("https://EXAMPLE.com:8080/"));
+        assert!(hosts.allows("https://img.cdn.net/a.jpg"));
+        assert!(!hosts.allows("https://ads.cdn.net/a.jpg"));
+        assert!(!hosts.allows("https://cdn.net/a.jpg"));
+        assert!(!hosts.allows("https://www.example.com/"));
+        assert!(!hosts.allows("https://notexample.com/"));
+        assert!(hosts.allows("data:image/png;base64,AAAA"));
+
+        let mut open = HostFilter::new(&[], &["tracker.io".to_string()]);
+        assert!(open.allows("https://anything.org/"));
+        assert!(!open.allows("https://tracker.io/pixel.gif"));
+        open.allow_only_host_of("https://shop.example.org/start");
+        assert!(open.allows("https://shop.example.org/item/1"));
+        assert!(!open.allows("https://anything.org/"));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::scraper::Page;
+
+/// Pages fetched earlier in the run, by requested URL, so navigating back to
+/// one doesn't fetch it again. Holds at most `capacity` pages, evicting the
+/// least recently used.
+#[derive(Debug, Default)]
+pub struct HtmlCache {
+    capacity: usize,
+    pages: HashMap<String, Page>,
+    /// Cached URLs from least to most recently used.
+    order: VecDeque<String>,
+}
+
+impl HtmlCache {
+    /// A cache of `capacity` pages; zero disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, ..Self::default() }
+    }
+
+    pub fn get(&mut self, url: &str) -> Option<Page> {
+        let page = self.pages.get(url)?.clone();
+        self.touch(url);
+        Some(page)
+    }
+
+    pub fn insert(&mut self, url: &str, page: Page) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.pages.insert(url.to_string(), page).is_some() {
+            self.touch(url);
+            return;
+        }
+        self.order.push_back(url.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.pages.remove(&evicted);
+            }
+        }
+    }
+
+    fn touch(&mut self, url: &str) {
+        if let Some(position) = self.order.iter().position(|cached| cached == url) {
+            let url = self.order.remove(position).unwrap_or_default();
+            self.order.push_back(url);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderMap;
+
+    fn page(html: &str) -> Page {
+        Page { url: "https://example.com/".to_string(), html: html.to_string(), headers: HeaderMap::new() }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = HtmlCache::new(2);
+        cache.insert("a", page("a"));
+        cache.insert("b", page("b"));
+        assert_eq!(cache.get("a").unwrap().html, "a");
+        cache.insert("c", page("c"));
+
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a").unwrap().html, "a");
+        assert_eq!(cache.get("c").unwrap().html, "c");
+
+        cache.insert("a", page("a2"));
+        assert_eq!(cache.get("a").unwrap().html, "a2");
+
+        let mut disabled = HtmlCache::new(0);
+        disabled.insert("a", page("a"));
+        assert!(disabled.get("a").is_none());
+    }
+}
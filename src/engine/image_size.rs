@@ -0,0 +1,82 @@
+/// Most of an image's start [`dimensions`] needs to see. JPEGs can put
+/// large EXIF blocks and thumbnails ahead of the frame header.
+pub(super) const MAX_HEADER_LEN: usize = 64 * 1024;
+
+/// Width and height of a PNG, GIF, JPEG or WebP image, read from the start
+/// of its content. `None` when the format isn't one of those or `head`
+/// stops before the dimensions.
+pub(super) fn dimensions(head: &[u8]) -> Option<(u32, u32)> {
+    let le16 = |at: usize| head.get(at..at + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as u32);
+    let be32 = |at: usize| head.get(at..at + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+    let le24 = |at: usize| head.get(at..at + 3).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]));
+
+    match head {
+        [0x89, b'P', b'N', b'G', ..] => Some((be32(16)?, be32(20)?)),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some((le16(6)?, le16(8)?)),
+        [0xFF, 0xD8, ..] => jpeg_dimensions(head),
+        _ if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" => match head.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3FFF, le16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(head.get(21..25)?.try_into().unwrap());
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Walks a JPEG's marker segments to the first start-of-frame header.
+fn jpeg_dimensions(head: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |at: usize| head.get(at..at + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize);
+    let mut at = 2;
+    loop {
+        if *head.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *head.get(at + 1)?;
+        match marker {
+            // Fill bytes before a marker
+            0xFF => at += 1,
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => at += 2,
+            // SOF0-SOF15, apart from DHT, JPG and DAC
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let height = be16(at + 5)?;
+                let width = be16(at + 7)?;
+                return Some((width as u32, height as u32));
+            }
+            // Start of scan or end of image before any frame header
+            0xDA | 0xD9 => return None,
+            _ => at += 2 + be16(at + 2)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_image_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(dimensions(&png), Some((640, 480)));
+        assert_eq!(dimensions(&png[..20]), None);
+
+        assert_eq!(dimensions(b"GIF89a\x10\x00\x20\x00"), Some((16, 32)));
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend_from_slice(&[0xFF, 0xC2, 0x00, 0x11, 0x08, 0x01, 0x2C, 0x03, 0x20]);
+        assert_eq!(dimensions(&jpeg), Some((800, 300)));
+        assert_eq!(dimensions(&jpeg[..10]), None);
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0".to_vec();
+        webp.extend_from_slice(&[0x3F, 0x01, 0x00, 0xC7, 0x00, 0x00]);
+        assert_eq!(dimensions(&webp), Some((320, 200)));
+
+        assert_eq!(dimensions(b"not an image"), None);
+    }
+}
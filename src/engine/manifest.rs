@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// File extension of gzipped manifests.
+const GZIP_EXTENSION: &str = "gz";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Most a gzipped manifest may decompress to, so a small corrupt or hostile
+/// file can't exhaust memory.
+const MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+/// Record of what a run downloaded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub downloads: Vec<DownloadRecord>,
+    /// Downloads that failed and were skipped.
+    #[serde(default)]
+    pub failed: Vec<FailedDownload>,
+    /// `set`s and `extract`s that found nothing, leaving their variable
+    /// empty.
+    #[serde(default)]
+    pub empty_extractions: Vec<EmptyExtraction>,
+}
+
+impl RunManifest {
+    /// Reads a manifest written by [`RunManifest::write_to`], gzipped or
+    /// not.
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+        let json = match bytes.starts_with(&GZIP_MAGIC) {
+            true => decompress(&bytes).with_context(|| format!("Failed to decompress manifest {}", path.display()))?,
+            false => bytes,
+        };
+        serde_json::from_slice(&json).with_context(|| format!("Invalid manifest {}", path.display()))
+    }
+
+    /// Writes the manifest to `path` as pretty-printed JSON, gzipped when
+    /// `path` ends in `.gz`.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        self.write(path, path.extension().is_some_and(|extension| extension == GZIP_EXTENSION))
+    }
+
+    /// Writes the manifest to `path` as gzipped JSON, whatever its name.
+    pub fn write_compressed_to(&self, path: &Path) -> Result<()> {
+        self.write(path, true)
+    }
+
+    fn write(&self, path: &Path, compress: bool) -> Result<()> {
+        let file = File::create(path).context("Failed to write manifest")?;
+        let mut writer = BufWriter::new(file);
+        // Serialized straight into the file, compressing as it goes
+        if compress {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            serde_json::to_writer_pretty(&mut encoder, self).context("Failed to write manifest")?;
+            writer = encoder.finish().context("Failed to write manifest")?;
+        } else {
+            serde_json::to_writer_pretty(&mut writer, self).context("Failed to write manifest")?;
+        }
+        writer.flush().context("Failed to write manifest")
+    }
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut json = Vec::new();
+    GzDecoder::new(bytes).take(MAX_DECOMPRESSED_SIZE + 1).read_to_end(&mut json)?;
+    if json.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        anyhow::bail!("decompresses to more than {} bytes", MAX_DECOMPRESSED_SIZE);
+    }
+    Ok(json)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadRecord {
+    pub url: String,
+    pub path: PathBuf,
+    /// Hex-encoded SHA-256 of the content, when content dedup is enabled.
+    pub sha256: Option<String>,
+    /// Earlier download with identical content. The file at `path` was
+    /// removed in favour of it.
+    pub duplicate_of: Option<PathBuf>,
+    /// Checksums the server sent that the content was verified against,
+    /// e.g. `md5`.
+    #[serde(default)]
+    pub verified: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedDownload {
+    pub url: String,
+    pub error: String,
+}
+
+/// A variable left empty because what it reads wasn't on the page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmptyExtraction {
+    pub variable: String,
+    /// What the variable was set from, as written in the script, e.g.
+    /// `attr("data-id")`.
+    pub source: String,
+    /// Selector of the enclosing `click`, whose element the source read.
+    pub selector: Option<String>,
+    pub url: Option<String>,
+}
+
+impl fmt::Display for EmptyExtraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {} found nothing", self.variable, self.source)?;
+        if let Some(selector) = &self.selector {
+            write!(f, " in the element matching \"{}\"", selector)?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, " on {}", url)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        let manifest = RunManifest {
+            downloads: vec![DownloadRecord {
+                url: "https://example.com/a.jpg".to_string(),
+                path: PathBuf::from("media/a.jpg"),
+                sha256: None,
+                duplicate_of: None,
+                verified: None,
+            }],
+            failed: Vec::new(),
+            empty_extractions: Vec::new(),
+        };
+
+        manifest.write_to(&path).unwrap();
+        let read = RunManifest::read_from(&path).unwrap();
+
+        assert_eq!(read.downloads.len(), 1);
+        assert_eq!(read.downloads[0].url, "https://example.com/a.jpg");
+        assert!(RunManifest::read_from(&dir.path().join("missing.json")).is_err());
+    }
+
+    #[test]
+    fn test_gzipped_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = RunManifest {
+            downloads: (0..1_000)
+                .map(|i| DownloadRecord {
+                    url: format!("https://example.com/{}.jpg", i),
+                    path: PathBuf::from(format!("media/{}.jpg", i)),
+                    sha256: Some(format!("{:064x}", i)),
+                    duplicate_of: None,
+                    verified: Some("md5".to_string()),
+                })
+                .collect(),
+            failed: vec![FailedDownload { url: "https://example.com/x.jpg".to_string(), error: "404".to_string() }],
+            empty_extractions: Vec::new(),
+        };
+        let json = serde_json::to_string_pretty(&manifest).unwrap();
+
+        let gzipped = dir.path().join("manifest.json.gz");
+        manifest.write_to(&gzipped).unwrap();
+        let written = std::fs::read(&gzipped).unwrap();
+        assert!(written.starts_with(&GZIP_MAGIC));
+        assert!(written.len() < json.len() / 4);
+        assert_eq!(serde_json::to_string_pretty(&RunManifest::read_from(&gzipped).unwrap()).unwrap(), json);
+
+        let named_plainly = dir.path().join("manifest.json");
+        manifest.write_compressed_to(&named_plainly).unwrap();
+        assert!(std::fs::read(&named_plainly).unwrap().starts_with(&GZIP_MAGIC));
+        assert_eq!(serde_json::to_string_pretty(&RunManifest::read_from(&named_plainly).unwrap()).unwrap(), json);
+    }
+}
@@ -1,33 +1,604 @@
 use anyhow::{Context, Result};
+use futures_util::stream::FuturesUnordered;
 use futures_util::StreamExt;
-use std::collections::HashMap;
-use std::path::Path;
+use rand::Rng;
+use regex::Regex;
+use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio_util::io::StreamReader;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::Notify;
+use tokio_util::io::{InspectReader, StreamReader};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, info, trace, warn, Instrument};
 
-use crate::parser::{MslCommand, MslScript, MslValue};
-use crate::scraper::{Scraper, ScrapingResult};
+mod hosts;
+mod checksum;
+mod html_cache;
+mod image_size;
+mod manifest;
+mod naming;
+mod selection;
+mod sink;
+mod stats;
+mod throttle;
+mod value;
+
+pub use hosts::HostFilter;
+pub use manifest::{DownloadRecord, EmptyExtraction, FailedDownload, RunManifest};
+pub use naming::NameTemplate;
+pub use selection::CommandSelection;
+pub use sink::{ConflictPolicy, FsSink, MediaBody, MediaSink, DEFAULT_BUFFER_SIZE};
+pub use stats::RunStats;
+pub use value::Value;
+
+use checksum::{ChecksumMismatch, ChecksumVerifier};
+use html_cache::HtmlCache;
+use throttle::HostLimits;
+
+use crate::clock::{Clock, TokioClock};
+use crate::parser::{
+    Credentials, ExtractSource, ExtractTarget, ForeachSource, MediaBlock, MediaFilter, MslCommand,
+    MslScript, MslValue, SortKey, Transform,
+};
+use crate::scraper::{
+    json_value_text, ElementData, JsonPath, MediaItem, Page, RequestHook, Scraper, ScraperConfig,
+};
+
+/// Options controlling how the engine runs a script.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    /// Remove downloads whose content is identical to an earlier download.
+    pub dedupe_content: bool,
+    /// Directory that relative save paths are resolved under instead of the
+    /// working directory.
+    pub output_dir: Option<PathBuf>,
+    /// Largest download, in bytes, to keep. Bigger files are skipped.
+    pub max_file_size: Option<u64>,
+    /// Narrowest image, in pixels, to keep. Images are measured from their
+    /// first bytes and skipped before the rest is downloaded; images whose
+    /// size can't be read are kept.
+    pub min_width: Option<u32>,
+    /// Shortest image, in pixels, to keep; see `min_width`.
+    pub min_height: Option<u32>,
+    /// Settings for the HTTP client.
+    pub scraper: ScraperConfig,
+    /// When non-empty, only media URLs matching one of these are downloaded.
+    pub include_urls: Vec<Regex>,
+    /// Media URLs matching any of these are never downloaded.
+    pub exclude_urls: Vec<Regex>,
+    /// Stop the run at the first failed download instead of recording the
+    /// failure in the manifest and moving on.
+    pub fail_fast: bool,
+    /// Check each download's leading bytes and fix its extension when the
+    /// content is a different format than the URL suggests.
+    pub sniff_media_type: bool,
+    /// Longest the whole run may take. When it's up the run stops as if
+    /// cancelled and returns [`DeadlineExceeded`].
+    pub deadline: Option<Duration>,
+    /// Pattern for download file names, in place of the name the URL
+    /// suggests.
+    pub name_template: Option<NameTemplate>,
+    /// When set, only these top-level commands run.
+    pub only: Option<CommandSelection>,
+    /// Top-level commands to leave out of the run.
+    pub skip: Option<CommandSelection>,
+    /// How many pages to keep in memory so that a `click` leading to a page
+    /// already fetched this run reuses it. Zero disables the cache.
+    pub html_cache_size: usize,
+    /// Collect the URLs of the media the script selects instead of
+    /// downloading them, and write nothing to disk; see
+    /// [`MslEngine::listed_media`].
+    pub list_media: bool,
+    /// Most pages the run may fetch. Once it has fetched this many, the
+    /// next navigation ends the run early but successfully.
+    pub max_pages: Option<usize>,
+    /// How many times to fetch the current page again when a `set` that
+    /// reads it comes out empty or a media block finds nothing, for pages
+    /// that fill in their content shortly after loading.
+    pub selector_retries: u32,
+    /// How long to wait before each of those fetches.
+    pub selector_retry_delay: Duration,
+    /// Bytes of a download written to disk at a time; `None` uses
+    /// [`DEFAULT_BUFFER_SIZE`]. Must be non-zero.
+    pub write_buffer_size: Option<usize>,
+    /// `fsync` each download once it's written; see [`FsSink::sync`].
+    pub sync_downloads: bool,
+    /// What to do when a download's path is already taken.
+    pub on_conflict: ConflictPolicy,
+    /// Save each download under the directories of its URL's path, so
+    /// `/img/2023/photo.jpg` goes to `img/2023/photo.jpg` in the save path
+    /// rather than straight into it. The query string is left out of the
+    /// name.
+    pub mirror_paths: bool,
+    /// Hosts pages and media may come from, as [`HostFilter`] patterns.
+    /// Empty allows every host that isn't denied.
+    pub allow_hosts: Vec<String>,
+    /// Hosts pages and media may never come from.
+    pub deny_hosts: Vec<String>,
+    /// Without `allow_hosts`, allow only the host of the first page the
+    /// run opens.
+    pub same_host: bool,
+    /// Most downloads a `media` block or `extract .. into media` runs at
+    /// once from each host. Zero and one both download one at a time per
+    /// host.
+    pub concurrency: usize,
+    /// Most downloads running at once across all hosts. `None` leaves only
+    /// the limit for each host.
+    pub max_downloads: Option<usize>,
+    /// Start with one download at a time from each host and ramp up to
+    /// `concurrency` while downloads succeed, halving the number whenever
+    /// the host answers 429 or a 5xx error. Throttled downloads are tried
+    /// again after a wait that doubles each time.
+    pub adaptive_concurrency: bool,
+    /// Check downloads against the checksums servers send in `Content-MD5`,
+    /// `Digest` or `Content-Digest` headers, downloading a file again once
+    /// when it doesn't match.
+    pub verify_checksums: bool,
+    /// Directory to save the current page's HTML in whenever a `set`,
+    /// `extract`, `media` block or failing `expect` finds nothing, named
+    /// after the page's URL and what was looked for, to see what changed.
+    pub dump_failures: Option<PathBuf>,
+    /// Fail the run when a `set` or `extract` finds nothing, instead of
+    /// warning and recording it in [`RunManifest::empty_extractions`].
+    pub strict: bool,
+    /// How many levels of `<iframe>` documents to fetch along with each
+    /// page, so that `media` and `foreach link in links` see their content
+    /// too. Zero leaves frames alone.
+    pub iframe_depth: usize,
+    /// Most top-level groups to run at once. A group starts at each
+    /// top-level `open` and runs to the next; commands before the first
+    /// `open` run first, on their own. Groups keep their own page and a copy
+    /// of the variables set before them, so a variable one group sets isn't
+    /// seen by the others. Zero and one run the script in order.
+    pub parallel_groups: usize,
+}
+
+impl EngineConfig {
+    /// Whether the include and exclude URL patterns let `url` through.
+    fn allows_url(&self, url: &str) -> bool {
+        (self.include_urls.is_empty() || self.include_urls.iter().any(|regex| regex.is_match(url)))
+            && !self.exclude_urls.iter().any(|regex| regex.is_match(url))
+    }
+}
+
+/// Returned when a run stops early because its cancellation token fired.
+#[derive(Debug, thiserror::Error)]
+#[error("run cancelled")]
+pub struct Cancelled;
+
+/// The element a `click` followed, along with every element its selector
+/// matched for `text all` and `attr(..) all`.
+struct Scope {
+    selector: String,
+    element: ElementData,
+    matches: Arc<Vec<ElementData>>,
+}
+
+/// Returned when a run is stopped by [`EngineConfig::deadline`].
+#[derive(Debug, thiserror::Error)]
+#[error("run exceeded its {0:?} deadline")]
+pub struct DeadlineExceeded(pub Duration);
+
+/// Stops the run when a navigation would go over
+/// [`EngineConfig::max_pages`]; the run still counts as a success.
+#[derive(Debug, thiserror::Error)]
+#[error("reached the limit of {0} pages")]
+struct PageLimitReached(usize);
+
+/// Returned when a navigation leads to a host the run may not fetch from.
+/// The navigation is skipped along with the rest of the block it's in, the
+/// current iteration of a `click`, `foreach` or `repeat`; at the top level
+/// it fails the run.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is not on an allowed host")]
+struct OffHost(String);
+
+/// Where media is saved when the script doesn't say.
+const DEFAULT_SAVE_PATH: &str = "./downloaded_media";
+
+/// How many times a download the server throttled is tried again under
+/// [`EngineConfig::adaptive_concurrency`].
+const MAX_THROTTLED_RETRIES: u32 = 3;
+
+/// How long a throttled download waits before it's tried again; the wait
+/// doubles with each further attempt.
+const THROTTLED_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A download written to the sink, to be named and recorded by
+/// [`MslEngine::finish_download`].
+struct FetchedMedia {
+    url: String,
+    /// Directory the download belongs in, for name templates.
+    dir: PathBuf,
+    path: PathBuf,
+    written: u64,
+    hash: Option<String>,
+    /// Checksums the content was verified against.
+    verified: Option<String>,
+}
 
 pub struct MslEngine {
-    scraper: Scraper,
-    variables: HashMap<String, String>,
+    /// Shared with the engines running parallel groups.
+    scraper: Arc<Scraper>,
+    config: EngineConfig,
+    variables: HashMap<String, Value>,
     current_html: Option<String>,
     current_url: Option<String>,
+    /// What links on the current page resolve against; see
+    /// [`Scraper::base_url`].
+    current_base: Option<String>,
+    /// Response headers of the current page, for `header(..)`.
+    current_headers: HeaderMap,
+    /// The current page parsed as JSON, when it's a JSON response.
+    current_json: Option<serde_json::Value>,
+    html_cache: HtmlCache,
+    /// Hosts the run may fetch from, shared with parallel groups so that
+    /// `same_host` pins one host for the whole run.
+    hosts: Arc<Mutex<HostFilter>>,
+    /// Pages fetched towards `max_pages` by this engine and the engines
+    /// running parallel groups.
+    pages_fetched: Arc<AtomicUsize>,
+    /// Where downloads are stored.
+    sink: Arc<dyn MediaSink>,
+    /// Element the enclosing `click` followed; `text` and `attr(..)` read
+    /// from it.
+    scope: Option<Scope>,
+    manifest: RunManifest,
+    stats: RunStats,
+    cancel: CancellationToken,
+    /// Content hash of each kept download, mapped to where it was saved.
+    /// Shared with parallel groups.
+    content_hashes: Arc<Mutex<HashMap<String, PathBuf>>>,
+    /// URLs downloaded by an earlier run being resumed; they are skipped.
+    completed: HashSet<String>,
+    /// Media selected while [`EngineConfig::list_media`] is set.
+    listed: Vec<String>,
+    /// Paths handed to downloads still in progress, so parallel downloads
+    /// with the same name don't pick the same path.
+    claimed_paths: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Woken whenever claimed paths are given up.
+    claims_released: Arc<Notify>,
+    /// What `wait`, retry delays and the deadline wait on.
+    clock: Arc<dyn Clock>,
+    /// `regex(..)` patterns compiled so far, so a pattern in a loop is only
+    /// compiled once. Shared with parallel groups.
+    regexes: Arc<Mutex<HashMap<String, Regex>>>,
 }
 
 impl MslEngine {
     pub fn new() -> Self {
-        Self {
-            scraper: Scraper::new(),
+        Self::with_config(EngineConfig::default()).expect("default engine config is valid")
+    }
+
+    pub fn with_config(config: EngineConfig) -> Result<Self> {
+        if config.write_buffer_size == Some(0) {
+            anyhow::bail!("Write buffer size must be greater than zero");
+        }
+        let html_cache_size = config.html_cache_size;
+        let sink = FsSink {
+            buffer_size: config.write_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            sync: config.sync_downloads,
+        };
+        let hosts = HostFilter::new(&config.allow_hosts, &config.deny_hosts);
+        Ok(Self {
+            scraper: Arc::new(Scraper::with_config(config.scraper.clone())?),
+            config,
             variables: HashMap::new(),
             current_html: None,
             current_url: None,
+            current_base: None,
+            current_headers: HeaderMap::new(),
+            current_json: None,
+            html_cache: HtmlCache::new(html_cache_size),
+            hosts: Arc::new(Mutex::new(hosts)),
+            pages_fetched: Arc::new(AtomicUsize::new(0)),
+            sink: Arc::new(sink),
+            scope: None,
+            manifest: RunManifest::default(),
+            stats: RunStats::default(),
+            cancel: CancellationToken::new(),
+            content_hashes: Arc::new(Mutex::new(HashMap::new())),
+            completed: HashSet::new(),
+            listed: Vec::new(),
+            claimed_paths: Arc::new(Mutex::new(HashSet::new())),
+            claims_released: Arc::new(Notify::new()),
+            clock: Arc::new(TokioClock),
+            regexes: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// What the engine has downloaded so far.
+    pub fn manifest(&self) -> &RunManifest {
+        &self.manifest
+    }
+
+    /// Continues from the manifest of an earlier run: its downloads are kept
+    /// in this run's manifest and not fetched again, while its failures are
+    /// retried.
+    pub fn resume(&mut self, manifest: RunManifest) {
+        for record in &manifest.downloads {
+            self.completed.insert(record.url.clone());
+            if let (Some(hash), None) = (&record.sha256, &record.duplicate_of) {
+                self.content_hashes.lock().unwrap().insert(hash.clone(), record.path.clone());
+            }
+        }
+        self.manifest.downloads.extend(manifest.downloads);
+    }
+
+    /// Token that stops the run when cancelled. Commands and downloads
+    /// already under way finish; nothing new starts.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Cancelled.into());
         }
+        Ok(())
+    }
+
+    /// Adds a hook that sees every request the engine sends and every
+    /// response it gets, e.g. to add headers or log traffic.
+    pub fn add_request_hook(&mut self, hook: impl RequestHook + 'static) {
+        Arc::get_mut(&mut self.scraper)
+            .expect("hooks are added while no run is under way")
+            .add_hook(Arc::new(hook));
+    }
+
+    /// Stores downloads in `sink` instead of the local filesystem.
+    pub fn set_media_sink(&mut self, sink: impl MediaSink + 'static) {
+        self.sink = Arc::new(sink);
+    }
+
+    /// Runs waits, retry delays, the deadline and the scraper's rate limit
+    /// backoff on `clock` instead of real time, e.g. a `MockClock` in
+    /// tests. Fails while the scraper is shared with a run under way.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) -> Result<()> {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        Arc::get_mut(&mut self.scraper)
+            .context("Can't change the clock while a run is under way")?
+            .set_clock(clock.clone());
+        self.clock = clock;
+        Ok(())
+    }
+
+    /// URLs of the media selected so far when listing instead of
+    /// downloading, in the order they were found and without repeats.
+    pub fn listed_media(&self) -> &[String] {
+        &self.listed
+    }
+
+    /// Saves cookies to the scraper's cookie file, if it has one, for the
+    /// next run to pick up.
+    pub fn save_session(&self) -> Result<()> {
+        self.scraper.save_cookies()
+    }
+
+    /// Totals for the work the engine has done so far.
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
     }
 
     pub async fn execute(&mut self, script: MslScript) -> Result<()> {
-        for command in script.commands {
-            self.execute_command(command).await?;
+        self.check_name_template(&script)?;
+        let commands = self.selected_commands(script.commands);
+        
+        let started = Instant::now();
+        let deadline = self.config.deadline;
+        let cancel = self.cancel.clone();
+        let clock = self.clock.clone();
+        let result = {
+            let run = self.execute_top_level(commands);
+            tokio::pin!(run);
+            match deadline {
+                // Stopping through the cancellation token lets a download
+                // that's under way finish rather than leaving it half written
+                Some(deadline) => tokio::select! {
+                    result = &mut run => result,
+                    _ = clock.sleep(deadline) => {
+                        cancel.cancel();
+                        match run.await {
+                            Err(e) if !e.is::<Cancelled>() => Err(e),
+                            _ => Err(DeadlineExceeded(deadline).into()),
+                        }
+                    }
+                },
+                None => run.await,
+            }
+        };
+        let result = match result {
+            Err(e) if e.is::<PageLimitReached>() => {
+                warn!("Stopping: {}", e);
+                Ok(())
+            }
+            result => result,
+        };
+        let result = result.and(self.scraper.flush_page_cache());
+        self.stats.elapsed += started.elapsed();
+        debug!(
+            pages = self.stats.pages,
+            media = self.stats.media,
+            bytes = self.stats.bytes,
+            elapsed_ms = self.stats.elapsed.as_millis() as u64,
+            "run finished"
+        );
+        result
+    }
+
+    /// Drops the top-level commands that `only` and `skip` leave out.
+    fn selected_commands(&self, commands: Vec<MslCommand>) -> Vec<MslCommand> {
+        let (only, skip) = (&self.config.only, &self.config.skip);
+        if only.is_none() && skip.is_none() {
+            return commands;
+        }
+        commands
+            .into_iter()
+            .enumerate()
+            .filter(|(i, command)| {
+                let selected = only.as_ref().is_none_or(|only| only.contains(*i, command))
+                    && !skip.as_ref().is_some_and(|skip| skip.contains(*i, command));
+                if !selected {
+                    debug!("Skipping command {}: {}", i + 1, command.keyword());
+                }
+                selected
+            })
+            .map(|(_, command)| command)
+            .collect()
+    }
+
+    /// Rejects name template tokens that are neither built in nor a variable
+    /// the script could set, before anything is downloaded.
+    fn check_name_template(&self, script: &MslScript) -> Result<()> {
+        let Some(template) = &self.config.name_template else {
+            return Ok(());
+        };
+        let mut defined: HashSet<&str> = naming::BUILTIN_TOKENS.iter().copied().collect();
+        defined.extend(self.variables.keys().map(String::as_str));
+        defined_variables(&script.commands, &mut defined);
+        
+        match template.tokens().iter().find(|token| !defined.contains(token.as_str())) {
+            Some(token) => anyhow::bail!("Unknown token {{{}}} in name template", token),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the script's top-level commands, with its groups in parallel
+    /// when [`EngineConfig::parallel_groups`] allows more than one at once.
+    async fn execute_top_level(&mut self, mut commands: Vec<MslCommand>) -> Result<()> {
+        let first_open = commands.iter().position(|command| matches!(command, MslCommand::Open { .. }));
+        let Some(first_open) = first_open.filter(|_| self.config.parallel_groups > 1) else {
+            return self.execute_commands(commands).await;
+        };
+        let groups = split_groups(commands.split_off(first_open));
+        self.execute_commands(commands).await?;
+        if groups.len() == 1 {
+            return self.execute_commands(groups.into_iter().next().unwrap()).await;
+        }
+
+        debug!("Running {} groups, up to {} at a time", groups.len(), self.config.parallel_groups);
+        let runs: Vec<_> = groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, group)| {
+                let mut engine = self.fork();
+                async move {
+                    let result = engine.execute_commands(group).instrument(debug_span!("group", n = i + 1)).await;
+                    (engine, result)
+                }
+            })
+            .collect();
+        let finished: Vec<_> = futures_util::stream::iter(runs).buffered(self.config.parallel_groups).collect().await;
+
+        let mut first_error = None;
+        for (i, (engine, result)) in finished.into_iter().enumerate() {
+            self.merge(engine);
+            match result {
+                Err(e) if e.is::<PageLimitReached>() => warn!("Stopping group {}: {}", i + 1, e),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+                Ok(()) => {}
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// An engine for one parallel group: it shares this engine's client,
+    /// sink, cancellation token, host filter, page count and content hashes
+    /// and starts with a copy of its variables, but no page.
+    fn fork(&self) -> MslEngine {
+        MslEngine {
+            scraper: self.scraper.clone(),
+            config: self.config.clone(),
+            variables: self.variables.clone(),
+            current_html: None,
+            current_url: None,
+            current_base: None,
+            current_headers: HeaderMap::new(),
+            current_json: None,
+            html_cache: HtmlCache::new(self.config.html_cache_size),
+            hosts: self.hosts.clone(),
+            pages_fetched: self.pages_fetched.clone(),
+            sink: self.sink.clone(),
+            scope: None,
+            manifest: RunManifest::default(),
+            stats: RunStats::default(),
+            cancel: self.cancel.clone(),
+            content_hashes: self.content_hashes.clone(),
+            completed: self.completed.clone(),
+            listed: Vec::new(),
+            claimed_paths: self.claimed_paths.clone(),
+            claims_released: self.claims_released.clone(),
+            clock: self.clock.clone(),
+            regexes: self.regexes.clone(),
+        }
+    }
+
+    /// Takes in what a forked engine did. Its variables and page replace
+    /// this engine's, as if its group had run last.
+    fn merge(&mut self, engine: MslEngine) {
+        self.manifest.downloads.extend(engine.manifest.downloads);
+        self.manifest.failed.extend(engine.manifest.failed);
+        self.manifest.empty_extractions.extend(engine.manifest.empty_extractions);
+        self.stats.pages += engine.stats.pages;
+        self.stats.media += engine.stats.media;
+        self.stats.bytes += engine.stats.bytes;
+        for url in engine.listed {
+            if !self.listed.contains(&url) {
+                self.listed.push(url);
+            }
+        }
+        self.variables.extend(engine.variables);
+        self.current_html = engine.current_html;
+        self.current_url = engine.current_url;
+        self.current_base = engine.current_base;
+        self.current_headers = engine.current_headers;
+        self.current_json = engine.current_json;
+    }
+
+    /// Runs a sequence of commands. A `save to` directly after a `media`
+    /// command names the directory that media command downloads into and
+    /// does nothing else.
+    async fn execute_commands(&mut self, commands: Vec<MslCommand>) -> Result<()> {
+        let mut commands = commands.into_iter().peekable();
+        while let Some(command) = commands.next() {
+            self.check_cancelled()?;
+            let span = debug_span!("command", name = command.keyword());
+            let (pages, media, bytes) = (self.stats.pages, self.stats.media, self.stats.bytes);
+            let started = Instant::now();
+            
+            let mut next_save_path = || match commands.next_if(|next| matches!(next, MslCommand::Save { .. })) {
+                Some(MslCommand::Save { path }) => Some(path),
+                _ => None,
+            };
+            match command {
+                MslCommand::Media { media_blocks } => {
+                    let save_to = next_save_path();
+                    self.execute_media(media_blocks, save_to).instrument(span.clone()).await?;
+                }
+                MslCommand::Extract { source, path, target: ExtractTarget::Media } => {
+                    let save_to = next_save_path();
+                    self.extract_media(source, &path, save_to).instrument(span.clone()).await?;
+                }
+                command => self.execute_command(command).instrument(span.clone()).await?,
+            }
+            
+            span.in_scope(|| {
+                debug!(
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    pages = self.stats.pages - pages,
+                    downloads = self.stats.media - media,
+                    bytes = self.stats.bytes - bytes,
+                    "command finished"
+                )
+            });
         }
         Ok(())
     }
@@ -41,175 +612,1314 @@ impl MslEngine {
             MslCommand::Open { url } => {
                 self.execute_open(url).await?;
             }
-            MslCommand::Click { selector, commands } => {
-                self.execute_click(selector, commands).await?;
+            MslCommand::Click { selector, each, commands } => {
+                self.execute_click(selector, each, commands).await?;
             }
             MslCommand::Set { variable, value } => {
-                self.execute_set(variable, value)?;
+                self.execute_set(variable, value).await?;
             }
             MslCommand::Media { media_blocks } => {
-                self.execute_media(media_blocks).await?;
+                self.execute_media(media_blocks, None).await?;
             }
             MslCommand::Save { path } => {
                 self.execute_save(path).await?;
             }
+            MslCommand::SavePage { path } => {
+                self.save_page(&path).await?;
+            }
             MslCommand::Wait { seconds } => {
-                self.execute_wait(seconds).await?;
+                self.execute_wait(Duration::from_secs(seconds)).await?;
+            }
+            MslCommand::WaitRandom { min, max } => {
+                self.execute_wait(random_delay(min, max)).await?;
+            }
+            MslCommand::Auth { credentials } => {
+                self.execute_auth(credentials)?;
+            }
+            MslCommand::Extract { source, path, target: ExtractTarget::Variable { name } } => {
+                self.extract_variable(source, &path, name).await?;
+            }
+            MslCommand::Extract { source, path, target: ExtractTarget::Media } => {
+                self.extract_media(source, &path, None).await?;
+            }
+            MslCommand::Expect { selector, operator, count } => {
+                self.execute_expect(&selector, &operator, count).await?;
+            }
+            MslCommand::Include { path } => {
+                anyhow::bail!("include \"{}\" was not resolved when the script was parsed", path);
+            }
+            MslCommand::Foreach { variable, source, commands } => {
+                self.execute_foreach(variable, source, commands).await?;
+            }
+            MslCommand::Repeat { times, variable, commands } => {
+                self.execute_repeat(times, variable, commands).await?;
+            }
+            MslCommand::Post { url, fields, json } => {
+                self.execute_post(url, fields, json).await?;
             }
         }
         Ok(())
     }
 
     async fn execute_open(&mut self, url: String) -> Result<()> {
-        println!("Opening: {}", url);
+        let url = self.interpolate(&url)?;
+        info!("Opening: {}", url);
+        
+        // Opening a page always fetches it afresh
+        self.load_page(&url, false).await?;
         
-        let result = self.scraper.fetch_page(&url).await?;
-        // Store the HTML content for later use
-        self.current_html = Some(self.get_html_content(&url).await?);
-        self.current_url = Some(url);
+        let title = self.current_html.as_deref().and_then(|html| self.scraper.title_from_html(html));
+        debug!("Loaded page: {}", title.unwrap_or_else(|| "No title".to_string()));
+        Ok(())
+    }
+
+    /// Fetches `url` and makes it the current page. After a redirect the
+    /// current URL is where the request ended up, so relative links and
+    /// media resolve against that. With `cached` a copy fetched earlier in
+    /// the run is used when there is one; such pages don't count towards
+    /// `max_pages`.
+    async fn load_page(&mut self, url: &str, cached: bool) -> Result<()> {
+        self.check_host(url)?;
+        let page = match self.html_cache.get(url).filter(|_| cached) {
+            Some(page) => {
+                debug!("Using cached page: {}", url);
+                page
+            }
+            None => {
+                self.reserve_page()?;
+                let page = self.scraper.fetch_html_within(url, |target| self.host_allowed(target)).await;
+                let page = self.page_fetched(page)?;
+                self.html_cache.insert(url, page.clone());
+                page
+            }
+        };
+        self.set_page(page);
+        Ok(())
+    }
+
+    /// Makes `page` the current page. A JSON response is parsed so that
+    /// `json(..)` can read it.
+    fn set_page(&mut self, page: Page) {
+        self.current_json = is_json(&page.headers).then(|| serde_json::from_str(&page.html)).and_then(|parsed| {
+            parsed.map_err(|e| warn!("Invalid JSON from {}: {}", page.url, e)).ok()
+        });
+        self.current_base = self.scraper.base_url(&page.html, &page.url).ok();
+        self.current_html = Some(page.html);
+        self.current_url = Some(page.url);
+        self.current_headers = page.headers;
+    }
+
+    /// Fails with [`OffHost`] when `url` isn't on an allowed host. With
+    /// `same_host`, the first URL checked decides the host.
+    fn check_host(&mut self, url: &str) -> Result<()> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if self.config.same_host {
+            hosts.allow_only_host_of(url);
+        }
+        match hosts.allows(url) {
+            true => Ok(()),
+            false => Err(OffHost(url.to_string()).into()),
+        }
+    }
+
+    fn host_allowed(&self, url: &str) -> bool {
+        self.hosts.lock().unwrap().allows(url)
+    }
+
+    /// Counts a page about to be fetched towards `max_pages`, failing with
+    /// [`PageLimitReached`] when the run has none left. Pass the fetch's
+    /// result to [`MslEngine::page_fetched`].
+    fn reserve_page(&self) -> Result<()> {
+        let max = self.config.max_pages;
+        self.pages_fetched
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pages| match max {
+                Some(max) if pages >= max => None,
+                _ => Some(pages + 1),
+            })
+            .map_err(|_| PageLimitReached(max.unwrap_or_default()))?;
+        Ok(())
+    }
+
+    /// Counts a page reserved with [`MslEngine::reserve_page`] as fetched,
+    /// or gives the reservation back when fetching it failed. Fails with
+    /// [`OffHost`] when redirects ended up on a host that isn't allowed.
+    fn page_fetched(&mut self, result: Result<Page>) -> Result<Page> {
+        match result {
+            Ok(page) => {
+                self.stats.pages += 1;
+                if !self.host_allowed(&page.url) {
+                    return Err(OffHost(page.url).into());
+                }
+                Ok(page)
+            }
+            Err(e) => {
+                self.pages_fetched.fetch_sub(1, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    /// Submits a form and makes the response the current page. Responses
+    /// to a POST are never cached.
+    async fn execute_post(&mut self, url: String, fields: Vec<(String, String)>, json: bool) -> Result<()> {
+        let url = self.interpolate(&url)?;
+        let fields = fields
+            .into_iter()
+            .map(|(name, value)| Ok((name, self.interpolate(&value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        info!("Posting {} fields to: {}", fields.len(), url);
         
-        println!("Loaded page: {}", result.title.unwrap_or_else(|| "No title".to_string()));
+        self.check_host(&url)?;
+        self.reserve_page()?;
+        let page = self.scraper.post_html(&url, &fields, json).await;
+        let page = self.page_fetched(page)?;
+        self.set_page(page);
         Ok(())
     }
 
-    async fn execute_click(&mut self, selector: String, commands: Vec<MslCommand>) -> Result<()> {
+    /// Follows the first link matching `selector`, or every one when `each`
+    /// is set, running `commands` on each page it reaches with the link as
+    /// the element `text` and `attr(..)` read from.
+    async fn execute_click(&mut self, selector: String, each: bool, commands: Vec<MslCommand>) -> Result<()> {
         let html = self.current_html.as_ref()
             .context("No page loaded. Use 'open' first.")?;
         
-        // Extract links matching the selector
-        let links = self.scraper.extract_attribute(html, &selector, "href")?;
-        
-        if links.is_empty() {
-            println!("No links found for selector: {}", selector);
+        // Collect the links up front so following one doesn't lose the rest
+        let matches = Arc::new(self.scraper.select_elements(html, &selector)?);
+        let mut elements: Vec<ElementData> = matches
+            .iter()
+            .filter(|element| element.attributes.contains_key("href"))
+            .cloned()
+            .collect();
+        if elements.is_empty() {
+            warn!("No links found for selector: {}", selector);
             return Ok(());
         }
+        if !each {
+            elements.truncate(1);
+        }
+        
+        for element in elements {
+            self.check_cancelled()?;
+            let link = self.absolute_url(&element.attributes["href"]);
+            info!("Following link: {}", link);
+            
+            // Fetch the new page
+            match self.load_page(&link, true).await {
+                Err(e) if e.is::<OffHost>() => {
+                    warn!("Skipping link: {}", e);
+                    continue;
+                }
+                result => result?,
+            }
+            
+            // Execute nested commands against the followed element
+            let outer_scope = self.scope.replace(Scope { selector: selector.clone(), element, matches: matches.clone() });
+            let result = Box::pin(self.execute_commands(commands.clone())).await;
+            self.scope = outer_scope;
+            result?;
+        }
+        
+        Ok(())
+    }
 
-        // For now, follow the first link. In a more sophisticated version,
-        // we could follow all links or implement pagination
-        let link = &links[0];
-        println!("Following link: {}", link);
+    /// Runs `commands` once per item of `source`. While iterating over
+    /// links, `text` and `attr(..)` read the current link, as under a `click`.
+    async fn execute_foreach(&mut self, variable: String, source: ForeachSource, commands: Vec<MslCommand>) -> Result<()> {
+        // Collect the items up front so the body can navigate freely
+        let (items, links) = match &source {
+            ForeachSource::Links => {
+                let frames = self.frame_pages().await?;
+                let html = self.current_html.as_ref()
+                    .context("No page loaded. Use 'open' first.")?;
+                let current_url = self.current_url.as_ref()
+                    .context("No current URL")?;
+                let mut links = self.scraper.links_from_html(html, current_url)?;
+                for frame in &frames {
+                    for mut link in self.scraper.links_from_html(&frame.html, &frame.url)? {
+                        if !links.iter().any(|known| known.url == link.url) {
+                            link.frame = Some(frame.url.clone());
+                            links.push(link);
+                        }
+                    }
+                }
+                let items = links.iter().map(|link| link.url.clone()).collect();
+                let elements: Vec<ElementData> = links
+                    .into_iter()
+                    .map(|link| ElementData { text: link.text, attributes: link.attributes, source: link.source })
+                    .collect();
+                (items, Some(Arc::new(elements)))
+            }
+            ForeachSource::Variable { name } => match self.variables.get(name) {
+                Some(Value::List(items)) => (items.clone(), None),
+                Some(Value::Scalar(_)) => anyhow::bail!("{} is not a list; set it with `text all` or `attr(..) all`", name),
+                None => anyhow::bail!("Undefined variable {} in foreach", name),
+            },
+        };
+        info!("Iterating over {} {}", items.len(), source);
         
-        // Fetch the new page
-        let result = self.scraper.fetch_page(link).await?;
-        self.current_html = Some(self.get_html_content(link).await?);
-        self.current_url = Some(link.clone());
+        for (i, item) in items.into_iter().enumerate() {
+            self.variables.insert(variable.clone(), item.into());
+            let Some(links) = &links else {
+                skip_off_host(Box::pin(self.execute_commands(commands.clone())).await)?;
+                continue;
+            };
+            let outer_scope = self.scope.replace(Scope { selector: "a[href]".to_string(), element: links[i].clone(), matches: links.clone() });
+            let result = Box::pin(self.execute_commands(commands.clone())).await;
+            self.scope = outer_scope;
+            skip_off_host(result)?;
+        }
         
-        // Execute nested commands
-        for command in commands {
-            Box::pin(self.execute_command_sync(command)).await?;
+        Ok(())
+    }
+
+    async fn execute_repeat(&mut self, times: u64, variable: String, commands: Vec<MslCommand>) -> Result<()> {
+        for i in 1..=times {
+            self.variables.insert(variable.clone(), i.to_string().into());
+            skip_off_host(Box::pin(self.execute_commands(commands.clone())).await)?;
         }
         
         Ok(())
     }
 
-    fn execute_set(&mut self, variable: String, value: MslValue) -> Result<()> {
-        let html = self.current_html.as_ref()
-            .context("No page loaded. Use 'open' first.")?;
+    /// Switches the credentials sent with later requests. Values may use
+    /// `{variable}` placeholders.
+    fn execute_auth(&mut self, credentials: Credentials) -> Result<()> {
+        let credentials = match credentials {
+            Credentials::Basic { username, password } => {
+                let username = self.interpolate(&username)?;
+                debug!("Using basic auth as {}", username);
+                Credentials::Basic {
+                    username,
+                    password: self.interpolate(&password)?,
+                }
+            }
+            Credentials::Bearer { token } => {
+                debug!("Using bearer token auth");
+                Credentials::Bearer { token: self.interpolate(&token)? }
+            }
+        };
+        // Once a page is open the credentials are for its host, otherwise
+        // for the next one opened
+        self.scraper.set_auth(Some(credentials), self.current_url.as_deref());
+        Ok(())
+    }
+
+    async fn execute_set(&mut self, variable: String, value_source: MslValue) -> Result<()> {
+        let mut attempt = 0;
+        let value = loop {
+            let resolved = self.evaluate(&value_source)?;
+            if !resolved.is_empty() || !reads_page(&value_source) || !self.refetch_for_retry(attempt).await? {
+                break resolved;
+            }
+            attempt += 1;
+        };
+        if value.is_empty() && extracts(&value_source) {
+            let selector = reads_element(&value_source).then(|| self.scope.as_ref().map(|scope| scope.selector.clone())).flatten();
+            self.empty_extraction(&variable, value_source.to_string(), selector).await?;
+        }
         
-        let extracted_value = match value {
-            MslValue::Text => {
-                // For text extraction, we need a selector from the context
-                // This is a simplified version - in practice, we'd need to track the current selector
-                "".to_string() // Placeholder
+        debug!("Set variable: {} = {}", variable, value);
+        self.variables.insert(variable, value);
+        Ok(())
+    }
+
+    /// Records that `variable` came out empty, or with
+    /// [`EngineConfig::strict`] fails the run.
+    async fn empty_extraction(&mut self, variable: &str, source: String, selector: Option<String>) -> Result<()> {
+        let empty = EmptyExtraction {
+            variable: variable.to_string(),
+            source,
+            selector,
+            url: self.current_url.clone(),
+        };
+        self.dump_failure(empty.selector.as_deref().unwrap_or(&empty.source)).await;
+        if self.config.strict {
+            anyhow::bail!("{}", empty);
+        }
+        warn!("{}", empty);
+        self.manifest.empty_extractions.push(empty);
+        Ok(())
+    }
+
+    fn evaluate(&self, value: &MslValue) -> Result<Value> {
+        Ok(match value {
+            MslValue::All { source } => Value::List(self.resolve_list(source)?),
+            // Setting one variable to another copies lists as well
+            MslValue::Variable { name } if matches!(self.variables.get(name), Some(Value::List(_))) => {
+                self.variables[name].clone()
             }
-            MslValue::Attribute { name } => {
-                // Similar to text, we need a selector
-                "".to_string() // Placeholder
+            value => Value::Scalar(self.resolve_value(value)?.unwrap_or_default()),
+        })
+    }
+
+    /// Waits and fetches the current page again, unless `attempt` retries
+    /// have already been made. Returns whether it did.
+    async fn refetch_for_retry(&mut self, attempt: u32) -> Result<bool> {
+        let retries = self.config.selector_retries;
+        let Some(url) = self.current_url.clone().filter(|_| attempt < retries) else {
+            return Ok(false);
+        };
+        debug!("Nothing found; fetching {} again (retry {} of {})", url, attempt + 1, retries);
+        self.execute_wait(self.config.selector_retry_delay).await?;
+        self.load_page(&url, false).await?;
+        Ok(true)
+    }
+
+    /// Saves the current page to [`EngineConfig::dump_failures`] after
+    /// `looked_for` found nothing on it. Failing to save it is only logged.
+    async fn dump_failure(&self, looked_for: &str) {
+        let (Some(dir), Some(html)) = (&self.config.dump_failures, &self.current_html) else {
+            return;
+        };
+        let url = self.current_url.as_deref().unwrap_or("page");
+        let path = dir.join(failure_file_name(url, looked_for));
+        let written = async {
+            fs::create_dir_all(dir).await?;
+            fs::write(&path, html).await
+        };
+        match written.await {
+            Ok(()) => info!("Saved {} to {} for inspection", url, path.display()),
+            Err(e) => warn!("Failed to save {} to {}: {}", url, path.display(), e),
+        }
+    }
+
+    /// Evaluates `source` against every element the enclosing `click`
+    /// matched, skipping elements it isn't available for. A `json(..)`
+    /// source lists every value its path selects instead.
+    fn resolve_list(&self, source: &MslValue) -> Result<Vec<String>> {
+        if let MslValue::Json { path } = source {
+            return Ok(self.json_values(path)?.into_iter().map(json_value_text).collect());
+        }
+        if let MslValue::Within { selector, value } = source {
+            return Ok(self.select_scoped(selector)?.iter().filter_map(|element| element_value(element, value)).collect());
+        }
+        let Some(scope) = &self.scope else {
+            return Ok(Vec::new());
+        };
+        Ok(scope.matches.iter().filter_map(|element| element_value(element, source)).collect())
+    }
+
+    /// Elements matching `selector` inside the element of the enclosing
+    /// `click` or `foreach`, or on the whole current page outside one.
+    fn select_scoped(&self, selector: &str) -> Result<Vec<ElementData>> {
+        match (&self.scope, &self.current_html) {
+            (Some(scope), _) => self.scraper.select_within(&scope.element, selector),
+            (None, Some(html)) => self.scraper.select_elements(html, selector),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+
+    /// Values at `path` in the current JSON response; none when the current
+    /// page isn't JSON.
+    fn json_values(&self, path: &str) -> Result<Vec<&serde_json::Value>> {
+        let path = JsonPath::parse(path)?;
+        Ok(self.current_json.as_ref().map(|json| path.select(json)).unwrap_or_default())
+    }
+
+    /// Evaluates a `set` value. `text` and `attr(..)` read the element of
+    /// the enclosing `click` and `url` and `title` the current page; `None`
+    /// means the value isn't available. Using a list variable is an error.
+    fn resolve_value(&self, value: &MslValue) -> Result<Option<String>> {
+        let resolved = match value {
+            MslValue::Text | MslValue::Html | MslValue::OuterHtml => self.scope.as_ref().and_then(|scope| element_value(&scope.element, value)),
+            MslValue::Url => self.current_url.clone(),
+            MslValue::Title => self.current_html.as_deref().and_then(|html| self.scraper.title_from_html(html)),
+            MslValue::Attribute { name } => self.scope.as_ref().and_then(|scope| scope.element.attributes.get(name).cloned()),
+            MslValue::Within { selector, value } => self.select_scoped(selector)?.first().and_then(|element| element_value(element, value)),
+            MslValue::Header { name } => self.current_headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            MslValue::Split { source, delimiter, index } => self.resolve_value(source)?.and_then(|source| {
+                let pieces: Vec<&str> = source.split(delimiter.as_str()).collect();
+                let index = if *index < 0 {
+                    pieces.len().checked_sub(index.unsigned_abs() as usize)?
+                } else {
+                    *index as usize
+                };
+                pieces.get(index).map(|piece| piece.to_string())
+            }),
+            MslValue::Literal { value } => Some(self.interpolate(value)?),
+            MslValue::Variable { name } => match self.variables.get(name) {
+                Some(Value::List(_)) => anyhow::bail!("{} is a list; use it with foreach, not as a single value", name),
+                Some(value) => Some(value.to_string()),
+                None => None,
+            },
+            MslValue::All { source } => Some(Value::List(self.resolve_list(source)?).to_string()),
+            MslValue::Json { path } => self.json_values(path)?.first().map(|value| json_value_text(value)),
+            MslValue::Concat { parts } => {
+                let mut joined = String::new();
+                for part in parts {
+                    joined.push_str(&self.resolve_value(part)?.unwrap_or_default());
+                }
+                Some(joined)
             }
-            MslValue::Split { delimiter, index } => {
-                // This would split a previously extracted value
-                "".to_string() // Placeholder
+            MslValue::Fallback { value, fallback } => match self.resolve_value(value)? {
+                Some(value) if !value.is_empty() => Some(value),
+                _ => self.resolve_value(fallback)?,
+            },
+            MslValue::Transform { source, transforms } => self.resolve_value(source)?.map(|source| {
+                transforms.iter().fold(source, |value, transform| apply_transform(&value, transform))
+            }),
+            MslValue::Regex { source, pattern, group } => {
+                let regex = self.regex(pattern)?;
+                self.resolve_value(source)?.and_then(|source| {
+                    regex.captures(&source)?.get(*group).map(|capture| capture.as_str().to_string())
+                })
             }
         };
+        Ok(resolved)
+    }
+
+    /// `pattern` compiled, from the cache once it has been.
+    fn regex(&self, pattern: &str) -> Result<Regex> {
+        let mut regexes = self.regexes.lock().unwrap();
+        if let Some(regex) = regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(pattern).with_context(|| format!("Invalid regex: {}", pattern))?;
+        regexes.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+
+    /// Checks how many elements on the current page match `selector`, so a
+    /// layout change fails the run instead of silently finding nothing.
+    async fn execute_expect(&self, selector: &str, operator: &str, count: usize) -> Result<()> {
+        let html = self.current_html.as_ref()
+            .context("No page loaded. Use 'open' first.")?;
+        let found = self.scraper.select_elements(html, selector)?.len();
         
-        let variable_clone = variable.clone();
-        let extracted_value_clone = extracted_value.clone();
-        self.variables.insert(variable, extracted_value);
-        println!("Set variable: {} = {}", variable_clone, extracted_value_clone);
+        let holds = match operator {
+            "=" => found == count,
+            "!=" => found != count,
+            ">=" => found >= count,
+            "<=" => found <= count,
+            ">" => found > count,
+            "<" => found < count,
+            _ => anyhow::bail!("Unknown comparison in expect: {}", operator),
+        };
+        if !holds {
+            if found == 0 {
+                self.dump_failure(selector).await;
+            }
+            anyhow::bail!(
+                "expect \"{}\" count {} {} failed: found {} on {}",
+                selector,
+                operator,
+                count,
+                found,
+                self.current_url.as_deref().unwrap_or("the current page")
+            );
+        }
         Ok(())
     }
 
-    async fn execute_media(&mut self, media_blocks: Vec<crate::parser::MediaBlock>) -> Result<()> {
+    /// Values at `path` in the page's structured data, with arrays unpacked.
+    fn extract_values(&self, source: ExtractSource, path: &str) -> Result<Vec<serde_json::Value>> {
         let html = self.current_html.as_ref()
             .context("No page loaded. Use 'open' first.")?;
+        let path = JsonPath::parse(path)?;
         
-        let current_url = self.current_url.as_ref()
-            .context("No current URL")?;
+        let documents = match source {
+            ExtractSource::JsonLd => self.scraper.extract_json_ld(html)?,
+        };
+        let values = documents
+            .iter()
+            .flat_map(|document| path.select(document))
+            .flat_map(|value| match value {
+                serde_json::Value::Array(items) => items.clone(),
+                value => vec![value.clone()],
+            })
+            .collect();
+        
+        Ok(values)
+    }
+
+    async fn extract_variable(&mut self, source: ExtractSource, path: &str, variable: String) -> Result<()> {
+        let Some(value) = self.extract_values(source, path)?.first().map(json_value_text) else {
+            self.empty_extraction(&variable, format!("jsonld \"{}\"", path), None).await?;
+            self.variables.insert(variable, String::new().into());
+            return Ok(());
+        };
         
-        // Extract all media from the current page
-        let all_media = self.scraper.extract_media_from_html(html, current_url).await?;
+        debug!("Set variable: {} = {}", variable, value);
+        self.variables.insert(variable, value.into());
+        Ok(())
+    }
+
+    /// Downloads the URLs found at `path`. Objects such as schema.org
+    /// `ImageObject`s contribute their `contentUrl` or `url`.
+    async fn extract_media(&mut self, source: ExtractSource, path: &str, save_to: Option<String>) -> Result<()> {
+        let urls: Vec<String> = self.extract_values(source, path)?
+            .iter()
+            .filter_map(|value| {
+                let url = match value {
+                    serde_json::Value::Object(map) => map.get("contentUrl").or_else(|| map.get("url"))?,
+                    value => value,
+                };
+                url.as_str().map(|url| self.absolute_url(url))
+            })
+            .filter(|url| self.config.allows_url(url))
+            .collect();
+        info!("Found {} media URLs at {}", urls.len(), path);
         
+        let save_path = self.resolve_save_path(save_to.as_deref().unwrap_or(DEFAULT_SAVE_PATH))?;
+        let items = urls
+            .into_iter()
+            .map(|url| MediaItem {
+                media_type: crate::scraper::media_type_for_url(&url),
+                url,
+                filename: None,
+                attributes: HashMap::new(),
+                content_length: None,
+                content_type: None,
+                frame: None,
+            })
+            .collect();
+        self.download_all(items, &save_path).await
+    }
+
+    /// Resolves a possibly relative link against the current page, or its
+    /// `<base href>` when it has one.
+    fn absolute_url(&self, link: &str) -> String {
+        self.current_base
+            .as_deref()
+            .and_then(|base| url::Url::parse(base).ok())
+            .and_then(|base| base.join(link).ok())
+            .map(|url| url.to_string())
+            .unwrap_or_else(|| link.to_string())
+    }
+
+    async fn execute_media(&mut self, media_blocks: Vec<crate::parser::MediaBlock>, save_to: Option<String>) -> Result<()> {
         for block in media_blocks {
-            let filtered_media = self.scraper.filter_media(&all_media, &block.filters);
+            let mut attempt = 0;
+            let filtered_media = loop {
+                let found = self.find_media(&block).await?;
+                if !found.is_empty() || !self.refetch_for_retry(attempt).await? {
+                    break found;
+                }
+                attempt += 1;
+            };
+            if filtered_media.is_empty() {
+                self.dump_failure(&format!("{} media", block.media_type)).await;
+            }
+            let filtered_media = self.order_media(&block, filtered_media).await;
             
-            println!("Found {} {} items", filtered_media.len(), match block.media_type {
+            info!("Found {} {} items", filtered_media.len(), match block.media_type {
                 crate::parser::MediaType::Image => "image",
                 crate::parser::MediaType::Video => "video", 
                 crate::parser::MediaType::Audio => "audio",
             });
             
-            // For now, we'll use a default save path since the save command is separate
-            // In a more sophisticated version, we'd track the save path from the save command
-            let save_path = "./downloaded_media";
+            // Resolved on every run of the command, so inside a `foreach`
+            // the placeholders take the current iteration's values
+            let save_path = block.save_path.as_deref().or(save_to.as_deref()).unwrap_or(DEFAULT_SAVE_PATH);
+            let save_path = self.resolve_save_path(save_path)?;
             
-            // Download media items
-            for media_item in filtered_media {
-                self.download_media(&media_item, save_path).await?;
-            }
+            self.download_all(filtered_media, &save_path).await?;
         }
         
         Ok(())
     }
 
-    async fn execute_save(&mut self, path: String) -> Result<()> {
-        // This would save the current page or extracted data
-        println!("Saving to: {}", path);
-        Ok(())
-    }
+    /// The media on the current page that `block` selects.
+    async fn find_media(&mut self, block: &MediaBlock) -> Result<Vec<MediaItem>> {
+        let frames = self.frame_pages().await?;
+        let html = self.current_html.as_ref()
+            .context("No page loaded. Use 'open' first.")?;
+        
+        let current_url = self.current_url.as_ref()
+            .context("No current URL")?;
+        
+        // Extract all media from the current page and its frames
+        let mut all_media = self.scraper.extract_media_from_html(html, current_url).await?;
+        let mut linked_media = self.scraper.extract_linked_media(html, current_url)?;
+        for frame in &frames {
+            let from_frame = |mut item: MediaItem| {
+                item.frame = Some(frame.url.clone());
+                item
+            };
+            all_media.extend(self.scraper.extract_media_from_html(&frame.html, &frame.url).await?.into_iter().map(from_frame));
+            linked_media.extend(self.scraper.extract_linked_media(&frame.html, &frame.url)?.into_iter().map(from_frame));
+        }
+        
+        // Each block only takes media of its own type, so blocks in one
+        // command can save to different places
+        let media_type = crate::scraper::MediaType::from(&block.media_type);
+        all_media.retain(|item| item.media_type == media_type);
 
-    async fn execute_wait(&mut self, seconds: u64) -> Result<()> {
-        println!("Waiting for {} seconds...", seconds);
-        tokio::time::sleep(tokio::time::Duration::from_secs(seconds)).await;
-        println!("Wait completed.");
-        Ok(())
+        // Files linked with `<a href>` are only candidates for blocks that
+        // name the extensions they want
+        let has_extensions = block.filters.iter().any(|filter| matches!(filter, MediaFilter::Extensions { .. }));
+        let candidates: Vec<MediaItem> = if has_extensions {
+            let linked = linked_media.iter().filter(|item| {
+                item.media_type == media_type && !all_media.iter().any(|embedded| embedded.url == item.url)
+            });
+            all_media.iter().chain(linked).cloned().collect()
+        } else {
+            all_media
+        };
+        let candidates: Vec<MediaItem> = candidates
+            .into_iter()
+            .filter(|item| self.config.allows_url(&item.url))
+            .collect();
+        Ok(self.select_media(block, &candidates).await)
     }
 
-    async fn download_media(&self, media_item: &crate::scraper::MediaItem, base_path: &str) -> Result<()> {
-        let url = &media_item.url;
-        let filename = self.generate_filename(url, &media_item.media_type);
+    /// Documents the current page embeds with `<iframe src>`, and those they
+    /// embed in turn, down to [`EngineConfig::iframe_depth`] levels. Frames
+    /// on hosts the run may not fetch from or that fail to load are left
+    /// out, and no more are fetched once the page limit is reached.
+    async fn frame_pages(&mut self) -> Result<Vec<Page>> {
+        let mut frames = Vec::new();
+        let (Some(html), Some(url)) = (&self.current_html, &self.current_url) else {
+            return Ok(frames);
+        };
+        if self.config.iframe_depth == 0 {
+            return Ok(frames);
+        }
+        let mut seen = HashSet::from([url.clone()]);
+        let mut level = self.scraper.iframe_urls(html, url)?;
         
-        // Create directory if it doesn't exist
-        let dir = Path::new(base_path);
-        if !dir.exists() {
-            fs::create_dir_all(dir).await.context("Failed to create directory")?;
+        for _ in 0..self.config.iframe_depth {
+            let mut next_level = Vec::new();
+            for frame_url in level {
+                if !seen.insert(frame_url.clone()) {
+                    continue;
+                }
+                if !self.host_allowed(&frame_url) {
+                    debug!("Skipping iframe on a host that isn't allowed: {}", frame_url);
+                    continue;
+                }
+                let page = match self.html_cache.get(&frame_url) {
+                    Some(page) => page,
+                    None => {
+                        if let Err(e) = self.reserve_page() {
+                            info!("Not following iframe {}: {}", frame_url, e);
+                            return Ok(frames);
+                        }
+                        let page = self.scraper.fetch_html_within(&frame_url, |target| self.host_allowed(target)).await;
+                        match self.page_fetched(page) {
+                            Ok(page) => {
+                                self.html_cache.insert(&frame_url, page.clone());
+                                page
+                            }
+                            Err(e) => {
+                                warn!("Failed to load iframe {}: {:#}", frame_url, e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                debug!("Following iframe: {}", page.url);
+                next_level.extend(self.scraper.iframe_urls(&page.html, &page.url)?);
+                frames.push(page);
+            }
+            level = next_level;
         }
+        Ok(frames)
+    }
+
+    /// Applies a block's filters to the page's media. Filters on `size` or
+    /// `type` need response headers, so those run last and only HEAD the
+    /// items that passed the other filters.
+    async fn select_media(&self, block: &MediaBlock, all_media: &[MediaItem]) -> Vec<MediaItem> {
+        let (head_filters, page_filters): (Vec<MediaFilter>, Vec<MediaFilter>) = block
+            .filters
+            .iter()
+            .cloned()
+            .partition(needs_head);
         
-        let file_path = dir.join(&filename);
+        let mut selected = self.scraper.filter_media(all_media, &page_filters);
+        if head_filters.is_empty() {
+            return selected;
+        }
         
-        println!("Downloading: {} -> {}", url, file_path.display());
+        for item in &mut selected {
+            self.fill_head_info(item).await;
+        }
         
-        // Download the file
-        let response = self.scraper.client.get(url).send().await
-            .context("Failed to download media")?;
+        self.scraper.filter_media(&selected, &head_filters)
+    }
+
+    /// Applies a block's `sort by` and then its `limit`. Sorting by size
+    /// HEADs items whose size isn't known yet; items whose size stays
+    /// unknown go last. Ties keep document order.
+    async fn order_media(&self, block: &MediaBlock, mut media: Vec<MediaItem>) -> Vec<MediaItem> {
+        if let Some(sort) = block.sort {
+            match sort.key {
+                SortKey::Size => {
+                    for item in media.iter_mut().filter(|item| item.content_length.is_none()) {
+                        self.fill_head_info(item).await;
+                    }
+                    media.sort_by(|a, b| match (a.content_length, b.content_length) {
+                        (Some(a), Some(b)) if sort.descending => b.cmp(&a),
+                        (a, b) => a.is_none().cmp(&b.is_none()).then(a.cmp(&b)),
+                    });
+                }
+                SortKey::Url if sort.descending => media.sort_by(|a, b| b.url.cmp(&a.url)),
+                SortKey::Url => media.sort_by(|a, b| a.url.cmp(&b.url)),
+            }
+        }
+        if let Some(limit) = block.limit {
+            media.truncate(limit);
+        }
+        media
+    }
+
+    /// Records the size and type a HEAD request reports for `item`.
+    async fn fill_head_info(&self, item: &mut MediaItem) {
+        match self.scraper.head(&item.url).await {
+            Ok(info) => {
+                item.content_length = info.content_length;
+                item.content_type = info.content_type;
+            }
+            Err(e) => warn!("HEAD request failed for {}: {}", item.url, e),
+        }
+    }
+
+    /// A `save to` that isn't a media destination saves the current page
+    /// when its path names a file, such as `page.html`.
+    async fn execute_save(&mut self, path: String) -> Result<()> {
+        let is_file = Path::new(&path).extension().is_some();
+        if is_file {
+            return self.save_page(&path).await;
+        }
         
-        let mut file = fs::File::create(&file_path).await
-            .context("Failed to create file")?;
+        debug!("Saving to: {}", path);
+        Ok(())
+    }
+
+    async fn save_page(&mut self, path: &str) -> Result<()> {
+        let html = self.current_html.as_ref()
+            .context("No page loaded. Use 'open' first.")?;
         
-        let bytes = response.bytes().await.context("Failed to read response bytes")?;
-        tokio::io::copy(&mut std::io::Cursor::new(bytes), &mut file).await
-            .context("Failed to write file")?;
+        let file_path = self.resolve_save_path(path)?;
+        if self.config.list_media {
+            debug!("Listing media only; not saving page to {}", file_path.display());
+            return Ok(());
+        }
+        if let Some(parent) = file_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).await.context("Failed to create directory")?;
+        }
+        fs::write(&file_path, html).await.context("Failed to write page")?;
         
-        println!("Downloaded: {}", file_path.display());
+        info!("Saved page: {}", file_path.display());
         Ok(())
     }
 
-    async fn get_html_content(&self, url: &str) -> Result<String> {
-        self.scraper.get_html_content(url).await
+    async fn execute_wait(&mut self, duration: Duration) -> Result<()> {
+        debug!("Waiting for {:.2} seconds...", duration.as_secs_f64());
+        tokio::select! {
+            _ = self.clock.sleep(duration) => {}
+            _ = self.cancel.cancelled() => return Err(Cancelled.into()),
+        }
+        trace!("Wait completed.");
+        Ok(())
     }
 
-    fn generate_filename(&self, url: &str, media_type: &crate::scraper::MediaType) -> String {
-        // Extract filename from URL or generate one
-        let filename = url.split('/').last().unwrap_or("unknown");
+    /// Downloads `items` into `dir`, up to [`EngineConfig::concurrency`] at
+    /// a time from each host and [`EngineConfig::max_downloads`] in all, or
+    /// only records their URLs when listing media. Unless the run is
+    /// configured to fail fast, failures are logged and recorded in the
+    /// manifest rather than returned.
+    ///
+    /// Downloads in progress share the engine, so each is only named and
+    /// recorded once the batch is over.
+    async fn download_all(&mut self, items: Vec<MediaItem>, dir: &Path) -> Result<()> {
+        let mut queue: VecDeque<(MediaItem, u32)> = VecDeque::new();
+        for item in items {
+            if self.should_download(&item) {
+                queue.push_back((item, 0));
+            }
+        }
+        let mut limits = HostLimits::new(self.config.concurrency, self.config.adaptive_concurrency, self.config.max_downloads);
+        let mut fetched = Vec::new();
+        let mut stopped = None;
+
+        let this = &*self;
+        let mut in_flight = FuturesUnordered::new();
+        let mut backing_off = FuturesUnordered::new();
+        'batch: loop {
+            // Start whatever the limits of the hosts involved allow, in order
+            let mut next = 0;
+            while next < queue.len() {
+                let host = hosts::host_of(&queue[next].0.url).unwrap_or_default();
+                if !limits.try_start(&host) {
+                    next += 1;
+                    continue;
+                }
+                let (item, attempt) = queue.remove(next).unwrap();
+                if let Err(e) = this.check_cancelled() {
+                    stopped = Some(e);
+                    break 'batch;
+                }
+                in_flight.push(async move {
+                    let result = this.fetch_media(&item, dir).await;
+                    (host, item, attempt, result)
+                });
+            }
+            let (host, item, attempt, result) = tokio::select! {
+                Some(done) = in_flight.next() => done,
+                Some(retry) = backing_off.next() => {
+                    queue.push_back(retry);
+                    continue;
+                }
+                else => break,
+            };
+            let limit = limits.finished(&host);
+            match result.as_ref().err().filter(|_| limit.is_adaptive()).and_then(throttled_status) {
+                Some(status) => {
+                    limit.throttled();
+                    if attempt < MAX_THROTTLED_RETRIES {
+                        info!("{} answered {}; downloading at most {} at a time from {}", item.url, status, limit.current(), host);
+                        let delay = THROTTLED_RETRY_DELAY * 2u32.pow(attempt);
+                        let sleep = this.clock.sleep(delay);
+                        backing_off.push(async move {
+                            sleep.await;
+                            (item, attempt + 1)
+                        });
+                        continue;
+                    }
+                }
+                None if result.is_ok() => limit.succeeded(),
+                None => {}
+            }
+            let failed = result.is_err();
+            fetched.push((item, result));
+            if failed && this.config.fail_fast {
+                break;
+            }
+        }
+        // Downloads already under way are seen through rather than dropped
+        // halfway, which would leave partial files and claimed paths behind
+        while let Some((_, item, _, result)) = in_flight.next().await {
+            fetched.push((item, result));
+        }
+        drop(in_flight);
+
+        let mut failure = None;
+        for (item, result) in fetched {
+            let result = match result {
+                Ok(Some(fetched)) => self.finish_download(fetched).await,
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result.or_else(|e| self.download_failed(&item, e)) {
+                failure.get_or_insert(e);
+            }
+        }
+        failure.or(stopped).map_or(Ok(()), Err)
+    }
+
+    /// Whether `media_item` still needs downloading: it wasn't downloaded
+    /// by the run being resumed, its host is allowed, and media is being
+    /// downloaded rather than listed.
+    fn should_download(&mut self, media_item: &MediaItem) -> bool {
+        if self.completed.contains(&media_item.url) {
+            debug!("Already downloaded: {}", media_item.url);
+            return false;
+        }
+        if !self.host_allowed(&media_item.url) {
+            warn!("Skipping {}: not on an allowed host", media_item.url);
+            return false;
+        }
+        if self.config.list_media {
+            if !self.listed.contains(&media_item.url) {
+                self.listed.push(media_item.url.clone());
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Records a failed download, or returns the error when failing fast.
+    fn download_failed(&mut self, media_item: &MediaItem, e: anyhow::Error) -> Result<()> {
+        if self.config.fail_fast {
+            return Err(e);
+        }
+        warn!("Download failed for {}: {:#}", media_item.url, e);
+        self.manifest.failed.push(FailedDownload {
+            url: media_item.url.clone(),
+            error: format!("{:#}", e),
+        });
+        Ok(())
+    }
+
+    /// Downloads `media_item` into the sink, or returns `None` when it's
+    /// skipped. A download that fails checksum verification is tried once
+    /// more.
+    async fn fetch_media(&self, media_item: &MediaItem, dir: &Path) -> Result<Option<FetchedMedia>> {
+        let mut retried = false;
+        loop {
+            let mut claims = Vec::new();
+            let fetched = self.fetch_into_sink(media_item, dir, &mut claims).await;
+            self.release_claims(claims);
+            match fetched {
+                Err(e) if !retried && e.is::<ChecksumMismatch>() => {
+                    warn!("{}: {}; downloading it again", media_item.url, e);
+                    retried = true;
+                }
+                fetched => return fetched,
+            }
+        }
+    }
+
+    async fn fetch_into_sink(&self, media_item: &MediaItem, dir: &Path, claims: &mut Vec<PathBuf>) -> Result<Option<FetchedMedia>> {
+        let url = &media_item.url;
+        let (dir, filename) = match url::Url::parse(url).ok().filter(|_| self.config.mirror_paths) {
+            Some(parsed) => {
+                let mut segments: Vec<&str> = parsed.path_segments().map(Iterator::collect).unwrap_or_default();
+                let last = segments.pop().unwrap_or_default();
+                let mirrored = segments
+                    .into_iter()
+                    .filter(|segment| !segment.is_empty())
+                    .fold(dir.to_path_buf(), |dir, segment| dir.join(sanitize_segment(segment)));
+                (mirrored, sanitize_segment(&self.generate_filename(last, &media_item.media_type)))
+            }
+            None => (dir.to_path_buf(), self.generate_filename(url, &media_item.media_type)),
+        };
+        let Some(mut file_path) = self.free_path(dir.join(&filename), claims).await else {
+            info!("Skipping {}: {} already exists", url, dir.join(&filename).display());
+            return Ok(None);
+        };
+        
+        debug!("Downloading: {}", url);
+        
+        // Download the file
+        let response = self.scraper.send(self.scraper.client.get(url)).await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to download media")?;
+        
+        if let (Some(max), Some(length)) = (self.config.max_file_size, response.content_length()) {
+            if length > max {
+                info!("Skipping {}: {} bytes exceeds the {} byte limit", url, length, max);
+                return Ok(None);
+            }
+        }
+        
+        let mut verifier = self.config.verify_checksums.then(|| ChecksumVerifier::from_headers(response.headers())).flatten();
+        let body = response
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(std::io::Error::other));
+        let mut body = BufReader::new(StreamReader::new(body));
+        
+        // Read just enough of an image to measure it, then put those bytes
+        // back in front of the rest
+        let mut head = Vec::new();
+        let measure = self.config.min_width.is_some() || self.config.min_height.is_some();
+        if measure && media_item.media_type == crate::scraper::MediaType::Image {
+            let size = loop {
+                if let Some(size) = image_size::dimensions(&head) {
+                    break Some(size);
+                }
+                let chunk = body.fill_buf().await.context("Failed to download media")?;
+                if chunk.is_empty() || head.len() >= image_size::MAX_HEADER_LEN {
+                    break None;
+                }
+                let take = chunk.len().min(image_size::MAX_HEADER_LEN - head.len());
+                head.extend_from_slice(&chunk[..take]);
+                body.consume(take);
+            };
+            match size {
+                Some((width, height))
+                    if self.config.min_width.is_some_and(|min| width < min)
+                        || self.config.min_height.is_some_and(|min| height < min) =>
+                {
+                    info!("Skipping {}: {}x{} is below the minimum image size", url, width, height);
+                    return Ok(None);
+                }
+                Some(_) => {}
+                None => debug!("Couldn't read the size of {}, keeping it", url),
+            }
+        }
+        let mut body = std::io::Cursor::new(head).chain(body);
+        
+        if self.config.sniff_media_type {
+            let head = body.fill_buf().await.context("Failed to download media")?;
+            if let Some((media_type, extension)) = crate::scraper::sniff_media_type(head) {
+                if media_type != media_item.media_type {
+                    warn!("{} is {:?} content, not {:?}", url, media_type, media_item.media_type);
+                }
+                let current = file_path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+                let matches = current.as_deref().is_some_and(|current| {
+                    current == extension || (current == "jpeg" && extension == "jpg")
+                });
+                if !matches {
+                    // Give up the first name before taking another, so two
+                    // downloads trading names can't wait on each other
+                    self.release_claims(std::mem::take(claims));
+                    let sniffed = file_path.with_extension(extension);
+                    let Some(sniffed) = self.free_path(sniffed.clone(), claims).await else {
+                        info!("Skipping {}: {} already exists", url, sniffed.display());
+                        return Ok(None);
+                    };
+                    file_path = sniffed;
+                    debug!("Content is .{}, saving as {}", extension, file_path.display());
+                }
+            }
+        }
+        
+        // Hash and count the body on its way to the sink. Reading one byte
+        // past the limit is enough to tell that a body is oversized.
+        let wants_hash = self.config.name_template.as_ref().is_some_and(|template| template.uses("hash"));
+        let mut hasher = (self.config.dedupe_content || wants_hash).then(Sha256::new);
+        let mut written = 0u64;
+        let limit = self.config.max_file_size.map_or(u64::MAX, |max| max.saturating_add(1));
+        let mut reader = InspectReader::new(body.take(limit), |chunk: &[u8]| {
+            written += chunk.len() as u64;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(chunk);
+            }
+            if let Some(verifier) = verifier.as_mut() {
+                verifier.update(chunk);
+            }
+        });
+        let stored = self.sink.write(media_item, &file_path, &mut reader).await;
+        drop(reader);
+        file_path = stored?;
+        
+        if let Some(max) = self.config.max_file_size.filter(|max| written > *max) {
+            self.sink.remove(&file_path).await?;
+            info!("Skipping {}: exceeded the {} byte limit", url, max);
+            return Ok(None);
+        }
+        let verified = match verifier.map(ChecksumVerifier::finish).transpose() {
+            Ok(verified) => verified,
+            Err(mismatch) => {
+                self.sink.remove(&file_path).await?;
+                return Err(mismatch.into());
+            }
+        };
+        if let Some(algorithms) = &verified {
+            debug!("Verified {} checksum of {}", algorithms, url);
+        }
+        Ok(Some(FetchedMedia {
+            url: url.clone(),
+            dir,
+            path: file_path,
+            written,
+            hash: hasher.map(|hasher| format!("{:x}", hasher.finalize())),
+            verified,
+        }))
+    }
+
+    /// Gives a fetched download its templated name and records it,
+    /// removing it again when it duplicates an earlier download.
+    async fn finish_download(&mut self, fetched: FetchedMedia) -> Result<()> {
+        let FetchedMedia { url, dir, path: mut file_path, written, hash, verified } = fetched;
+        if let Some(template) = &self.config.name_template {
+            let templated = dir.join(self.templated_filename(template, &url, &file_path, hash.as_deref())?);
+            if templated != file_path {
+                let mut claims = Vec::new();
+                let free = self.free_path(templated.clone(), &mut claims).await;
+                let renamed = match &free {
+                    Some(free) => self.sink.rename(&file_path, free).await.map(Some),
+                    None => self.sink.remove(&file_path).await.map(|()| None),
+                };
+                self.release_claims(claims);
+                let Some(renamed) = renamed? else {
+                    info!("Skipping {}: {} already exists", url, templated.display());
+                    return Ok(());
+                };
+                file_path = renamed;
+            }
+        }
+        
+        debug!("Downloaded: {}", file_path.display());
+        self.stats.media += 1;
+        self.stats.bytes += written;
+        
+        let mut record = DownloadRecord {
+            url,
+            path: file_path.clone(),
+            sha256: None,
+            duplicate_of: None,
+            verified,
+        };
+        if let Some(hash) = hash.filter(|_| self.config.dedupe_content) {
+            let original = {
+                let mut hashes = self.content_hashes.lock().unwrap();
+                match hashes.get(&hash) {
+                    Some(original) if *original != file_path => Some(original.clone()),
+                    _ => {
+                        hashes.insert(hash.clone(), file_path.clone());
+                        None
+                    }
+                }
+            };
+            if let Some(original) = original {
+                self.sink.remove(&file_path).await
+                    .context("Failed to remove duplicate")?;
+                info!("Removed duplicate of {}: {}", original.display(), file_path.display());
+                record.duplicate_of = Some(original);
+            }
+            record.sha256 = Some(hash);
+        }
+        self.manifest.downloads.push(record);
+        
+        Ok(())
+    }
+
+    /// Where to save a download meant for `path` under
+    /// [`EngineConfig::on_conflict`], or `None` to skip it because `path`
+    /// is taken. A path another download in progress is using counts as
+    /// taken, except that an overwriting download waits its turn for it;
+    /// the path returned is claimed and added to `claims`.
+    async fn free_path(&self, path: PathBuf, claims: &mut Vec<PathBuf>) -> Option<PathBuf> {
+        if self.config.on_conflict == ConflictPolicy::Overwrite {
+            self.claim_path_when_released(&path, claims).await;
+            return Some(path);
+        }
+        if self.claim_path(&path, claims).await {
+            return Some(path);
+        }
+        if self.config.on_conflict == ConflictPolicy::Skip {
+            return None;
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+        for counter in 1.. {
+            let renamed = path.with_file_name(format!("{}-{}{}", stem, counter, extension.as_deref().unwrap_or("")));
+            if self.claim_path(&renamed, claims).await {
+                return Some(renamed);
+            }
+        }
+        unreachable!("ran out of counters for {}", path.display())
+    }
+
+    /// Gives up paths claimed by [`MslEngine::free_path`] once what's
+    /// stored there can be seen in the sink.
+    fn release_claims(&self, claims: Vec<PathBuf>) {
+        let mut claimed = self.claimed_paths.lock().unwrap();
+        for path in claims {
+            claimed.remove(&path);
+        }
+        drop(claimed);
+        self.claims_released.notify_waiters();
+    }
+
+    /// Claims `path` whatever is stored there, once no other download is
+    /// using it.
+    async fn claim_path_when_released(&self, path: &Path, claims: &mut Vec<PathBuf>) {
+        loop {
+            // Listen before looking, so a release in between isn't missed
+            let released = self.claims_released.notified();
+            if self.claimed_paths.lock().unwrap().insert(path.to_path_buf()) {
+                break;
+            }
+            released.await;
+        }
+        claims.push(path.to_path_buf());
+    }
+
+    /// Claims `path` when no other download has and nothing is stored there.
+    async fn claim_path(&self, path: &Path, claims: &mut Vec<PathBuf>) -> bool {
+        if !self.claimed_paths.lock().unwrap().insert(path.to_path_buf()) {
+            return false;
+        }
+        if self.sink.exists(path).await {
+            self.claimed_paths.lock().unwrap().remove(path);
+            return false;
+        }
+        claims.push(path.to_path_buf());
+        true
+    }
+
+    /// Fills in `{variable}`s in a save path and roots relative paths under
+    /// the configured output directory. Slashes in a value nest directories,
+    /// so `{user}/{title}` can become `alice/2024/summer`; each segment a
+    /// value produces is sanitized on its own.
+    fn resolve_save_path(&self, path: &str) -> Result<PathBuf> {
+        let mut resolved = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(segment) => {
+                    let segment = self.interpolate(&segment.to_string_lossy())?;
+                    for piece in segment.split(['/', '\\']).filter(|piece| !piece.is_empty()) {
+                        resolved.push(sanitize_segment(piece));
+                    }
+                }
+                component => resolved.push(component),
+            }
+        }
+        let path = resolved;
+        Ok(match &self.config.output_dir {
+            Some(output_dir) if path.is_relative() => {
+                output_dir.join(path.strip_prefix(".").unwrap_or(&path))
+            }
+            _ => path,
+        })
+    }
+
+    /// Replaces `{name}` placeholders with the values of script variables,
+    /// and `${NAME}` placeholders with environment variables, which must be
+    /// set. A list variable can't stand in for a single value. Substituted
+    /// values are not searched for further placeholders.
+    fn interpolate(&self, template: &str) -> Result<String> {
+        let mut result = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let is_env = rest[..start].ends_with('$');
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                break;
+            };
+            let name = &rest[start + 1..end];
+            if name.contains('{') {
+                // Not a placeholder, as in `{a{b}`; keep the brace
+                result.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+                continue;
+            }
+            if is_env {
+                result.push_str(&rest[..start - 1]);
+                let value = std::env::var(name).map_err(|_| {
+                    anyhow::anyhow!("Environment variable {} is not set (used in \"{}\")", name, template)
+                })?;
+                result.push_str(&value);
+            } else {
+                result.push_str(&rest[..start]);
+                match self.variables.get(name) {
+                    Some(Value::List(_)) => {
+                        anyhow::bail!("{} is a list; use it with foreach, not in \"{}\"", name, template)
+                    }
+                    Some(value) => result.push_str(&value.to_string()),
+                    None => result.push_str(&rest[start..=end]),
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// Name for a finished download from the configured template.
+    /// `default_path` is where it would otherwise be saved.
+    fn templated_filename(&self, template: &NameTemplate, url: &str, default_path: &Path, hash: Option<&str>) -> Result<String> {
+        if let Some(token) = template.tokens().iter().find(|token| matches!(self.variables.get(*token), Some(Value::List(_)))) {
+            anyhow::bail!("{} is a list; use it with foreach, not in a name template", token);
+        }
+        let index = self.stats.media + 1;
+        let host = url::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string));
+        let stem = default_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        let ext = default_path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        
+        Ok(template.render(|token| {
+            sanitize_segment(&match token {
+                "index" => index.to_string(),
+                "host" => host.clone().unwrap_or_default(),
+                "basename" => stem.to_string(),
+                "ext" => ext.to_string(),
+                "hash" => hash.map(|hash| hash[..16].to_string()).unwrap_or_default(),
+                name => self.variables.get(name).map(Value::to_string).unwrap_or_default(),
+            })
+        }))
+    }
+
+    fn generate_filename(&self, url: &str, media_type: &crate::scraper::MediaType) -> String {
+        // Extract filename from URL or generate one
+        let filename = url.split('/').next_back().unwrap_or("unknown");
         
         // Add appropriate extension if missing
         if !filename.contains('.') {
@@ -225,8 +1935,2462 @@ impl MslEngine {
     }
 }
 
+/// The text, markup or attribute `value` reads from `element`.
+fn element_value(element: &ElementData, value: &MslValue) -> Option<String> {
+    match value {
+        MslValue::Text => Some(element.text.clone()),
+        MslValue::Html => element.inner_html(),
+        MslValue::OuterHtml => element.outer_html(),
+        MslValue::Attribute { name } => element.attributes.get(name).cloned(),
+        _ => None,
+    }
+}
+
+/// Whether a `set` value is read from the current page, so that fetching
+/// the page again could change it.
+fn reads_page(value: &MslValue) -> bool {
+    match value {
+        MslValue::Title | MslValue::Header { .. } | MslValue::Json { .. } | MslValue::Within { .. } => true,
+        MslValue::Split { source, .. } | MslValue::Transform { source, .. } | MslValue::Regex { source, .. } | MslValue::All { source } => reads_page(source),
+        MslValue::Fallback { value, fallback } => reads_page(value) || reads_page(fallback),
+        MslValue::Concat { parts } => parts.iter().any(reads_page),
+        MslValue::Text | MslValue::Html | MslValue::OuterHtml | MslValue::Url | MslValue::Attribute { .. } | MslValue::Literal { .. } | MslValue::Variable { .. } => false,
+    }
+}
+
+/// The status of a response that failed `error` because the server is
+/// overloaded or limiting requests: 429 Too Many Requests or a 5xx error.
+fn throttled_status(error: &anyhow::Error) -> Option<reqwest::StatusCode> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .and_then(reqwest::Error::status)
+        .filter(|status| *status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+}
+
+/// Whether `value` reads something from the page or an element on it, so
+/// coming out empty means it wasn't found.
+fn extracts(value: &MslValue) -> bool {
+    match value {
+        MslValue::Title | MslValue::Header { .. } | MslValue::Json { .. } => true,
+        MslValue::Text | MslValue::Html | MslValue::OuterHtml | MslValue::Attribute { .. } | MslValue::Within { .. } => true,
+        MslValue::Split { source, .. } | MslValue::Transform { source, .. } | MslValue::Regex { source, .. } | MslValue::All { source } => extracts(source),
+        MslValue::Fallback { value, fallback } => extracts(value) || extracts(fallback),
+        MslValue::Concat { parts } => parts.iter().any(extracts),
+        MslValue::Url | MslValue::Literal { .. } | MslValue::Variable { .. } => false,
+    }
+}
+
+/// Whether `value` reads the element of the enclosing `click`.
+fn reads_element(value: &MslValue) -> bool {
+    match value {
+        MslValue::Text | MslValue::Html | MslValue::OuterHtml | MslValue::Attribute { .. } | MslValue::Within { .. } => true,
+        MslValue::Split { source, .. } | MslValue::Transform { source, .. } | MslValue::Regex { source, .. } | MslValue::All { source } => reads_element(source),
+        MslValue::Fallback { value, fallback } => reads_element(value) || reads_element(fallback),
+        MslValue::Concat { parts } => parts.iter().any(reads_element),
+        _ => false,
+    }
+}
+
+/// Whether `headers` describe a JSON response, such as `application/json`
+/// or `application/ld+json`.
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|mime| mime.trim().to_ascii_lowercase())
+        .is_some_and(|mime| mime == "application/json" || mime.ends_with("+json"))
+}
+
+/// A random delay between `min` and `max` seconds, to the millisecond.
+fn random_delay(min: u64, max: u64) -> Duration {
+    let millis = rand::thread_rng().gen_range(min.saturating_mul(1000)..=max.saturating_mul(1000));
+    Duration::from_millis(millis)
+}
+
+/// Makes one path segment safe to create on any platform: characters
+/// Windows forbids become `_`, trailing dots and spaces are dropped, and `.`
+/// or `..` coming from a variable can't step out of the save directory.
+fn sanitize_segment(segment: &str) -> String {
+    let sanitized: String = segment
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') { '_' } else { c })
+        .collect();
+    match sanitized.trim_end_matches(['.', ' ']) {
+        "" => "_".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Name of the file [`EngineConfig::dump_failures`] saves `url` in after
+/// `looked_for` found nothing on it, e.g.
+/// `example.com_shop--div.card_a.html`.
+fn failure_file_name(url: &str, looked_for: &str) -> String {
+    let clean = |text: &str| -> String {
+        let cleaned: String = text
+            .chars()
+            .map(|c| if c.is_alphanumeric() || matches!(c, '.' | '-') { c } else { '_' })
+            .collect();
+        cleaned.split('_').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("_").chars().take(80).collect()
+    };
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    format!("{}--{}.html", clean(url), clean(looked_for))
+}
+
+fn apply_transform(value: &str, transform: &Transform) -> String {
+    match transform {
+        Transform::Trim => value.trim().to_string(),
+        Transform::Lower => value.to_lowercase(),
+        Transform::Upper => value.to_uppercase(),
+        Transform::Replace { from, .. } if from.is_empty() => value.to_string(),
+        Transform::Replace { from, to } => value.replace(from.as_str(), to),
+        Transform::Slug => value
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
+
+/// Collects the names of variables `commands` can set.
+fn defined_variables<'a>(commands: &'a [MslCommand], defined: &mut HashSet<&'a str>) {
+    for command in commands {
+        match command {
+            MslCommand::Set { variable, .. } => {
+                defined.insert(variable);
+            }
+            MslCommand::Extract { target: ExtractTarget::Variable { name }, .. } => {
+                defined.insert(name);
+            }
+            MslCommand::Foreach { variable, commands, .. } | MslCommand::Repeat { variable, commands, .. } => {
+                defined.insert(variable);
+                defined_variables(commands, defined);
+            }
+            MslCommand::Click { commands, .. } => defined_variables(commands, defined),
+            _ => {}
+        }
+    }
+}
+
+/// Splits top-level commands into groups that each start at an `open`.
+fn split_groups(commands: Vec<MslCommand>) -> Vec<Vec<MslCommand>> {
+    let mut groups: Vec<Vec<MslCommand>> = Vec::new();
+    for command in commands {
+        match groups.last_mut() {
+            Some(group) if !matches!(command, MslCommand::Open { .. }) => group.push(command),
+            _ => groups.push(vec![command]),
+        }
+    }
+    groups
+}
+
+/// Turns an [`OffHost`] from the body of a loop into a warning, so the loop
+/// goes on with its next iteration.
+fn skip_off_host(result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.is::<OffHost>() => {
+            warn!("Skipping the rest of this iteration: {}", e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Whether a filter compares against values only known from a HEAD request.
+fn needs_head(filter: &MediaFilter) -> bool {
+    match filter {
+        MediaFilter::Where { field, .. } => field == "size" || field == "type",
+        MediaFilter::Extensions { .. } => false,
+        MediaFilter::And(filters) | MediaFilter::Or(filters) => filters.iter().any(needs_head),
+    }
+}
+
 impl Default for MslEngine {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_script;
+    use crate::clock::MockClock;
+    use crate::testing::{LogBuffer, MockResponse, MockServer};
+    use futures_util::future::BoxFuture;
+
+    /// Downloads one item, returning any failure.
+    async fn download(engine: &mut MslEngine, media_item: &MediaItem, dir: &Path) -> Result<()> {
+        match engine.fetch_media(media_item, dir).await? {
+            Some(fetched) => engine.finish_download(fetched).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Lets every sleep finish at once.
+    struct NoWait;
+
+    impl Clock for NoWait {
+        fn sleep(&self, _duration: Duration) -> BoxFuture<'static, ()> {
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    fn first_media_block(script: &str) -> MediaBlock {
+        match parse_script(script).unwrap().commands.remove(0) {
+            MslCommand::Media { mut media_blocks } => media_blocks.remove(0),
+            other => panic!("expected media command, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_media_by_head_size() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/large.jpg"><img src="/small.jpg">"#)),
+            ("/large.jpg", MockResponse::bytes("image/jpeg", vec![0; 20_000])),
+            ("/small.jpg", MockResponse::bytes("image/jpeg", vec![0; 100])),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+        engine.execute_open(server.url("/")).await.unwrap();
+        let all_media = engine.scraper
+            .extract_media_from_html(engine.current_html.as_ref().unwrap(), &server.url("/"))
+            .await
+            .unwrap();
+
+        let block = first_media_block("media\n  image\n    where size > 10000\n    where type = \"image/jpeg\"\n");
+        let selected = engine.select_media(&block, &all_media).await;
+
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].url, server.url("/large.jpg"));
+        assert!(server.requests().iter().all(|request| request.method != "GET" || request.path == "/"));
+    }
+
+    #[tokio::test]
+    async fn test_sort_by_size_keeps_largest() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/small.jpg"><img src="/large.jpg"><img src="/medium.jpg">"#)),
+            ("/small.jpg", MockResponse::bytes("image/jpeg", vec![0; 100])),
+            ("/large.jpg", MockResponse::bytes("image/jpeg", vec![0; 30_000])),
+            ("/medium.jpg", MockResponse::bytes("image/jpeg", vec![0; 2_000])),
+        ])
+        .await;
+        let mut engine = MslEngine::with_config(EngineConfig { list_media: true, ..EngineConfig::default() }).unwrap();
+        let script = format!("open \"{}\"\nmedia\n  image\n    sort by size desc\n    limit 2\n", server.url("/"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(engine.listed_media(), [server.url("/large.jpg"), server.url("/medium.jpg")]);
+    }
+
+    #[tokio::test]
+    async fn test_same_host_skips_off_host_links_and_media() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <a href="http://elsewhere.test/outside">Out</a>
+                <a href="/inside">In</a>
+                <img src="/on.jpg"><img src="http://elsewhere.test/off.jpg">
+            "#)),
+            ("/inside", MockResponse::html("<title>Inside</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::with_config(EngineConfig {
+            same_host: true,
+            list_media: true,
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let script = format!(
+            "open \"{}\"\nmedia\n  image\nclick each \"a\"\n  set visited = title\n",
+            server.url("/")
+        );
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(engine.variables["visited"], "Inside");
+        assert_eq!(engine.listed_media(), [server.url("/on.jpg")]);
+        assert_eq!(engine.stats().pages, 2);
+    }
+
+    #[tokio::test]
+    async fn test_denied_host_ends_block() {
+        let server = MockServer::with_routes(vec![("/", MockResponse::html("<title>Home</title>"))]).await;
+        let mut engine = MslEngine::with_config(EngineConfig {
+            deny_hosts: vec!["blocked.test".to_string()],
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let script = format!(
+            "repeat 2 as n\n  open \"{}\"\n  set last = title\n  open \"http://blocked.test/{{n}}\"\n  set after = title\n",
+            server.url("/")
+        );
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(engine.variables["last"], "Home");
+        assert!(!engine.variables.contains_key("after"));
+        assert_eq!(server.hits("/"), 2);
+
+        let script = parse_script("open \"http://blocked.test/\"\nset after = \"yes\"\n").unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert!(error.is::<OffHost>(), "{}", error);
+        assert!(!engine.variables.contains_key("after"));
+    }
+
+    #[tokio::test]
+    async fn test_redirects_to_denied_hosts_are_off_host() {
+        let server = MockServer::start(|request| match request.path.as_str() {
+            "/" => MockResponse::html(r#"<a href="/away">Away</a><a href="/stay">Stay</a>"#),
+            "/away" => {
+                let port = request.headers["host"].rsplit(':').next().unwrap().to_string();
+                MockResponse::new(302).header("Location", &format!("http://blocked.test:{}/elsewhere", port))
+            }
+            "/stay" => MockResponse::html("<title>Stayed</title>"),
+            _ => MockResponse::html("<title>Elsewhere</title>"),
+        })
+        .await;
+        let mut engine = MslEngine::with_config(EngineConfig {
+            deny_hosts: vec!["blocked.test".to_string()],
+            scraper: ScraperConfig {
+                resolve: vec![("blocked.test".to_string(), "127.0.0.1".parse().unwrap())],
+                ..ScraperConfig::default()
+            },
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let script = format!("open \"{}\"\nclick each \"a\"\n  set title = title\n", server.url("/"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(engine.variables["title"], "Stayed");
+
+        let script = format!("open \"{}\"\n", server.url("/away"));
+        let error = engine.execute(parse_script(&script).unwrap()).await.unwrap_err();
+        assert!(error.is::<OffHost>(), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_media_inside_iframes() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/top.jpg"><iframe src="/frames/gallery.html"></iframe>"#)),
+            ("/frames/gallery.html", MockResponse::html(r#"<img src="photo.jpg"><iframe src="/nested.html"></iframe>"#)),
+            ("/nested.html", MockResponse::html(r#"<img src="/deep.jpg">"#)),
+        ])
+        .await;
+        let mut engine = MslEngine::with_config(EngineConfig { iframe_depth: 1, ..EngineConfig::default() }).unwrap();
+        engine.execute_open(server.url("/")).await.unwrap();
+
+        let found = engine.find_media(&first_media_block("media\n  image\n")).await.unwrap();
+
+        let found: Vec<(&str, Option<&str>)> = found.iter().map(|item| (item.url.as_str(), item.frame.as_deref())).collect();
+        let frame = server.url("/frames/gallery.html");
+        assert_eq!(found, [(server.url("/top.jpg").as_str(), None), (server.url("/frames/photo.jpg").as_str(), Some(frame.as_str()))]);
+        assert_eq!(server.hits("/nested.html"), 0);
+
+        let mut without_frames = MslEngine::new();
+        without_frames.execute_open(server.url("/")).await.unwrap();
+        assert_eq!(without_frames.find_media(&first_media_block("media\n  image\n")).await.unwrap().len(), 1);
+
+        // Frames count towards the page limit like any other page
+        let mut limited = MslEngine::with_config(EngineConfig { iframe_depth: 2, max_pages: Some(2), ..EngineConfig::default() }).unwrap();
+        limited.execute_open(server.url("/")).await.unwrap();
+        assert_eq!(limited.find_media(&first_media_block("media\n  image\n")).await.unwrap().len(), 2);
+        assert_eq!(limited.stats().pages, 2);
+        assert_eq!(server.hits("/nested.html"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_environment_variables_in_strings() {
+        let server = MockServer::start(|_| MockResponse::html("<title>Items</title>")).await;
+        std::env::set_var("MSL_TEST_API_KEY", "s3cret");
+        let mut engine = MslEngine::new();
+        engine.variables.insert("MSL_TEST_API_KEY".to_string(), "script".into());
+        engine.variables.insert("page".to_string(), "2".into());
+        let script = format!("open \"{}\"\n", server.url("/items/{page}/${MSL_TEST_API_KEY}/{MSL_TEST_API_KEY}/{missing}"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(server.requests()[0].path, "/items/2/s3cret/script/%7Bmissing%7D");
+
+        let script = format!("open \"{}\"\n", server.url("/${MSL_TEST_UNSET_VARIABLE}"));
+        let error = engine.execute(parse_script(&script).unwrap()).await.unwrap_err().to_string();
+        assert!(error.starts_with("Environment variable MSL_TEST_UNSET_VARIABLE is not set"), "{}", error);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_content_removes_identical_downloads() {
+        let server = MockServer::with_routes(vec![
+            ("/a.jpg", MockResponse::bytes("image/jpeg", "same bytes")),
+            ("/b.jpg", MockResponse::bytes("image/jpeg", "same bytes")),
+            ("/c.jpg", MockResponse::bytes("image/jpeg", "other bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            dedupe_content: true,
+            ..EngineConfig::default()
+        }).unwrap();
+
+        for path in ["/a.jpg", "/b.jpg", "/c.jpg"] {
+            let item = MediaItem {
+                url: server.url(path),
+                media_type: crate::scraper::MediaType::Image,
+                filename: None,
+                attributes: HashMap::new(),
+                content_length: None,
+                content_type: None,
+                frame: None,
+            };
+            download(&mut engine, &item, dir.path()).await.unwrap();
+        }
+
+        assert!(dir.path().join("a.jpg").exists());
+        assert!(!dir.path().join("b.jpg").exists());
+        assert!(dir.path().join("c.jpg").exists());
+
+        let downloads = &engine.manifest().downloads;
+        assert_eq!(downloads.len(), 3);
+        assert_eq!(downloads[0].sha256, downloads[1].sha256);
+        assert_eq!(downloads[1].duplicate_of.as_deref(), Some(dir.path().join("a.jpg").as_path()));
+    }
+
+    #[tokio::test]
+    async fn test_output_dir_roots_relative_save_paths() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/photo.jpg">"#)),
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+        engine.variables.insert("user".to_string(), "alice".into());
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  image\nsave to \"./media/{{user}}\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert!(dir.path().join("media/alice/photo.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn test_media_blocks_save_to_their_own_paths() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/photo.jpg"><video src="/clip.mp4"></video>"#)),
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes")),
+            ("/clip.mp4", MockResponse::bytes("video/mp4", "mp4 bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  image\n    save to \"images\"\n  video\n    save to \"videos\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("images/photo.jpg")).unwrap(), b"jpeg bytes");
+        assert_eq!(std::fs::read(dir.path().join("videos/clip.mp4")).unwrap(), b"mp4 bytes");
+        assert!(!dir.path().join("images/clip.mp4").exists());
+        assert!(!dir.path().join("videos/photo.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn test_video_block_only_downloads_videos() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <img src="/poster.jpg"><video src="/clip.mp4"></video><audio src="/song.mp3"></audio>
+                <a href="/wallpaper.png">Wallpaper</a><a href="/trailer.webm">Trailer</a>
+            "#)),
+            ("/poster.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes")),
+            ("/clip.mp4", MockResponse::bytes("video/mp4", "mp4 bytes")),
+            ("/song.mp3", MockResponse::bytes("audio/mpeg", "mp3 bytes")),
+            ("/wallpaper.png", MockResponse::bytes("image/png", "png bytes")),
+            ("/trailer.webm", MockResponse::bytes("video/webm", "webm bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  video\n    extensions *\nsave to \"videos\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let mut saved: Vec<String> = std::fs::read_dir(dir.path().join("videos"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        saved.sort();
+        assert_eq!(saved, ["clip.mp4", "trailer.webm"]);
+        assert_eq!(server.hits("/poster.jpg") + server.hits("/song.mp3") + server.hits("/wallpaper.png"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_save_path_variables_create_nested_dirs() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/photo.jpg">"#)),
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+        engine.variables.insert("user".to_string(), "alice".into());
+        engine.variables.insert("title".to_string(), "2024/Summer: best?".into());
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  image\nsave to \"./out/{{user}}/{{title}}\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert!(dir.path().join("out/alice/2024").is_dir());
+        assert!(dir.path().join("out/alice/2024/Summer_ best_/photo.jpg").exists());
+    }
+
+    #[test]
+    fn test_save_path_segments_are_sanitized() {
+        let mut engine = MslEngine::new();
+        engine.variables.insert("user".to_string(), "../../etc".into());
+        engine.variables.insert("title".to_string(), "a<b>|c. ".into());
+
+        assert_eq!(engine.resolve_save_path("media/{user}").unwrap(), PathBuf::from("media/_/_/etc"));
+        assert_eq!(engine.resolve_save_path("../media/{title}").unwrap(), PathBuf::from("../media/a_b__c"));
+        assert_eq!(engine.resolve_save_path("/srv/{user}.d").unwrap(), PathBuf::from("/srv/_/_/etc.d"));
+    }
+
+    #[test]
+    fn test_output_dir_keeps_absolute_save_paths() {
+        let engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(PathBuf::from("/tmp/out")),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        assert_eq!(engine.resolve_save_path("/srv/media").unwrap(), PathBuf::from("/srv/media"));
+        assert_eq!(engine.resolve_save_path("media").unwrap(), PathBuf::from("/tmp/out/media"));
+    }
+
+    fn image_item(url: String) -> MediaItem {
+        MediaItem {
+            url,
+            media_type: crate::scraper::MediaType::Image,
+            filename: None,
+            attributes: HashMap::new(),
+            content_length: None,
+            content_type: None,
+            frame: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_file_size_skips_oversized_downloads() {
+        let server = MockServer::with_routes(vec![
+            ("/small.jpg", MockResponse::bytes("image/jpeg", vec![1; 500])),
+            ("/large.jpg", MockResponse::bytes("image/jpeg", vec![1; 5_000])),
+            ("/streamed.jpg", MockResponse::bytes("image/jpeg", vec![1; 5_000]).close_delimited()),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            max_file_size: Some(1_000),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        for path in ["/small.jpg", "/large.jpg", "/streamed.jpg"] {
+            download(&mut engine, &image_item(server.url(path)), dir.path()).await.unwrap();
+        }
+
+        assert_eq!(std::fs::read(dir.path().join("small.jpg")).unwrap().len(), 500);
+        assert!(!dir.path().join("large.jpg").exists());
+        assert!(!dir.path().join("streamed.jpg").exists());
+        assert_eq!(engine.manifest().downloads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_min_image_size_skips_small_images() {
+        let png = |width: u32, height: u32| {
+            let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+            png.extend_from_slice(&width.to_be_bytes());
+            png.extend_from_slice(&height.to_be_bytes());
+            png.extend_from_slice(&[0; 1_000]);
+            png
+        };
+        let server = MockServer::with_routes(vec![
+            ("/icon.png", MockResponse::bytes("image/png", png(16, 16))),
+            ("/photo.png", MockResponse::bytes("image/png", png(800, 600))),
+            ("/banner.png", MockResponse::bytes("image/png", png(800, 40))),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            min_width: Some(100),
+            min_height: Some(100),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        for path in ["/icon.png", "/photo.png", "/banner.png"] {
+            download(&mut engine, &image_item(server.url(path)), dir.path()).await.unwrap();
+        }
+
+        assert_eq!(std::fs::read(dir.path().join("photo.png")).unwrap(), png(800, 600));
+        assert!(!dir.path().join("icon.png").exists());
+        assert!(!dir.path().join("banner.png").exists());
+        assert_eq!(engine.manifest().downloads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_on_conflict_policies() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/photo.jpg">"#)),
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", "new")),
+        ])
+        .await;
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"));
+
+        for (policy, photo, renamed) in [
+            (ConflictPolicy::Skip, "old", None),
+            (ConflictPolicy::Overwrite, "new", None),
+            (ConflictPolicy::Rename, "old", Some("new")),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("photo.jpg"), "old").unwrap();
+            std::fs::write(dir.path().join("photo-1.jpg"), "older").unwrap();
+            let mut engine = MslEngine::with_config(EngineConfig {
+                output_dir: Some(dir.path().to_path_buf()),
+                on_conflict: policy,
+                ..EngineConfig::default()
+            }).unwrap();
+
+            engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+            assert_eq!(std::fs::read_to_string(dir.path().join("photo.jpg")).unwrap(), photo, "{:?}", policy);
+            assert_eq!(std::fs::read_to_string(dir.path().join("photo-1.jpg")).unwrap(), "older", "{:?}", policy);
+            assert_eq!(std::fs::read_to_string(dir.path().join("photo-2.jpg")).ok().as_deref(), renamed, "{:?}", policy);
+            assert_eq!(engine.stats().media, usize::from(policy != ConflictPolicy::Skip), "{:?}", policy);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_overwriting_downloads_take_turns_at_a_path() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (active, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (downloading, most_at_once) = (active.clone(), most.clone());
+        let server = MockServer::start(move |request| {
+            if request.path == "/" {
+                return MockResponse::html(r#"<img src="/a/photo.jpg"><img src="/b/photo.jpg">"#);
+            }
+            most_at_once.fetch_max(downloading.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            downloading.fetch_sub(1, Ordering::SeqCst);
+            MockResponse::bytes("image/jpeg", request.path.clone())
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            on_conflict: ConflictPolicy::Overwrite,
+            concurrency: 2,
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, ["photo.jpg"]);
+        assert_eq!(engine.stats().media, 2);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_paths_keep_url_directories() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/img/2023/photo.jpg?w=800"><img src="/static/a:b/photo.jpg"><img src="/logo.png">"#)),
+            ("/img/2023/photo.jpg?w=800", MockResponse::bytes("image/jpeg", "2023")),
+            ("/static/a:b/photo.jpg", MockResponse::bytes("image/jpeg", "static")),
+            ("/logo.png", MockResponse::bytes("image/png", "logo")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            mirror_paths: true,
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!("open \"{}\"\nmedia\n  image\nsave to \"out\"\n", server.url("/"))).unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("out/img/2023/photo.jpg")).unwrap(), b"2023");
+        assert_eq!(std::fs::read(dir.path().join("out/static/a_b/photo.jpg")).unwrap(), b"static");
+        assert_eq!(std::fs::read(dir.path().join("out/logo.png")).unwrap(), b"logo");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_adaptive_concurrency_backs_off() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Refuses a download while two others are in progress
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let refused = Arc::new(AtomicUsize::new(0));
+        let (active, rejections) = (in_flight.clone(), refused.clone());
+        let server = MockServer::start(move |request| {
+            if request.path == "/" {
+                let images: String = (0..12).map(|i| format!(r#"<img src="/{}.jpg">"#, i)).collect();
+                return MockResponse::html(&images);
+            }
+            if active.fetch_add(1, Ordering::SeqCst) >= 2 {
+                active.fetch_sub(1, Ordering::SeqCst);
+                rejections.fetch_add(1, Ordering::SeqCst);
+                return MockResponse::new(503);
+            }
+            std::thread::sleep(Duration::from_millis(30));
+            active.fetch_sub(1, Ordering::SeqCst);
+            MockResponse::bytes("image/jpeg", "jpeg bytes")
+        })
+        .await;
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"));
+        let run = |adaptive: bool| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut engine = MslEngine::with_config(EngineConfig {
+                output_dir: Some(dir.path().to_path_buf()),
+                concurrency: 8,
+                adaptive_concurrency: adaptive,
+                ..EngineConfig::default()
+            }).unwrap();
+            engine.set_clock(NoWait).unwrap();
+            (dir, engine)
+        };
+
+        // All at once, most downloads are refused
+        let (_dir, mut engine) = run(false);
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+        assert!(engine.manifest().failed.len() >= 6, "{:?}", engine.manifest().failed);
+
+        let logs = LogBuffer::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt().with_ansi(false).without_time().with_writer(logs.clone()).finish(),
+        );
+        refused.store(0, Ordering::SeqCst);
+        let (dir, mut engine) = run(true);
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert!(engine.manifest().failed.is_empty(), "{:?}", engine.manifest().failed);
+        assert!((0..12).all(|i| dir.path().join(format!("{}.jpg", i)).exists()));
+        assert!(refused.load(Ordering::SeqCst) > 0);
+        assert!(logs.contents().contains("answered 503 Service Unavailable; downloading at most"), "{}", logs.contents());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_hosts_are_throttled_separately() {
+        // slow.test refuses the first request for each file; fast.test
+        // serves everything
+        let refused = Arc::new(Mutex::new(HashSet::new()));
+        let server = MockServer::start(move |request| {
+            let host = request.headers.get("host").cloned().unwrap_or_default();
+            if host.starts_with("slow.test") {
+                if refused.lock().unwrap().insert(request.path.clone()) {
+                    return MockResponse::new(503);
+                }
+            } else if !host.starts_with("fast.test") {
+                let port = host.rsplit(':').next().unwrap().to_string();
+                let slow = (0..6).map(|i| format!(r#"<img src="http://slow.test:{}/s{}.jpg">"#, port, i));
+                let fast = (0..9).map(|i| format!(r#"<img src="http://fast.test:{}/f{}.jpg">"#, port, i));
+                return MockResponse::html(&slow.chain(fast).collect::<String>());
+            }
+            MockResponse::bytes("image/jpeg", "jpeg bytes")
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let localhost: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            concurrency: 3,
+            adaptive_concurrency: true,
+            scraper: ScraperConfig {
+                resolve: vec![("slow.test".to_string(), localhost), ("fast.test".to_string(), localhost)],
+                ..ScraperConfig::default()
+            },
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let clock = MockClock::new();
+        engine.set_clock(clock.clone()).unwrap();
+        let hits = |host: &str| server.requests().iter().filter(|request| request.headers["host"].starts_with(host)).count();
+
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"));
+        let (result, ()) = tokio::join!(engine.execute(parse_script(&script).unwrap()), async {
+            // Every slow download is refused and backs off, and the fast
+            // host's downloads all go ahead while they wait
+            clock.wait_for_sleeps(6).await;
+            let fast_done = async {
+                while hits("fast.test") < 9 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), fast_done).await.expect("fast.test was held back");
+            assert_eq!(hits("slow.test"), 6);
+            clock.advance(Duration::from_secs(1));
+        });
+        result.unwrap();
+
+        assert!(engine.manifest().failed.is_empty(), "{:?}", engine.manifest().failed);
+        assert_eq!(engine.manifest().downloads.len(), 15);
+        assert_eq!(hits("slow.test"), 12);
+        assert_eq!(clock.sleeps().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_downloads_wait_before_retrying() {
+        let server = MockServer::start(|request| match request.path.as_str() {
+            "/" => MockResponse::html(r#"<img src="/photo.jpg">"#),
+            _ => MockResponse::new(503),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            adaptive_concurrency: true,
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let clock = MockClock::new();
+        engine.set_clock(clock.clone()).unwrap();
+
+        let script = parse_script(&format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"))).unwrap();
+        let (result, ()) = tokio::join!(engine.execute(script), async {
+            for (sleeps, delay) in [(1, 1), (2, 2), (3, 4)] {
+                clock.wait_for_sleeps(sleeps).await;
+                assert_eq!(server.hits("/photo.jpg"), sleeps);
+                clock.advance(Duration::from_secs(delay));
+            }
+        });
+        result.unwrap();
+
+        assert_eq!(clock.sleeps(), [1, 2, 4].map(Duration::from_secs));
+        assert_eq!(server.hits("/photo.jpg"), 4);
+        assert_eq!(engine.manifest().failed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_verify_rejects_mismatched_content() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/good.jpg"><img src="/bad.jpg">"#)),
+            ("/good.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes").header("Content-MD5", "LM15nzpRMDUEeImUR7aqBg==")),
+            ("/bad.jpg", MockResponse::bytes("image/jpeg", "corrupted").header("Content-MD5", "LM15nzpRMDUEeImUR7aqBg==")),
+        ])
+        .await;
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"));
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            verify_checksums: true,
+            ..EngineConfig::default()
+        }).unwrap();
+
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        let manifest = engine.manifest();
+        assert_eq!(manifest.downloads.len(), 1);
+        assert_eq!(manifest.downloads[0].verified.as_deref(), Some("md5"));
+        assert!(dir.path().join("good.jpg").exists());
+        assert_eq!(manifest.failed.len(), 1);
+        assert!(manifest.failed[0].error.starts_with("md5 checksum mismatch"), "{}", manifest.failed[0].error);
+        assert!(!dir.path().join("bad.jpg").exists());
+        assert_eq!(server.hits("/bad.jpg"), 2);
+
+        // Unverified, the same content is kept as is
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+        assert!(dir.path().join("bad.jpg").exists());
+        assert!(engine.manifest().downloads.iter().all(|download| download.verified.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_download_streams_large_body() {
+        let body: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let server = MockServer::with_routes(vec![
+            ("/video.mp4", MockResponse::bytes("video/mp4", body.clone()).close_delimited()),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::new();
+
+        download(&mut engine, &image_item(server.url("/video.mp4")), dir.path()).await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("video.mp4")).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn test_set_concatenates_values() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="user" href="/u/alice">Alice</a>"#)),
+            ("/u/alice", MockResponse::html("<h1>Alice</h1>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+click "a.user"
+  set name = text
+  set id = attr("href").split("/")[-1]
+  set path = name + "/" + id
+  set label = "{{id}}-{{name}}"
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["path"], "Alice/alice");
+        assert_eq!(engine.variables["label"], "alice-Alice");
+    }
+
+    #[tokio::test]
+    async fn test_set_falls_back_when_extraction_is_empty() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="user" href="/u/1"><img src="/avatar.png"></a>"#)),
+            ("/u/1", MockResponse::html("<h1>Profile</h1>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nclick \"a.user\"\n  set user = text or \"anonymous\"\n  set title = attr(\"title\") or user\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["user"], "anonymous");
+        assert_eq!(engine.variables["title"], "anonymous");
+    }
+
+    #[tokio::test]
+    async fn test_empty_extractions_warn_or_fail_under_strict() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="user" href="/u/1">Alice</a>"#)),
+            ("/u/1", MockResponse::html("<h1>Profile</h1>")),
+        ])
+        .await;
+        let script = format!(
+            "open \"{}\"\nclick \"a.user\"\n  set name = text\n  set id = attr(\"data-id\")\n  set note = \"\"\nextract jsonld \"$.name\" into product\n",
+            server.url("/")
+        );
+
+        let mut engine = MslEngine::new();
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+        assert_eq!(engine.variables["name"], "Alice");
+        assert_eq!(engine.variables["id"], "");
+        let empty = &engine.manifest().empty_extractions;
+        assert_eq!(empty.iter().map(|empty| empty.variable.as_str()).collect::<Vec<_>>(), ["id", "product"]);
+        assert_eq!(empty[0].to_string(), format!("id = attr(\"data-id\") found nothing in the element matching \"a.user\" on {}", server.url("/u/1")));
+
+        let mut strict = MslEngine::with_config(EngineConfig { strict: true, ..EngineConfig::default() }).unwrap();
+        let error = strict.execute(parse_script(&script).unwrap()).await.unwrap_err();
+        assert_eq!(error.to_string(), empty[0].to_string());
+        assert!(!strict.variables.contains_key("id"));
+    }
+
+    #[tokio::test]
+    async fn test_set_reads_data_attributes() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="item" href="/1" data-id="1" data-user-id="alice" data-id-extra="x">One</a>
+                <a class="item" href="/2" data-id="2">Two</a>"#)),
+            ("/1", MockResponse::html("<h1>One</h1>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nclick \"a.item\"\n  set id = data(\"id\")\n  set user = data(\"userId\")\n  set ids = data(\"id\") all\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["id"], "1");
+        assert_eq!(engine.variables["user"], "alice");
+        assert_eq!(engine.variables["ids"], Value::List(vec!["1".to_string(), "2".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_foreach_iterates_page_links() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <a href="/a">A</a> <a href="/b">B</a> <a href="/c">C</a>
+            "#)),
+            ("/a", MockResponse::html(r#"<img src="/a.jpg">"#)),
+            ("/b", MockResponse::html(r#"<img src="/b.jpg">"#)),
+            ("/c", MockResponse::html(r#"<img src="/c.jpg">"#)),
+            ("/a.jpg", MockResponse::bytes("image/jpeg", "a")),
+            ("/b.jpg", MockResponse::bytes("image/jpeg", "b")),
+            ("/c.jpg", MockResponse::bytes("image/jpeg", "c")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nforeach link in links\n  open \"{{link}}\"\n  media\n    image\n  save to \"media\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            assert!(dir.path().join("media").join(name).exists());
+        }
+        assert_eq!(engine.variables["link"], server.url("/c"));
+    }
+
+    #[tokio::test]
+    async fn test_foreach_links_bind_link_text() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a href="/a" title="first"> Alpha </a><a href="/b">Beta</a><a href="/a">Alpha again</a>"#)),
+            ("/a", MockResponse::html("<p>a</p>")),
+            ("/b", MockResponse::html("<p>b</p>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nforeach link in links\n  set label = label + text + \";\"\n  set titles = attr(\"title\") or \"-\"\n  open \"{{link}}\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.variables.insert("label".to_string(), "".into());
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["label"], "Alpha;Beta;");
+        assert_eq!(engine.variables["titles"], "-");
+        assert_eq!(server.hits("/a"), 1);
+        assert!(engine.scope.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_repeat_binds_iteration_number() {
+        let server = MockServer::with_routes(vec![
+            ("/page/1", MockResponse::html("<title>One</title>")),
+            ("/page/2", MockResponse::html("<title>Two</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "repeat 2 as i\n  open \"{}\"\n  set last = title\n",
+            server.url("/page/{i}")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let paths: Vec<String> = server.requests().iter().map(|request| request.path.clone()).collect();
+        assert_eq!(paths, vec!["/page/1", "/page/2"]);
+        assert_eq!(engine.variables["last"], "Two");
+        assert_eq!(engine.variables["i"], "2");
+    }
+
+    #[tokio::test]
+    async fn test_foreach_media_save_paths_use_iteration_variables() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a href="/a">A</a> <a href="/b">B</a>"#)),
+            ("/a", MockResponse::html(r#"<title>Alpha</title><img src="/a/photo.jpg">"#)),
+            ("/b", MockResponse::html(r#"<title>Beta</title><img src="/b/photo.jpg"><img src="/b/extra.jpg">"#)),
+            ("/a/photo.jpg", MockResponse::bytes("image/jpeg", "a")),
+            ("/b/photo.jpg", MockResponse::bytes("image/jpeg", "b")),
+            ("/b/extra.jpg", MockResponse::bytes("image/jpeg", "extra")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+foreach link in links
+  open "{{link}}"
+  set name = title | lower
+  media
+    image
+      where src ~ "photo"
+  save to "media/{{name}}"
+  media
+    image
+      where src ~ "extra"
+      save to "extras/{{name}}"
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("media/alpha/photo.jpg")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dir.path().join("media/beta/photo.jpg")).unwrap(), b"b");
+        assert!(dir.path().join("extras/beta/extra.jpg").exists());
+        assert!(!dir.path().join("extras/alpha").exists());
+    }
+
+    #[tokio::test]
+    async fn test_click_each_downloads_every_detail_page() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <div class="card"><a href="/p/1">Red Lamp</a></div>
+                <div class="card"><a href="/p/2">Oak Desk</a></div>
+                <a href="/about">About</a>
+            "#)),
+            ("/p/1", MockResponse::html(r#"<img src="/img/hero-1.jpg"><img src="/img/logo.png">"#)),
+            ("/p/2", MockResponse::html(r#"<img src="/img/hero-2.jpg"><img src="/img/logo.png">"#)),
+            ("/img/hero-1.jpg", MockResponse::bytes("image/jpeg", "lamp")),
+            ("/img/hero-2.jpg", MockResponse::bytes("image/jpeg", "desk")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+click each ".card a"
+  set name = text | slug
+  media
+    image
+      where src ~ "hero"
+  save to "products/{{name}}"
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("products/red-lamp/hero-1.jpg")).unwrap(), b"lamp");
+        assert_eq!(std::fs::read(dir.path().join("products/oak-desk/hero-2.jpg")).unwrap(), b"desk");
+        assert_eq!(server.hits("/about"), 0);
+        assert_eq!(server.hits("/img/logo.png"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_media_resolves_against_redirected_url() {
+        let server = MockServer::with_routes(vec![
+            ("/start", MockResponse::new(301).header("Location", "/albums/summer/")),
+            ("/albums/summer/", MockResponse::html(r#"<img src="beach.jpg">"#)),
+            ("/albums/summer/beach.jpg", MockResponse::bytes("image/jpeg", "beach")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  image\nsave to \"media\"\n",
+            server.url("/start")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.current_url.as_deref(), Some(server.url("/albums/summer/").as_str()));
+        assert_eq!(std::fs::read(dir.path().join("media/beach.jpg")).unwrap(), b"beach");
+        assert_eq!(server.hits("/start"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_click_resolves_against_base_href() {
+        let server = MockServer::with_routes(vec![
+            ("/list", MockResponse::html(r#"<base href="/items/"><a class="item" href="42">Item</a>"#)),
+            ("/items/42", MockResponse::html("<title>Item 42</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!("open \"{}\"\nclick \"a.item\"\n  set name = title\n", server.url("/list"))).unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["name"], "Item 42");
+        assert_eq!(server.hits("/items/42"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_save_page_writes_current_html() {
+        let page = "<html><head><title>Alice</title></head><body><p>Profile</p></body></html>";
+        let server = MockServer::with_routes(vec![("/u/alice", MockResponse::html(page))]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+        engine.variables.insert("user".to_string(), "alice".into());
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nsave page to \"./pages/{{user}}/profile\"\nsave to \"archive/{{user}}.html\"\n",
+            server.url("/u/alice")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.path().join("pages/alice/profile")).unwrap(), page);
+        assert_eq!(std::fs::read_to_string(dir.path().join("archive/alice.html")).unwrap(), page);
+    }
+
+    #[tokio::test]
+    async fn test_media_includes_linked_audio() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <a href="/tracks/song.mp3">Download</a>
+                <a href="/tracks/live.mp4">Live video</a>
+            "#)),
+            ("/tracks/song.mp3", MockResponse::bytes("audio/mpeg", "mp3")),
+            ("/tracks/live.mp4", MockResponse::bytes("video/mp4", "mp4")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  audio\n    extensions mp3, mp4\nsave to \"media\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert!(dir.path().join("media/song.mp3").exists());
+        assert!(!dir.path().join("media/live.mp4").exists());
+        assert_eq!(engine.manifest().downloads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_stats_totals() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="next" href="/gallery">Gallery</a>"#)),
+            ("/gallery", MockResponse::html(r#"<img src="/a.jpg"><img src="/b.jpg">"#)),
+            ("/a.jpg", MockResponse::bytes("image/jpeg", vec![0; 300])),
+            ("/b.jpg", MockResponse::bytes("image/jpeg", vec![0; 700])),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nclick \"a.next\"\n  media\n    image\n  save to \"media\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let stats = engine.stats();
+        assert_eq!(stats.pages, 2);
+        assert_eq!(stats.media, 2);
+        assert_eq!(stats.bytes, 1_000);
+        assert!(stats.elapsed > std::time::Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_script_without_commands_is_a_no_op() {
+        let mut engine = MslEngine::new();
+        engine.execute(parse_script("# nothing to do yet\n\n").unwrap()).await.unwrap();
+
+        assert!(engine.variables.is_empty());
+        assert!(engine.manifest().downloads.is_empty());
+        assert_eq!(engine.stats().pages, 0);
+        assert!(engine.current_url.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_stops_remaining_commands() {
+        let server = MockServer::with_routes(vec![
+            ("/first", MockResponse::html("<title>First</title>")),
+            ("/second", MockResponse::html("<title>Second</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+        let token = engine.cancellation_token();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nwait 60\nopen \"{}\"\n",
+            server.url("/first"),
+            server.url("/second")
+        ))
+        .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            token.cancel();
+        });
+        let error = tokio::time::timeout(std::time::Duration::from_secs(5), engine.execute(script))
+            .await
+            .expect("cancelled run should stop promptly")
+            .unwrap_err();
+
+        assert!(error.is::<Cancelled>());
+        assert_eq!(server.hits("/first"), 1);
+        assert_eq!(server.hits("/second"), 0);
+        assert_eq!(engine.stats().pages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_url_regex_filters() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <img src="/photos/beach.jpg"><img src="/photos/thumb_beach.jpg"><img src="/ads/banner.jpg">
+            "#)),
+            ("/photos/beach.jpg", MockResponse::bytes("image/jpeg", "beach")),
+            ("/photos/thumb_beach.jpg", MockResponse::bytes("image/jpeg", "thumb")),
+            ("/ads/banner.jpg", MockResponse::bytes("image/jpeg", "ad")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            include_urls: vec![Regex::new("/photos/").unwrap()],
+            exclude_urls: vec![Regex::new("thumb_").unwrap()],
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  image\nsave to \"media\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert!(dir.path().join("media/beach.jpg").exists());
+        assert!(!dir.path().join("media/thumb_beach.jpg").exists());
+        assert!(!dir.path().join("media/banner.jpg").exists());
+        assert_eq!(server.hits("/ads/banner.jpg"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_auth_command_applies_to_later_requests() {
+        let server = MockServer::with_routes(vec![
+            ("/public", MockResponse::html(r#"<a class="key" data-token="abc123" href="/private">Private</a>"#)),
+            ("/private", MockResponse::html("<title>Private</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nclick \"a.key\"\n  set token = attr(\"data-token\")\nauth bearer \"{{token}}\"\nopen \"{}\"\n",
+            server.url("/public"),
+            server.url("/private")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(!requests[0].headers.contains_key("authorization"));
+        assert!(!requests[1].headers.contains_key("authorization"));
+        assert_eq!(requests[2].headers.get("authorization").map(String::as_str), Some("Bearer abc123"));
+    }
+
+    #[tokio::test]
+    async fn test_post_loads_response_page() {
+        let server = MockServer::start(|request| match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/search") => MockResponse::html(&format!(
+                "<title>Results</title><p>{}</p>",
+                String::from_utf8_lossy(&request.body)
+            )),
+            _ => MockResponse::new(404),
+        })
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "set term = \"red cats\"\npost \"{url}\" {{ q: \"{{term}}\", page: \"2\" }}\nset form = title\npost json \"{url}\" {{ q: \"{{term}}\" }}\n",
+            url = server.url("/search")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].headers["content-type"], "application/x-www-form-urlencoded");
+        assert_eq!(requests[0].body, b"q=red+cats&page=2");
+        assert_eq!(requests[1].headers["content-type"], "application/json");
+        assert_eq!(engine.variables["form"], "Results");
+        assert_eq!(engine.current_url.as_deref(), Some(server.url("/search").as_str()));
+        assert!(engine.current_html.as_deref().unwrap().contains(r#"<p>{"q":"red cats"}</p>"#));
+        assert_eq!(engine.stats().pages, 2);
+    }
+
+    #[tokio::test]
+    async fn test_set_reads_json_responses() {
+        let server = MockServer::with_routes(vec![(
+            "/api/user",
+            MockResponse::bytes(
+                "application/json; charset=utf-8",
+                r#"{"data": {"id": 42, "name": "Ada", "photos": [{"url": "/a.jpg"}, {"url": "/b.jpg"}]}}"#,
+            ),
+        )])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nset id = json(\"$.data.id\")\nset name = json(\"$.data.name\") | upper\nset photos = json(\"$.data.photos[*].url\") all\nset missing = json(\"$.data.email\") or \"none\"\n",
+            server.url("/api/user")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["id"], "42");
+        assert_eq!(engine.variables["name"], "ADA");
+        assert_eq!(engine.variables["photos"], Value::List(vec!["/a.jpg".to_string(), "/b.jpg".to_string()]));
+        assert_eq!(engine.variables["missing"], "none");
+    }
+
+    #[tokio::test]
+    async fn test_set_page_title_and_url() {
+        let server = MockServer::with_routes(vec![(
+            "/albums/42",
+            MockResponse::html("<html><head><title>Summer Trip</title></head><body></body></html>"),
+        )])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nset album = title\nset page = url\nset id = url.split(\"/\")[-1]\n",
+            server.url("/albums/42")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["album"], "Summer Trip");
+        assert_eq!(engine.variables["page"], server.url("/albums/42"));
+        assert_eq!(engine.variables["id"], "42");
+    }
+
+    #[tokio::test]
+    async fn test_extract_json_ld() {
+        let server = MockServer::with_routes(vec![
+            ("/product", MockResponse::html(r#"
+                <script type="application/ld+json">{ broken</script>
+                <script type="application/ld+json">
+                    {
+                        "@type": "Product",
+                        "name": "Desk Lamp",
+                        "image": ["/images/lamp.jpg", {"@type": "ImageObject", "contentUrl": "/images/lamp-side.jpg"}]
+                    }
+                </script>
+            "#)),
+            ("/images/lamp.jpg", MockResponse::bytes("image/jpeg", "front")),
+            ("/images/lamp-side.jpg", MockResponse::bytes("image/jpeg", "side")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nextract jsonld \"$.name\" into product\nextract jsonld \"$.image[0]\" into cover\nextract jsonld \"$.image\" into media\nsave to \"{{product}}\"\n",
+            server.url("/product")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["product"], "Desk Lamp");
+        assert_eq!(engine.variables["cover"], "/images/lamp.jpg");
+        assert_eq!(std::fs::read(dir.path().join("Desk Lamp/lamp.jpg")).unwrap(), b"front");
+        assert_eq!(std::fs::read(dir.path().join("Desk Lamp/lamp-side.jpg")).unwrap(), b"side");
+    }
+
+    fn failing_download_server_routes() -> Vec<(&'static str, MockResponse)> {
+        vec![
+            ("/", MockResponse::html(r#"<img src="/a.jpg"><img src="/missing.jpg"><img src="/c.jpg">"#)),
+            ("/a.jpg", MockResponse::bytes("image/jpeg", "a")),
+            ("/c.jpg", MockResponse::bytes("image/jpeg", "c")),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_continues_past_failed_downloads() {
+        let server = MockServer::with_routes(failing_download_server_routes()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!("open \"{}\"\nmedia\n  image\nsave to \"media\"\n", server.url("/"))).unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert!(dir.path().join("media/a.jpg").exists());
+        assert!(!dir.path().join("media/missing.jpg").exists());
+        assert!(dir.path().join("media/c.jpg").exists());
+        let failed = &engine.manifest().failed;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].url, server.url("/missing.jpg"));
+        assert!(failed[0].error.contains("404"), "{}", failed[0].error);
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_stops_at_failed_download() {
+        let server = MockServer::with_routes(failing_download_server_routes()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            fail_fast: true,
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!("open \"{}\"\nmedia\n  image\nsave to \"media\"\n", server.url("/"))).unwrap();
+        assert!(engine.execute(script).await.is_err());
+
+        assert!(dir.path().join("media/a.jpg").exists());
+        assert_eq!(server.hits("/c.jpg"), 0);
+        assert!(engine.manifest().failed.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_fail_fast_finishes_downloads_under_way() {
+        let server = MockServer::start(|request| match request.path.as_str() {
+            "/" => MockResponse::html(r#"<img src="/slow.jpg"><img src="/broken.jpg">"#),
+            "/slow.jpg" => {
+                std::thread::sleep(Duration::from_millis(100));
+                MockResponse::bytes("image/jpeg", "slow")
+            }
+            _ => MockResponse::new(404),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            concurrency: 2,
+            fail_fast: true,
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!("open \"{}\"\nmedia\n  image\nsave to \".\"\n", server.url("/"))).unwrap();
+        assert!(engine.execute(script).await.is_err());
+
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, ["slow.jpg"]);
+        assert!(engine.claimed_paths.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expect_count() {
+        let server = MockServer::with_routes(vec![("/", MockResponse::html("<ul><li>a</li><li>b</li></ul>"))]).await;
+        let url = server.url("/");
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!("open \"{}\"\nexpect \"li\" count = 2\nexpect \"li\" count >= 1", url)).unwrap();
+        engine.execute(script).await.unwrap();
+
+        let script = parse_script("expect \".product\" count >= 1").unwrap();
+        let error = engine.execute(script).await.unwrap_err().to_string();
+        assert!(error.contains("expect \".product\" count >= 1 failed: found 0"), "{}", error);
+        assert!(error.contains(&url), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_sniffing_corrects_extension() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        let server = MockServer::with_routes(vec![
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", png.clone())),
+            ("/other.jpg", MockResponse::bytes("image/jpeg", png)),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut engine = MslEngine::with_config(EngineConfig {
+            sniff_media_type: true,
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        download(&mut engine, &image_item(server.url("/photo.jpg")), dir.path()).await.unwrap();
+        assert!(dir.path().join("photo.png").exists());
+        assert!(!dir.path().join("photo.jpg").exists());
+        assert_eq!(engine.manifest().downloads[0].path, dir.path().join("photo.png"));
+
+        let mut engine = MslEngine::new();
+        download(&mut engine, &image_item(server.url("/other.jpg")), dir.path()).await.unwrap();
+        assert!(dir.path().join("other.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_skips_completed_downloads() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/a.jpg"><img src="/b.jpg">"#)),
+            ("/a.jpg", MockResponse::bytes("image/jpeg", "a")),
+            ("/b.jpg", MockResponse::bytes("image/jpeg", "b")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("manifest.json");
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \"media\"\n", server.url("/"));
+
+        for _ in 0..2 {
+            let mut engine = MslEngine::with_config(EngineConfig {
+                output_dir: Some(dir.path().to_path_buf()),
+                ..EngineConfig::default()
+            })
+            .unwrap();
+            if manifest_path.exists() {
+                engine.resume(RunManifest::read_from(&manifest_path).unwrap());
+            }
+            engine.execute(parse_script(&script).unwrap()).await.unwrap();
+            engine.manifest().write_to(&manifest_path).unwrap();
+        }
+
+        assert_eq!(server.hits("/a.jpg"), 1);
+        assert_eq!(server.hits("/b.jpg"), 1);
+        assert_eq!(RunManifest::read_from(&manifest_path).unwrap().downloads.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_deadline_stops_slow_run() {
+        let mut engine = MslEngine::with_config(EngineConfig {
+            deadline: Some(Duration::from_millis(100)),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        let script = parse_script("set before = \"1\"\nwait 30\nset after = \"1\"").unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+
+        assert!(error.is::<DeadlineExceeded>(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(engine.variables.contains_key("before"));
+        assert!(!engine.variables.contains_key("after"));
+    }
+
+    #[tokio::test]
+    async fn test_max_pages_stops_crawl() {
+        let server = MockServer::with_routes(vec![
+            ("/1", MockResponse::html(r#"<a class="next" href="/2">Next</a>"#)),
+            ("/2", MockResponse::html(r#"<a class="next" href="/3">Next</a>"#)),
+            ("/3", MockResponse::html(r#"<a class="next" href="/4">Next</a>"#)),
+            ("/4", MockResponse::html("<p>Last page</p>")),
+        ])
+        .await;
+        let script = format!(
+            "open \"{}\"\nclick \"a.next\"\n  click \"a.next\"\n    click \"a.next\"\nset done = \"yes\"\n",
+            server.url("/1")
+        );
+
+        let mut engine = MslEngine::with_config(EngineConfig { max_pages: Some(2), ..EngineConfig::default() }).unwrap();
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(engine.stats().pages, 2);
+        assert_eq!((server.hits("/1"), server.hits("/2"), server.hits("/3")), (1, 1, 0));
+        assert!(!engine.variables.contains_key("done"));
+    }
+
+    #[tokio::test]
+    async fn test_selector_retries_refetch_late_content() {
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let server = MockServer::start(move |request| {
+            let fetch = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match (request.path.as_str(), fetch) {
+                ("/", 0) => MockResponse::html("<title></title><p>Loading...</p>"),
+                ("/", _) => MockResponse::html(r#"<title>Gallery</title><img src="/late.jpg">"#),
+                ("/late.jpg", _) => MockResponse::bytes("image/jpeg", "jpeg bytes"),
+                _ => MockResponse::new(404),
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let script = parse_script(&format!("open \"{}\"\nset name = title\nmedia\n  image\nsave to \"./media\"\n", server.url("/"))).unwrap();
+
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            selector_retries: 2,
+            selector_retry_delay: Duration::from_millis(10),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        engine.execute(script.clone()).await.unwrap();
+
+        assert_eq!(engine.variables["name"], "Gallery");
+        assert_eq!(server.hits("/"), 2);
+        assert!(dir.path().join("media/late.jpg").exists());
+
+        // Without retries the first, empty fetch is all there is
+        fetches.store(0, std::sync::atomic::Ordering::SeqCst);
+        let mut engine = MslEngine::new();
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.variables["name"], "");
+    }
+
+    #[tokio::test]
+    async fn test_wait_random_stays_in_bounds() {
+        for _ in 0..100 {
+            let delay = random_delay(2, 5);
+            assert!((Duration::from_secs(2)..=Duration::from_secs(5)).contains(&delay), "{:?}", delay);
+        }
+        assert_eq!(random_delay(3, 3), Duration::from_secs(3));
+
+        let mut engine = MslEngine::new();
+        let started = Instant::now();
+        engine.execute(parse_script("wait random(0, 1)").unwrap()).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(1500), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_request_hooks_see_every_request() {
+        struct TagRequests {
+            statuses: Arc<std::sync::Mutex<Vec<u16>>>,
+        }
+
+        impl RequestHook for TagRequests {
+            fn on_request(&self, request: &mut reqwest::Request) {
+                request.headers_mut().insert("x-scraped-by", "msl-test".parse().unwrap());
+            }
+
+            fn on_response(&self, response: &reqwest::Response) {
+                self.statuses.lock().unwrap().push(response.status().as_u16());
+            }
+        }
+
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/photo.jpg">"#)),
+            ("/photo.jpg", MockResponse::bytes("image/jpeg", "jpeg bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let statuses = Arc::new(std::sync::Mutex::new(Vec::new()));
+        engine.add_request_hook(TagRequests { statuses: statuses.clone() });
+
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \"media\"\nopen \"{}\"\n", server.url("/"), server.url("/gone"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|request| request.headers["x-scraped-by"] == "msl-test"));
+        assert_eq!(*statuses.lock().unwrap(), vec![200, 200, 404]);
+    }
+
+    #[tokio::test]
+    async fn test_media_sink_receives_downloads() {
+        /// The URL and content of each stored file.
+        type Files = HashMap<PathBuf, (String, Vec<u8>)>;
+
+        #[derive(Clone, Default)]
+        struct MemorySink {
+            files: Arc<std::sync::Mutex<Files>>,
+        }
+
+        impl MediaSink for MemorySink {
+            fn write<'a>(&'a self, item: &'a MediaItem, path: &'a Path, body: MediaBody<'a>) -> BoxFuture<'a, Result<PathBuf>> {
+                Box::pin(async move {
+                    let mut bytes = Vec::new();
+                    body.read_to_end(&mut bytes).await?;
+                    self.files.lock().unwrap().insert(path.to_path_buf(), (item.url.clone(), bytes));
+                    Ok(path.to_path_buf())
+                })
+            }
+
+            fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<PathBuf>> {
+                Box::pin(async move {
+                    let mut files = self.files.lock().unwrap();
+                    let file = files.remove(from).context("no such file")?;
+                    files.insert(to.to_path_buf(), file);
+                    Ok(to.to_path_buf())
+                })
+            }
+
+            fn remove<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<()>> {
+                Box::pin(async move {
+                    self.files.lock().unwrap().remove(path).context("no such file")?;
+                    Ok(())
+                })
+            }
+        }
+
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/a.jpg"><img src="/b.png"><img src="/copy.jpg">"#)),
+            ("/a.jpg", MockResponse::bytes("image/jpeg", "a bytes")),
+            ("/b.png", MockResponse::bytes("image/png", "b bytes")),
+            ("/copy.jpg", MockResponse::bytes("image/jpeg", "a bytes")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            dedupe_content: true,
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let sink = MemorySink::default();
+        engine.set_media_sink(sink.clone());
+
+        let script = format!("open \"{}\"\nmedia\n  image\nsave to \"media\"\n", server.url("/"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        let files = sink.files.lock().unwrap();
+        let media = dir.path().join("media");
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[&media.join("a.jpg")], (server.url("/a.jpg"), b"a bytes".to_vec()));
+        assert_eq!(files[&media.join("b.png")], (server.url("/b.png"), b"b bytes".to_vec()));
+        assert_eq!(engine.manifest().downloads[2].duplicate_of.as_deref(), Some(media.join("a.jpg").as_path()));
+        assert!(!media.exists());
+    }
+
+    #[tokio::test]
+    async fn test_name_template() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<img src="/img/cat.jpg"><img src="/img/dog">"#)),
+            ("/img/cat.jpg", MockResponse::bytes("image/jpeg", "cat")),
+            ("/img/dog", MockResponse::bytes("image/jpeg", "dog")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            name_template: Some(NameTemplate::parse("{album}-{index}-{host}-{basename}-{hash}.{ext}").unwrap()),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+
+        let script = format!("open \"{}\"\nset album = \"pets\"\nmedia\n  image\nsave to \"media\"\n", server.url("/"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        let hash = |content: &str| format!("{:x}", Sha256::digest(content))[..16].to_string();
+        let names: Vec<String> = engine
+            .manifest()
+            .downloads
+            .iter()
+            .map(|record| record.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec![
+            format!("pets-1-127.0.0.1-cat-{}.jpg", hash("cat")),
+            format!("pets-2-127.0.0.1-dog-{}.jpg", hash("dog")),
+        ]);
+        assert!(dir.path().join("media").join(&names[0]).exists());
+        assert!(engine.manifest().downloads[0].sha256.is_none());
+        assert!(engine.claimed_paths.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_name_template_values_are_sanitized() {
+        let mut engine = MslEngine::new();
+        engine.variables.insert("album".to_string(), "..".to_string().into());
+        engine.variables.insert("title".to_string(), "{index}: a/b?".to_string().into());
+        let template = NameTemplate::parse("{album}-{title}-{index}.{ext}").unwrap();
+
+        let name = engine.templated_filename(&template, "https://example.com/cat.jpg", Path::new("cat.jpg"), None).unwrap();
+        assert_eq!(name, "_-{index}_ a_b_-1.jpg");
+    }
+
+    #[tokio::test]
+    async fn test_name_template_rejects_unknown_tokens() {
+        let mut engine = MslEngine::with_config(EngineConfig {
+            name_template: Some(NameTemplate::parse("{album}-{index}.{ext}").unwrap()),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+
+        let error = engine.execute(parse_script("wait 0").unwrap()).await.unwrap_err();
+        assert!(error.to_string().contains("{album}"), "{}", error);
+
+        let script = parse_script("foreach link in links\n  set album = \"x\"\n").unwrap();
+        assert!(engine.check_name_template(&script).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_log_levels() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<title>Home</title><img src="/a.jpg">"#)),
+            ("/a.jpg", MockResponse::bytes("image/jpeg", "a")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let logs = LogBuffer::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_ansi(false)
+                .without_time()
+                .with_writer(logs.clone())
+                .finish(),
+        );
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = format!("open \"{}\"\nset name = title\nwait 0\nmedia\n  image\n", server.url("/"));
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        let level_of = |message: &str| {
+            let line = logs.contents().lines().find(|line| line.contains(message)).map(str::to_string);
+            line.unwrap_or_else(|| panic!("no log line containing {:?}", message))
+                .split_whitespace()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(level_of("Opening:"), "INFO");
+        assert_eq!(level_of("Loaded page: Home"), "DEBUG");
+        assert_eq!(level_of("Set variable: name = Home"), "DEBUG");
+        assert_eq!(level_of("Found 1 image items"), "INFO");
+        assert_eq!(level_of("Downloading:"), "DEBUG");
+        assert_eq!(level_of("Downloaded:"), "DEBUG");
+        assert_eq!(level_of("Wait completed"), "TRACE");
+    }
+
+    #[tokio::test]
+    async fn test_set_first_and_all_matches() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <a class="user" href="/u/alice">Alice</a>
+                <a class="user" href="/u/bob">Bob</a>
+                <a class="user">Carol</a>
+            "#)),
+            ("/u/alice", MockResponse::html("<title>Alice</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+click "a.user"
+  set user = text
+  set users = text all
+  set links = attr("href") all
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["user"], "Alice");
+        assert_eq!(engine.variables["users"], Value::List(vec!["Alice".into(), "Bob".into(), "Carol".into()]));
+        assert_eq!(engine.variables["links"], Value::List(vec!["/u/alice".into(), "/u/bob".into()]));
+    }
+
+    #[tokio::test]
+    async fn test_foreach_iterates_list_variable() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <a class="user" href="/u/alice">Alice</a>
+                <a class="user" href="/u/bob">Bob</a>
+            "#)),
+            ("/u/alice", MockResponse::html("<title>Alice</title>")),
+            ("/u/bob", MockResponse::html("<title>Bob</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+click "a.user"
+  set links = attr("href") all
+set copy = links
+foreach link in copy
+  open "{}{{link}}"
+  set last = title
+"#,
+            server.url("/"),
+            server.url("")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        // Once through the click, once from the loop
+        assert_eq!(server.hits("/u/alice"), 2);
+        assert_eq!(server.hits("/u/bob"), 1);
+        assert_eq!(engine.variables["copy"], Value::List(vec!["/u/alice".into(), "/u/bob".into()]));
+        assert_eq!(engine.variables["last"], "Bob");
+    }
+
+    #[tokio::test]
+    async fn test_list_variable_rejected_as_scalar() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="user" href="/u">Alice</a><a class="user">Bob</a>"#)),
+            ("/u", MockResponse::html("<title>Alice</title>")),
+        ])
+        .await;
+
+        for usage in [r#"set label = "Users: {users}""#, "set label = users + \"!\"", "open \"{users}\""] {
+            let mut engine = MslEngine::new();
+            let script = parse_script(&format!(
+                "open \"{}\"\nclick \"a.user\"\n  set users = text all\n{}\n",
+                server.url("/"),
+                usage
+            ))
+            .unwrap();
+            let error = engine.execute(script).await.unwrap_err();
+            assert!(format!("{:#}", error).contains("users is a list"), "{}: {:#}", usage, error);
+        }
+
+        let mut engine = MslEngine::new();
+        let script = parse_script("set name = \"Alice\"\nforeach x in name\n  wait 0\n").unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("name is not a list"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn test_set_reads_response_headers() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html("<p>home</p>").header("X-Revision", "42").header("ETag", "\"abc\"")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+set revision = header("x-revision")
+set etag = header("ETag")
+set missing = header("x-missing") or "none"
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["revision"], "42");
+        assert_eq!(engine.variables["etag"], "\"abc\"");
+        assert_eq!(engine.variables["missing"], "none");
+    }
+
+    #[tokio::test]
+    async fn test_page_cache_reuses_unchanged_pages() {
+        let server = MockServer::start(|request| {
+            if request.headers.get("if-none-match").map(String::as_str) == Some("\"v1\"") {
+                MockResponse::new(304).header("ETag", "\"v1\"")
+            } else {
+                MockResponse::html("<title>First fetch</title>").header("ETag", "\"v1\"")
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig {
+            scraper: ScraperConfig {
+                page_cache: Some(dir.path().join("pages.json")),
+                ..ScraperConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+        let script = format!("open \"{}\"\nset heading = title\n", server.url("/"));
+
+        for _ in 0..2 {
+            let mut engine = MslEngine::with_config(config.clone()).unwrap();
+            engine.execute(parse_script(&script).unwrap()).await.unwrap();
+            assert_eq!(engine.variables["heading"], "First fetch");
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].headers.contains_key("if-none-match"));
+        assert_eq!(requests[1].headers["if-none-match"], "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_cookie_file_keeps_session_between_runs() {
+        let server = MockServer::start(|request| match request.path.as_str() {
+            "/login" => MockResponse::html("<title>Welcome</title>").header("Set-Cookie", "session=abc123; Path=/"),
+            _ => MockResponse::html("<title>Account</title>"),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = EngineConfig {
+            scraper: ScraperConfig {
+                cookie_file: Some(dir.path().join("cookies.json")),
+                ..ScraperConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+
+        for path in ["/login", "/account"] {
+            let mut engine = MslEngine::with_config(config.clone()).unwrap();
+            engine.execute(parse_script(&format!("open \"{}\"\n", server.url(path))).unwrap()).await.unwrap();
+            engine.save_session().unwrap();
+        }
+
+        let requests = server.requests();
+        assert!(!requests[0].headers.contains_key("cookie"));
+        assert_eq!(requests[1].headers["cookie"], "session=abc123");
+    }
+
+    #[tokio::test]
+    async fn test_html_cache_avoids_refetching() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="first" href="/detail">One</a><a class="second" href="/detail">Two</a>"#)),
+            ("/detail", MockResponse::html("<title>Detail</title>")),
+        ])
+        .await;
+        let script = format!(
+            r#"
+open "{home}"
+click "a.first"
+  set first = title
+open "{home}"
+click "a.second"
+  set second = title
+open "{detail}"
+"#,
+            home = server.url("/"),
+            detail = server.url("/detail")
+        );
+
+        let mut engine = MslEngine::with_config(EngineConfig { html_cache_size: 8, ..EngineConfig::default() }).unwrap();
+        engine.execute(parse_script(&script).unwrap()).await.unwrap();
+
+        assert_eq!(engine.variables["first"], "Detail");
+        assert_eq!(engine.variables["second"], "Detail");
+        // The second click is served from the cache; the explicit open isn't
+        assert_eq!(server.hits("/detail"), 2);
+        assert_eq!(engine.stats().pages, 4);
+
+        let mut uncached = MslEngine::new();
+        uncached.execute(parse_script(&script).unwrap()).await.unwrap();
+        assert_eq!(server.hits("/detail"), 5);
+        assert_eq!(server.hits("/"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_set_applies_transforms() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="photo" href="/p/1">  My Photo </a>"#)),
+            ("/p/1", MockResponse::html("<title>Café &amp; Bar: Summer 2024!</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+click "a.photo"
+  set name = text | trim | lower | replace(" ", "_")
+  set shout = text | trim | upper
+  set slug = title | slug
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["name"], "my_photo");
+        assert_eq!(engine.variables["shout"], "MY PHOTO");
+        assert_eq!(engine.variables["slug"], "café-bar-summer-2024");
+    }
+
+    #[tokio::test]
+    async fn test_scoped_selectors_read_inside_each_element() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <h2 class="name">Featured</h2>
+                <a class="card" href="/1"><h2 class="name">First</h2><img class="thumb" src="/1.jpg"><span class="tag">a</span></a>
+                <a class="card" href="/2"><h2 class="name">Second</h2><img class="thumb" src="/2.jpg"><span class="tag">b</span><span class="tag">c</span></a>
+            "#)),
+            ("/1", MockResponse::html("<h2 class=\"name\">Page one</h2>")),
+            ("/2", MockResponse::html("<h2 class=\"name\">Page two</h2>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+set featured = text(".name")
+click each "a.card"
+  set name = text(".name")
+  set thumb = attr("src", ".card img.thumb")
+  set tags = text(".tag") all
+  set seen = seen + name + " " + thumb + ";"
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["featured"], "Featured");
+        assert_eq!(engine.variables["seen"], "First /1.jpg;Second /2.jpg;");
+        assert_eq!(engine.variables["tags"], Value::List(vec!["b".to_string(), "c".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_dump_failures_saves_pages_where_nothing_was_found() {
+        let page = r#"<h1 class="title">Redesigned</h1>"#;
+        let server = MockServer::with_routes(vec![("/shop/", MockResponse::html(page))]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let failures = dir.path().join("failures");
+        let mut engine = MslEngine::with_config(EngineConfig {
+            dump_failures: Some(failures.clone()),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nset title = text(\"h1.title\")\nset price = text(\"div.card .price\")\nmedia\n  image\n  save to \"{}\"\nexpect \"div.card\" count > 0\n",
+            server.url("/shop/"),
+            dir.path().join("media").display()
+        ))
+        .unwrap();
+        assert!(engine.execute(script).await.is_err());
+
+        let host = server.url("").trim_start_matches("http://").replace(':', "_");
+        let mut dumped: Vec<String> = std::fs::read_dir(&failures)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        dumped.sort();
+        assert_eq!(
+            dumped,
+            [
+                format!("{}_shop--div.card.html", host),
+                format!("{}_shop--image_media.html", host),
+                format!("{}_shop--text_div.card_.price.html", host),
+            ]
+        );
+        let saved = std::fs::read_to_string(failures.join(&dumped[0])).unwrap();
+        assert!(saved.contains("Redesigned"));
+
+        assert_eq!(failure_file_name("https://example.com/a?b=1", "a:contains(\"Next\")"), "example.com_a_b_1--a_contains_Next.html");
+    }
+
+    #[tokio::test]
+    async fn test_set_reads_element_markup() {
+        // One attribute, since attribute order isn't kept when serializing
+        let card = r#"<a href="/1"><h2>First</h2><p class="body">Some <b>bold</b> text</p></a>"#;
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(&format!("<div>{}</div>", card))),
+            ("/1", MockResponse::html("<h1>One</h1>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nset outside = html\nclick \"a\"\n  set inner = html\n  set outer = outerhtml\n  set body = html(\".body\")\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["outer"], card);
+        assert_eq!(engine.variables["inner"], r#"<h2>First</h2><p class="body">Some <b>bold</b> text</p>"#);
+        assert_eq!(engine.variables["body"], "Some <b>bold</b> text");
+        assert_eq!(engine.variables["outside"], "");
+    }
+
+    #[tokio::test]
+    async fn test_set_extracts_regex_captures() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"<a class="profile" href="/user/4217/photos">Profile: /user/4217/</a>"#)),
+            ("/user/4217/photos", MockResponse::html("<title>Photos</title>")),
+        ])
+        .await;
+        let mut engine = MslEngine::new();
+
+        let script = parse_script(&format!(
+            r#"
+open "{}"
+click "a.profile"
+  set id = text | regex("/user/(\d+)/", 1)
+  set whole = attr("href") | regex("user/\d+", 0)
+  set missing = text | regex("/post/(\d+)", 1) or "none"
+"#,
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert_eq!(engine.variables["id"], "4217");
+        assert_eq!(engine.variables["whole"], "user/4217");
+        assert_eq!(engine.variables["missing"], "none");
+        assert_eq!(engine.regexes.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_or_filter_downloads_union() {
+        let server = MockServer::with_routes(vec![
+            ("/", MockResponse::html(r#"
+                <img src="/a/1.jpg"><img src="/b/2.jpg"><img src="/c/3.jpg">
+            "#)),
+            ("/a/1.jpg", MockResponse::bytes("image/jpeg", "1")),
+            ("/b/2.jpg", MockResponse::bytes("image/jpeg", "2")),
+            ("/c/3.jpg", MockResponse::bytes("image/jpeg", "3")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            ..EngineConfig::default()
+        }).unwrap();
+
+        let script = parse_script(&format!(
+            "open \"{}\"\nmedia\n  image\n    where src ~ \"/a/\" or src ~ \"/b/\"\nsave to \"media\"\n",
+            server.url("/")
+        ))
+        .unwrap();
+        engine.execute(script).await.unwrap();
+
+        assert!(dir.path().join("media/1.jpg").exists());
+        assert!(dir.path().join("media/2.jpg").exists());
+        assert!(!dir.path().join("media/3.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_groups_run_at_once() {
+        let server = MockServer::with_routes(vec![
+            ("/a/", MockResponse::html(r#"<img src="/a/1.jpg">"#)),
+            ("/b/", MockResponse::html(r#"<img src="/b/2.jpg">"#)),
+            ("/a/1.jpg", MockResponse::bytes("image/jpeg", "1")),
+            ("/b/2.jpg", MockResponse::bytes("image/jpeg", "2")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MslEngine::with_config(EngineConfig {
+            output_dir: Some(dir.path().to_path_buf()),
+            parallel_groups: 2,
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let clock = MockClock::new();
+        engine.set_clock(clock.clone()).unwrap();
+
+        let script = parse_script(&format!(
+            "set folder = \"media\"\nopen \"{}\"\nwait 5\nmedia\n  image\nsave to \"{{folder}}/a\"\nopen \"{}\"\nwait 5\nmedia\n  image\nsave to \"{{folder}}/b\"\n",
+            server.url("/a/"),
+            server.url("/b/")
+        ))
+        .unwrap();
+        let (result, ()) = tokio::join!(engine.execute(script), async {
+            // Run one after the other, the second wait wouldn't start
+            // until the first was over
+            let both_waiting = tokio::time::timeout(Duration::from_secs(5), clock.wait_for_sleeps(2)).await;
+            clock.advance(Duration::from_secs(5));
+            both_waiting.expect("both groups should wait at once");
+        });
+        result.unwrap();
+
+        assert!(dir.path().join("media/a/1.jpg").exists());
+        assert!(dir.path().join("media/b/2.jpg").exists());
+        assert_eq!(engine.manifest().downloads.len(), 2);
+        assert_eq!(engine.stats().pages, 2);
+        assert_eq!(engine.current_url.as_deref(), Some(server.url("/b/").as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_parallel_groups_share_run_limits() {
+        let server = MockServer::with_routes(vec![
+            ("/a/", MockResponse::html(r#"<img src="/a/1.jpg">"#)),
+            ("/b/", MockResponse::html(r#"<img src="/b/2.jpg">"#)),
+            ("/a/1.jpg", MockResponse::bytes("image/jpeg", "same bytes")),
+            ("/b/2.jpg", MockResponse::bytes("image/jpeg", "same bytes")),
+        ])
+        .await;
+        let other_host = server.url("/b/").replace("127.0.0.1", "cdn.test");
+        let run = |config: EngineConfig, second: &str| {
+            let dir = tempfile::tempdir().unwrap();
+            let engine = MslEngine::with_config(EngineConfig {
+                output_dir: Some(dir.path().to_path_buf()),
+                parallel_groups: 2,
+                scraper: ScraperConfig {
+                    resolve: vec![("cdn.test".to_string(), "127.0.0.1".parse().unwrap())],
+                    ..ScraperConfig::default()
+                },
+                ..config
+            })
+            .unwrap();
+            let script = format!("open \"{}\"\nmedia\n  image\nopen \"{}\"\nmedia\n  image\n", server.url("/a/"), second);
+            (dir, engine, parse_script(&script).unwrap())
+        };
+
+        let (_dir, mut engine, script) = run(EngineConfig { max_pages: Some(1), ..EngineConfig::default() }, &server.url("/b/"));
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.stats().pages, 1);
+
+        let (_dir, mut engine, script) = run(EngineConfig { same_host: true, ..EngineConfig::default() }, &other_host);
+        let error = engine.execute(script).await.unwrap_err();
+        assert!(error.is::<OffHost>(), "{}", error);
+        assert_eq!(engine.stats().pages, 1);
+        assert_eq!(engine.manifest().downloads.len(), 1);
+
+        let (_dir, mut engine, script) = run(EngineConfig { dedupe_content: true, ..EngineConfig::default() }, &server.url("/b/"));
+        engine.execute(script).await.unwrap();
+        let duplicates = engine.manifest().downloads.iter().filter(|record| record.duplicate_of.is_some()).count();
+        assert_eq!(duplicates, 1);
+    }
+
+    #[tokio::test]
+    async fn test_waits_run_on_the_engine_clock() {
+        let mut engine = MslEngine::new();
+        let clock = MockClock::new();
+        engine.set_clock(clock.clone()).unwrap();
+        let shared = engine.scraper.clone();
+        assert!(engine.set_clock(TokioClock).is_err());
+        drop(shared);
+
+        let script = parse_script("wait 30\nset middle = \"1\"\nwait random(2, 5)\nset after = \"1\"").unwrap();
+        let (result, ()) = tokio::join!(engine.execute(script), async {
+            clock.wait_for_sleeps(1).await;
+            clock.advance(Duration::from_secs(30));
+            clock.wait_for_sleeps(2).await;
+            clock.advance(Duration::from_secs(5));
+        });
+        result.unwrap();
+
+        let sleeps = clock.sleeps();
+        assert_eq!(sleeps[0], Duration::from_secs(30));
+        assert!((Duration::from_secs(2)..=Duration::from_secs(5)).contains(&sleeps[1]), "{:?}", sleeps);
+        assert!(engine.variables.contains_key("after"));
+
+        // The deadline is measured on the same clock
+        let mut engine = MslEngine::with_config(EngineConfig {
+            deadline: Some(Duration::from_secs(60)),
+            ..EngineConfig::default()
+        })
+        .unwrap();
+        let clock = MockClock::new();
+        engine.set_clock(clock.clone()).unwrap();
+        let script = parse_script("wait 3600\nset after = \"1\"").unwrap();
+        let (result, ()) = tokio::join!(engine.execute(script), async {
+            clock.wait_for_sleeps(2).await;
+            clock.advance(Duration::from_secs(60));
+        });
+
+        assert!(result.unwrap_err().is::<DeadlineExceeded>());
+        assert!(!engine.variables.contains_key("after"));
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Result};
+
+/// Tokens every download can fill in, besides script variables.
+pub const BUILTIN_TOKENS: &[&str] = &["index", "host", "basename", "ext", "hash"];
+
+/// A download file name pattern such as `{index}-{host}-{basename}.{ext}`.
+///
+/// `{index}` counts downloads from 1, `{host}` is the media URL's host,
+/// `{basename}` and `{ext}` are the file name the URL suggests split at its
+/// last dot, `{hash}` is the first 16 hex digits of the content's SHA-256,
+/// and any other `{name}` is the script variable of that name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    template: String,
+    tokens: Vec<String>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut tokens = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                bail!("unmatched '}}' in name template: {}", template);
+            }
+            let Some(end) = rest[start..].find('}') else {
+                bail!("unclosed '{{' in name template: {}", template);
+            };
+            let token = &rest[start + 1..start + end];
+            if token.is_empty() || !token.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                bail!("invalid token '{{{}}}' in name template: {}", token, template);
+            }
+            tokens.push(token.to_string());
+            rest = &rest[start + end + 1..];
+        }
+        if template.contains(['/', '\\']) {
+            bail!("name template must not contain path separators: {}", template);
+        }
+
+        Ok(Self { template: template.to_string(), tokens })
+    }
+
+    /// Every `{token}` in the template, in order.
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    pub fn uses(&self, token: &str) -> bool {
+        self.tokens.iter().any(|used| used == token)
+    }
+
+    /// Fills in each token from `lookup`, left to right, so a value that
+    /// itself looks like `{token}` is kept as is. Path separators in the
+    /// values are replaced so the result stays a single file name.
+    pub fn render(&self, lookup: impl Fn(&str) -> String) -> String {
+        let mut name = String::new();
+        let mut rest = self.template.as_str();
+        for token in &self.tokens {
+            let start = rest.find('{').expect("parsed tokens are in the template");
+            name.push_str(&rest[..start]);
+            name.push_str(&lookup(token).replace(['/', '\\'], "_"));
+            rest = &rest[start + token.len() + 2..];
+        }
+        name.push_str(rest);
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_render() {
+        let template = NameTemplate::parse("{index}-{host}-{basename}.{ext}").unwrap();
+        assert_eq!(template.tokens(), ["index", "host", "basename", "ext"]);
+        assert!(template.uses("host"));
+        assert!(!template.uses("hash"));
+
+        let name = template.render(|token| match token {
+            "index" => "7".to_string(),
+            "host" => "cdn.example.com".to_string(),
+            "basename" => "a/b".to_string(),
+            _ => "png".to_string(),
+        });
+        assert_eq!(name, "7-cdn.example.com-a_b.png");
+
+        // Values aren't expanded again
+        let template = NameTemplate::parse("{title}-{index}").unwrap();
+        let name = template.render(|token| if token == "title" { "{index}".to_string() } else { "1".to_string() });
+        assert_eq!(name, "{index}-1");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(NameTemplate::parse("{index").is_err());
+        assert!(NameTemplate::parse("index}").is_err());
+        assert!(NameTemplate::parse("{}.jpg").is_err());
+        assert!(NameTemplate::parse("{bad token}").is_err());
+        assert!(NameTemplate::parse("{host}/{basename}").is_err());
+    }
+}
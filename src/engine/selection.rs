@@ -0,0 +1,71 @@
+use anyhow::{bail, Result};
+
+use crate::parser::MslCommand;
+
+/// Keywords a selection may name, as returned by [`MslCommand::keyword`].
+const KEYWORDS: &[&str] = &[
+    "open", "click", "set", "media", "save", "save page", "wait", "foreach", "repeat", "auth",
+    "extract", "expect", "include", "post",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Item {
+    /// Position among the top-level commands, counting from 1.
+    Index(usize),
+    Keyword(String),
+}
+
+/// A set of top-level commands given as a comma-separated list of positions
+/// and keywords, e.g. `2,3` or `open,media`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSelection {
+    items: Vec<Item>,
+}
+
+impl CommandSelection {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut items = Vec::new();
+        for item in spec.split(',').map(str::trim) {
+            if let Ok(index) = item.parse::<usize>() {
+                if index == 0 {
+                    bail!("command positions start at 1: {}", spec);
+                }
+                items.push(Item::Index(index));
+            } else if KEYWORDS.contains(&item) {
+                items.push(Item::Keyword(item.to_string()));
+            } else {
+                bail!("'{}' is neither a command position nor a command keyword", item);
+            }
+        }
+        Ok(Self { items })
+    }
+
+    /// Whether the command at `index` (from 0) is in the selection.
+    pub fn contains(&self, index: usize, command: &MslCommand) -> bool {
+        self.items.iter().any(|item| match item {
+            Item::Index(position) => *position == index + 1,
+            Item::Keyword(keyword) => keyword == command.keyword(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_contains() {
+        let open = MslCommand::Open { url: "https://example.com".to_string() };
+        let wait = MslCommand::Wait { seconds: 1 };
+
+        let selection = CommandSelection::parse("2, open").unwrap();
+        assert!(selection.contains(0, &open));
+        assert!(selection.contains(1, &wait));
+        assert!(!selection.contains(2, &wait));
+
+        assert!(CommandSelection::parse("save page").unwrap().contains(0, &MslCommand::SavePage { path: "a.html".to_string() }));
+        assert!(CommandSelection::parse("0").is_err());
+        assert!(CommandSelection::parse("medai").is_err());
+        assert!(CommandSelection::parse("1,,2").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWriteExt, BufReader};
+
+use crate::scraper::MediaItem;
+
+/// A download's content as it arrives from the network.
+pub type MediaBody<'a> = &'a mut (dyn AsyncRead + Send + Unpin);
+
+/// Where downloaded media is stored. The engine picks each download's path
+/// and hands the sink the content to store there; [`FsSink`], the default,
+/// writes to the local filesystem.
+pub trait MediaSink: Send + Sync {
+    /// Stores `body`, the content of `item`, at `path` and returns where it
+    /// ended up.
+    fn write<'a>(&'a self, item: &'a MediaItem, path: &'a Path, body: MediaBody<'a>) -> BoxFuture<'a, Result<PathBuf>>;
+
+    /// Moves something written earlier, for names that depend on content.
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<PathBuf>>;
+
+    /// Deletes something written earlier, such as a duplicate.
+    fn remove<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<()>>;
+
+    /// Whether something is already stored at `path`, for
+    /// [`ConflictPolicy`]. Sinks that can't tell report nothing there.
+    fn exists<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, bool> {
+        Box::pin(async { false })
+    }
+}
+
+/// What a download does when its path is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the existing file and don't download.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Save under the first free name with a counter added, e.g.
+    /// `photo-1.jpg`.
+    #[default]
+    Rename,
+}
+
+/// Writes media to files, creating directories as needed.
+#[derive(Debug, Clone, Copy)]
+pub struct FsSink {
+    /// Most bytes read from the network and written to disk at a time.
+    pub buffer_size: usize,
+    /// `fsync` each file before moving it into place, so a finished download
+    /// survives a crash or power loss. Slower, especially on slow disks.
+    pub sync: bool,
+}
+
+/// [`FsSink::buffer_size`] unless configured otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+impl Default for FsSink {
+    fn default() -> Self {
+        Self { buffer_size: DEFAULT_BUFFER_SIZE, sync: false }
+    }
+}
+
+impl MediaSink for FsSink {
+    fn write<'a>(&'a self, _item: &'a MediaItem, path: &'a Path, body: MediaBody<'a>) -> BoxFuture<'a, Result<PathBuf>> {
+        Box::pin(async move {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+                fs::create_dir_all(dir).await.context("Failed to create directory")?;
+            }
+
+            // Write under a temporary name and move the file into place once
+            // it's complete, so an interrupted download never looks finished
+            let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+            part_name.push(".part");
+            let part_path = path.with_file_name(part_name);
+            let mut file = fs::File::create(&part_path).await
+                .context("Failed to create file")?;
+
+            // Copy straight from the network stream so memory use stays flat
+            // however large the download is
+            let mut body = BufReader::with_capacity(self.buffer_size.max(1), body);
+            let copied = match tokio::io::copy_buf(&mut body, &mut file).await {
+                Ok(_) if self.sync => file.sync_all().await,
+                Ok(_) => file.flush().await,
+                Err(e) => Err(e),
+            };
+            drop(file);
+            if let Err(e) = copied {
+                let _ = fs::remove_file(&part_path).await;
+                return Err(e).context("Failed to write file");
+            }
+
+            fs::rename(&part_path, path).await
+                .context("Failed to move download into place")?;
+            Ok(path.to_path_buf())
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<PathBuf>> {
+        Box::pin(async move {
+            fs::rename(from, to).await.context("Failed to rename download")?;
+            Ok(to.to_path_buf())
+        })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { fs::remove_file(path).await.context("Failed to remove file") })
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, bool> {
+        Box::pin(async move { fs::try_exists(path).await.unwrap_or(false) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_small_buffer_writes_whole_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/large.bin");
+        let content: Vec<u8> = (0..1_000_003u32).map(|i| (i % 251) as u8).collect();
+        let item = MediaItem {
+            url: "https://example.com/large.bin".to_string(),
+            media_type: crate::scraper::MediaType::Video,
+            filename: None,
+            attributes: Default::default(),
+            content_length: None,
+            content_type: None,
+            frame: None,
+        };
+        let sink = FsSink { buffer_size: 7, sync: true };
+
+        let stored = sink.write(&item, &path, &mut content.as_slice()).await.unwrap();
+
+        assert_eq!(stored, path);
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert!(!dir.path().join("nested/large.bin.part").exists());
+    }
+}
@@ -0,0 +1,25 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Totals gathered while a script runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunStats {
+    /// Pages fetched by `open` and `click`.
+    pub pages: usize,
+    /// Media files downloaded, duplicates included.
+    pub media: usize,
+    /// Bytes written by media downloads.
+    pub bytes: u64,
+    /// Wall-clock time spent in [`MslEngine::execute`](super::MslEngine::execute).
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pages, {} media files, {} bytes in {:.2?}",
+            self.pages, self.media, self.bytes, self.elapsed
+        )
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+/// How many downloads may run at once. A fixed limit stays at the
+/// configured maximum; an adaptive one starts at one and adds one download
+/// for every full round of successes, halving whenever the server pushes
+/// back (additive increase, multiplicative decrease).
+#[derive(Debug, Clone)]
+pub(super) struct ConcurrencyLimit {
+    current: usize,
+    max: usize,
+    adaptive: bool,
+    /// Successes since the limit last changed.
+    successes: usize,
+}
+
+impl ConcurrencyLimit {
+    /// A limit of at most `max` downloads at once; zero counts as one.
+    pub(super) fn new(max: usize, adaptive: bool) -> Self {
+        let max = max.max(1);
+        Self { current: if adaptive { 1 } else { max }, max, adaptive, successes: 0 }
+    }
+
+    pub(super) fn current(&self) -> usize {
+        self.current
+    }
+
+    pub(super) fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    pub(super) fn succeeded(&mut self) {
+        if !self.adaptive {
+            return;
+        }
+        self.successes += 1;
+        if self.successes >= self.current && self.current < self.max {
+            self.current += 1;
+            self.successes = 0;
+        }
+    }
+
+    pub(super) fn throttled(&mut self) {
+        if !self.adaptive {
+            return;
+        }
+        self.current = (self.current / 2).max(1);
+        self.successes = 0;
+    }
+}
+
+/// A [`ConcurrencyLimit`] for each host, along with how many downloads
+/// from it are running, so that a slow or throttling host only holds back
+/// its own downloads, under an optional ceiling on downloads from all
+/// hosts together. URLs without a host share one limit.
+#[derive(Debug)]
+pub(super) struct HostLimits {
+    max: usize,
+    adaptive: bool,
+    hosts: HashMap<String, (ConcurrencyLimit, usize)>,
+    /// Most downloads running at once across all hosts.
+    total: usize,
+    running: usize,
+}
+
+impl HostLimits {
+    pub(super) fn new(max: usize, adaptive: bool, total: Option<usize>) -> Self {
+        Self { max, adaptive, hosts: HashMap::new(), total: total.map_or(usize::MAX, |total| total.max(1)), running: 0 }
+    }
+
+    /// Counts a download from `host` as running if both its limit and the
+    /// ceiling have room.
+    pub(super) fn try_start(&mut self, host: &str) -> bool {
+        if self.running >= self.total {
+            return false;
+        }
+        let (limit, running) = self.entry(host);
+        if *running >= limit.current() {
+            return false;
+        }
+        *running += 1;
+        self.running += 1;
+        true
+    }
+
+    /// Counts a download from `host` as no longer running, returning the
+    /// host's limit to record how it went.
+    pub(super) fn finished(&mut self, host: &str) -> &mut ConcurrencyLimit {
+        self.running = self.running.saturating_sub(1);
+        let (limit, running) = self.entry(host);
+        *running = running.saturating_sub(1);
+        limit
+    }
+
+    fn entry(&mut self, host: &str) -> &mut (ConcurrencyLimit, usize) {
+        let (max, adaptive) = (self.max, self.adaptive);
+        self.hosts.entry(host.to_string()).or_insert_with(|| (ConcurrencyLimit::new(max, adaptive), 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_additive_increase_multiplicative_decrease() {
+        let mut limit = ConcurrencyLimit::new(4, true);
+        assert_eq!(limit.current(), 1);
+        limit.succeeded();
+        assert_eq!(limit.current(), 2);
+        limit.succeeded();
+        limit.succeeded();
+        assert_eq!(limit.current(), 3);
+        (0..10).for_each(|_| limit.succeeded());
+        assert_eq!(limit.current(), 4);
+
+        limit.throttled();
+        assert_eq!(limit.current(), 2);
+        limit.throttled();
+        limit.throttled();
+        assert_eq!(limit.current(), 1);
+
+        let mut fixed = ConcurrencyLimit::new(3, false);
+        fixed.throttled();
+        assert_eq!(fixed.current(), 3);
+        assert_eq!(ConcurrencyLimit::new(0, false).current(), 1);
+    }
+
+    #[test]
+    fn test_hosts_are_limited_separately() {
+        let mut limits = HostLimits::new(2, true, None);
+        assert!(limits.try_start("slow.example"));
+        assert!(!limits.try_start("slow.example"));
+        assert!(limits.try_start("fast.example"));
+
+        limits.finished("fast.example").succeeded();
+        assert!(limits.try_start("fast.example"));
+        assert!(limits.try_start("fast.example"));
+        assert!(!limits.try_start("fast.example"));
+
+        limits.finished("slow.example").throttled();
+        assert!(limits.try_start("slow.example"));
+        assert!(!limits.try_start("slow.example"));
+        assert!(!limits.try_start("fast.example"));
+        limits.finished("fast.example");
+        assert!(limits.try_start("fast.example"));
+    }
+
+    #[test]
+    fn test_total_ceiling_applies_across_hosts() {
+        let mut limits = HostLimits::new(2, false, Some(3));
+        assert!(limits.try_start("a.example"));
+        assert!(limits.try_start("a.example"));
+        assert!(limits.try_start("b.example"));
+        assert!(!limits.try_start("b.example"));
+        assert!(!limits.try_start("c.example"));
+
+        limits.finished("a.example");
+        assert!(limits.try_start("c.example"));
+        assert!(!limits.try_start("a.example"));
+        limits.finished("b.example");
+        assert!(limits.try_start("a.example"));
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::{self, Display, Formatter};
+
+/// The value of a script variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Scalar(String),
+    /// Set by a `text all` or `attr("..") all` value.
+    List(Vec<String>),
+}
+
+impl Value {
+    /// An empty scalar or a list with no items.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::Scalar(value) => value.is_empty(),
+            Value::List(items) => items.is_empty(),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Scalar(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Scalar(value.to_string())
+    }
+}
+
+/// Scalars as they are; lists as their items joined with `, `.
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Scalar(value) => write!(f, "{}", value),
+            Value::List(items) => write!(f, "{}", items.join(", ")),
+        }
+    }
+}
+
+impl PartialEq<str> for Value {
+    fn eq(&self, other: &str) -> bool {
+        matches!(self, Value::Scalar(value) if value == other)
+    }
+}
+
+impl PartialEq<&str> for Value {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<String> for Value {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
@@ -1,11 +1,18 @@
+pub mod clock;
 pub mod parser;
 pub mod scraper;
 pub mod engine;
 pub mod cli;
 
-pub use engine::MslEngine;
+#[cfg(test)]
+mod testing;
+
+pub use clock::{Clock, TokioClock};
+#[cfg(any(test, feature = "test-util"))]
+pub use clock::MockClock;
+pub use engine::{Cancelled, DeadlineExceeded, EngineConfig, MediaSink, MslEngine};
 pub use parser::{parse_script, MslScript, MslError};
-pub use scraper::{Scraper, ScrapingResult};
+pub use scraper::{RequestHook, Scraper, ScrapingResult};
 
 use anyhow::Result;
 
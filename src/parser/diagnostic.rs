@@ -0,0 +1,98 @@
+use anstyle::{AnsiColor, Style};
+
+use super::MslError;
+
+const MESSAGE: Style = Style::new().bold();
+const CARET: Style = Style::new().bold().fg_color(Some(anstyle::Color::Ansi(AnsiColor::Red)));
+const GUTTER: Style = Style::new().bold().fg_color(Some(anstyle::Color::Ansi(AnsiColor::Blue)));
+
+/// Renders `error` for a terminal. Parse errors show the offending line of
+/// `source` with a caret under the column where parsing stopped, and
+/// invalid selectors one under the start of the selector, e.g.
+///
+/// ```text
+/// expected a command
+///  --> line 3, column 7
+///   |
+/// 3 | click each
+///   |       ^
+/// ```
+///
+/// Other errors render as their message. `color` adds ANSI styling.
+pub fn render_error(error: &MslError, source: &str, color: bool) -> String {
+    let paint = |style: Style, text: &str| {
+        if color {
+            format!("{}{}{}", style.render(), text, style.render_reset())
+        } else {
+            text.to_string()
+        }
+    };
+
+    let (MslError::ParseError { line, column, message, .. } | MslError::InvalidSelector { line, column, message }) = error
+    else {
+        return error.to_string();
+    };
+    let Some(source_line) = source.lines().nth(line - 1) else {
+        return error.to_string();
+    };
+
+    let number = line.to_string();
+    let margin = " ".repeat(number.len());
+    // Tabs keep their width by being copied into the caret line as is
+    let padding: String = source_line
+        .chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    format!(
+        "{}\n{}{} line {}, column {}\n{} {}\n{} {} {}\n{} {} {}{}",
+        paint(MESSAGE, message),
+        margin,
+        paint(GUTTER, "-->"),
+        line,
+        column,
+        margin,
+        paint(GUTTER, "|"),
+        paint(GUTTER, &number),
+        paint(GUTTER, "|"),
+        source_line,
+        margin,
+        paint(GUTTER, "|"),
+        padding,
+        paint(CARET, "^"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_script;
+
+    #[test]
+    fn test_render_parse_error() {
+        let source = "open \"https://example.com\"\n\nclick \".card\"\n    wait soon\n";
+        let error = parse_script(source).unwrap_err();
+        assert!(matches!(error, MslError::ParseError { line: 4, column: 10, .. }), "{:?}", error);
+
+        let rendered = render_error(&error, source, false);
+        assert_eq!(
+            rendered,
+            "expected a command\n --> line 4, column 10\n  |\n4 |     wait soon\n  |          ^"
+        );
+
+        let colored = render_error(&error, source, true);
+        assert!(colored.contains("\u{1b}["));
+        assert!(colored.contains("    wait soon"));
+    }
+
+    #[test]
+    fn test_render_invalid_selector() {
+        let source = "click \"a:hover\"\n";
+        let error = parse_script(source).unwrap_err();
+        assert_eq!(
+            render_error(&error, source, false),
+            "Invalid CSS selector \"a:hover\": :hover is not supported\n --> line 1, column 8\n  |\n1 | click \"a:hover\"\n  |        ^"
+        );
+    }
+}
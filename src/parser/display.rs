@@ -0,0 +1,349 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::{
+    is_identifier_char, Credentials, ExtractSource, ExtractTarget, ForeachSource, MediaBlock, MediaFilter, MediaSort, MediaType,
+    MslCommand, MslScript, MslValue, SortKey, Transform,
+};
+
+const INDENT: &str = "  ";
+
+/// Renders a script in canonical form: one command per line, two spaces per
+/// nesting level and a blank line around top-level blocks. Parsing the output
+/// yields the same AST.
+impl Display for MslScript {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut previous_was_block = false;
+        for (i, command) in self.commands.iter().enumerate() {
+            let is_block = command.has_body();
+            if i > 0 && (is_block || previous_was_block) {
+                writeln!(f)?;
+            }
+            write_command(f, command, 0)?;
+            previous_was_block = is_block;
+        }
+        Ok(())
+    }
+}
+
+impl Display for MslCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write_command(f, self, 0)
+    }
+}
+
+impl Display for MslValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MslValue::Text => write!(f, "text"),
+            MslValue::Html => write!(f, "html"),
+            MslValue::OuterHtml => write!(f, "outerhtml"),
+            MslValue::Url => write!(f, "url"),
+            MslValue::Title => write!(f, "title"),
+            MslValue::Attribute { name } => write!(f, "attr(\"{}\")", name),
+            MslValue::Within { selector, value } => match &**value {
+                MslValue::Attribute { name } => write!(f, "attr(\"{}\", \"{}\")", name, selector),
+                value => write!(f, "{}(\"{}\")", value, selector),
+            },
+            MslValue::Header { name } => write!(f, "header(\"{}\")", name),
+            MslValue::Json { path } => write!(f, "json(\"{}\")", path),
+            MslValue::Split { source, delimiter, index } => {
+                write!(f, "{}.split(\"{}\")[{}]", source, delimiter, index)
+            }
+            MslValue::Literal { value } => write!(f, "\"{}\"", value),
+            MslValue::Variable { name } => write!(f, "{}", name),
+            MslValue::Concat { parts } => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " + ")?;
+                    }
+                    write!(f, "{}", part)?;
+                }
+                Ok(())
+            }
+            MslValue::Fallback { value, fallback } => write!(f, "{} or {}", value, fallback),
+            MslValue::All { source } => write!(f, "{} all", source),
+            MslValue::Transform { source, transforms } => {
+                write!(f, "{}", source)?;
+                for transform in transforms {
+                    write!(f, " | {}", transform)?;
+                }
+                Ok(())
+            }
+            MslValue::Regex { source, pattern, group } => write!(f, "{} | regex(\"{}\", {})", source, pattern, group),
+        }
+    }
+}
+
+impl Display for Transform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::Trim => write!(f, "trim"),
+            Transform::Lower => write!(f, "lower"),
+            Transform::Upper => write!(f, "upper"),
+            Transform::Replace { from, to } => write!(f, "replace(\"{}\", \"{}\")", from, to),
+            Transform::Slug => write!(f, "slug"),
+        }
+    }
+}
+
+impl Display for ForeachSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ForeachSource::Links => write!(f, "links"),
+            ForeachSource::Variable { name } => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaType::Image => write!(f, "image"),
+            MediaType::Video => write!(f, "video"),
+            MediaType::Audio => write!(f, "audio"),
+        }
+    }
+}
+
+impl Display for MediaFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaFilter::Extensions { extensions } => {
+                write!(f, "extensions {}", extensions.join(", "))
+            }
+            condition => {
+                write!(f, "where ")?;
+                write_condition(f, condition)
+            }
+        }
+    }
+}
+
+impl Display for MediaSort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let key = match self.key {
+            SortKey::Size => "size",
+            SortKey::Url => "url",
+        };
+        let order = if self.descending { "desc" } else { "asc" };
+        write!(f, "sort by {} {}", key, order)
+    }
+}
+
+fn write_condition(f: &mut Formatter<'_>, filter: &MediaFilter) -> fmt::Result {
+    let (filters, keyword) = match filter {
+        MediaFilter::Where { field, operator, value } => {
+            return if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+                write!(f, "{} {} {}", field, operator, value)
+            } else {
+                write!(f, "{} {} \"{}\"", field, operator, value)
+            };
+        }
+        MediaFilter::Extensions { .. } => return write!(f, "{}", filter),
+        MediaFilter::And(filters) => (filters, " and "),
+        MediaFilter::Or(filters) => (filters, " or "),
+    };
+    
+    for (i, inner) in filters.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{}", keyword)?;
+        }
+        // `and` already binds tighter than `or`; every other nested group
+        // needs parentheses to parse back into the same tree
+        let bare = matches!(inner, MediaFilter::Where { .. })
+            || (matches!(filter, MediaFilter::Or(_)) && matches!(inner, MediaFilter::And(_)));
+        if bare {
+            write_condition(f, inner)?;
+        } else {
+            write!(f, "(")?;
+            write_condition(f, inner)?;
+            write!(f, ")")?;
+        }
+    }
+    Ok(())
+}
+
+impl MslCommand {
+    fn has_body(&self) -> bool {
+        matches!(
+            self,
+            MslCommand::Click { .. }
+                | MslCommand::Media { .. }
+                | MslCommand::Foreach { .. }
+                | MslCommand::Repeat { .. }
+        )
+    }
+}
+
+fn write_line(f: &mut Formatter<'_>, depth: usize, line: impl Display) -> fmt::Result {
+    writeln!(f, "{}{}", INDENT.repeat(depth), line)
+}
+
+fn write_command(f: &mut Formatter<'_>, command: &MslCommand, depth: usize) -> fmt::Result {
+    match command {
+        MslCommand::Open { url } => write_line(f, depth, format_args!("open \"{}\"", url)),
+        MslCommand::Click { selector, each, commands } => {
+            let each = if *each { "each " } else { "" };
+            write_line(f, depth, format_args!("click {}\"{}\"", each, selector))?;
+            for command in commands {
+                write_command(f, command, depth + 1)?;
+            }
+            Ok(())
+        }
+        MslCommand::Set { variable, value } => {
+            write_line(f, depth, format_args!("set {} = {}", variable, value))
+        }
+        MslCommand::Media { media_blocks } => {
+            write_line(f, depth, "media")?;
+            for block in media_blocks {
+                write_media_block(f, block, depth + 1)?;
+            }
+            Ok(())
+        }
+        MslCommand::Save { path } => write_line(f, depth, format_args!("save to \"{}\"", path)),
+        MslCommand::SavePage { path } => {
+            write_line(f, depth, format_args!("save page to \"{}\"", path))
+        }
+        MslCommand::Wait { seconds } => write_line(f, depth, format_args!("wait {}", seconds)),
+        MslCommand::WaitRandom { min, max } => {
+            write_line(f, depth, format_args!("wait random({}, {})", min, max))
+        }
+        MslCommand::Auth { credentials: Credentials::Basic { username, password } } => {
+            write_line(f, depth, format_args!("auth basic \"{}\" \"{}\"", username, password))
+        }
+        MslCommand::Auth { credentials: Credentials::Bearer { token } } => {
+            write_line(f, depth, format_args!("auth bearer \"{}\"", token))
+        }
+        MslCommand::Extract { source, path, target } => {
+            let source = match source {
+                ExtractSource::JsonLd => "jsonld",
+            };
+            let target = match target {
+                ExtractTarget::Variable { name } => name.as_str(),
+                ExtractTarget::Media => "media",
+            };
+            write_line(f, depth, format_args!("extract {} \"{}\" into {}", source, path, target))
+        }
+        MslCommand::Expect { selector, operator, count } => {
+            write_line(f, depth, format_args!("expect \"{}\" count {} {}", selector, operator, count))
+        }
+        MslCommand::Include { path } => write_line(f, depth, format_args!("include \"{}\"", path)),
+        MslCommand::Post { url, fields, json } => {
+            let json = if *json { "json " } else { "" };
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(name, value)| {
+                    if !name.is_empty() && name.chars().all(is_identifier_char) {
+                        format!("{}: \"{}\"", name, value)
+                    } else {
+                        format!("\"{}\": \"{}\"", name, value)
+                    }
+                })
+                .collect();
+            if fields.is_empty() {
+                write_line(f, depth, format_args!("post {}\"{}\"", json, url))
+            } else {
+                write_line(f, depth, format_args!("post {}\"{}\" {{ {} }}", json, url, fields.join(", ")))
+            }
+        }
+        MslCommand::Foreach { variable, source, commands } => {
+            write_line(f, depth, format_args!("foreach {} in {}", variable, source))?;
+            for command in commands {
+                write_command(f, command, depth + 1)?;
+            }
+            Ok(())
+        }
+        MslCommand::Repeat { times, variable, commands } => {
+            write_line(f, depth, format_args!("repeat {} as {}", times, variable))?;
+            for command in commands {
+                write_command(f, command, depth + 1)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_media_block(f: &mut Formatter<'_>, block: &MediaBlock, depth: usize) -> fmt::Result {
+    write_line(f, depth, &block.media_type)?;
+    for filter in &block.filters {
+        write_line(f, depth + 1, filter)?;
+    }
+    if let Some(sort) = &block.sort {
+        write_line(f, depth + 1, sort)?;
+    }
+    if let Some(limit) = block.limit {
+        write_line(f, depth + 1, format_args!("limit {}", limit))?;
+    }
+    if let Some(path) = &block.save_path {
+        write_line(f, depth + 1, format_args!("save to \"{}\"", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_script;
+
+    #[test]
+    fn test_format_is_idempotent() {
+        let script = r#"
+open   "https://example.com"
+wait 2
+wait   random( 1,3 )
+expect   ".gallery img" count>=3
+auth   bearer "{token}"
+post json  "https://example.com/api" {q:"{term}" ,"page[size]": "20",}
+extract jsonld "$.image"   into   media
+save page to "./pages/home.html"
+media
+    image
+        where src ~ "cdn.example.com"
+        extensions jpg,png
+        sort  by   size
+        limit   3
+save to "./media"
+set id = attr("href").split("/")[-1]
+set path = user+"/"+id
+set name = text   or "anonymous"
+set names = text    all
+set etag = header("etag")   or "none"
+set ids = json("$.items[*].id")   all
+set slug = title|trim|replace(" ","_")  or  "page" | upper
+foreach link in links
+    open "{link}"
+foreach  name   in names
+    wait 1
+click   each ".card a"
+    set name = text
+repeat   2   as  page
+    open "https://example.com/{page}"
+media
+  video
+    where (src ~ "a" or src ~ "b") and type = "video/mp4" or size > 100
+"#;
+        let formatted = parse_script(script).unwrap().to_string();
+        let reformatted = parse_script(&formatted).unwrap().to_string();
+
+        assert_eq!(formatted, reformatted);
+        assert!(formatted.contains("extract jsonld \"$.image\" into media\n"));
+        assert!(formatted.contains("wait random(1, 3)\n"));
+        assert!(formatted.contains("expect \".gallery img\" count >= 3\n"));
+        assert!(formatted.contains("auth bearer \"{token}\"\n"));
+        assert!(formatted.contains("post json \"https://example.com/api\" { q: \"{term}\", \"page[size]\": \"20\" }\n"));
+        assert!(formatted.contains("save page to \"./pages/home.html\"\n"));
+        assert!(formatted.contains("  image\n    where src ~ \"cdn.example.com\"\n"));
+        assert!(formatted.contains("    extensions jpg, png\n    sort by size asc\n    limit 3\n"));
+        assert!(formatted.contains("set id = attr(\"href\").split(\"/\")[-1]\n"));
+        assert!(formatted.contains("set path = user + \"/\" + id\n"));
+        assert!(formatted.contains("set name = text or \"anonymous\"\n"));
+        assert!(formatted.contains("set names = text all\n"));
+        assert!(formatted.contains("set etag = header(\"etag\") or \"none\"\n"));
+        assert!(formatted.contains("set ids = json(\"$.items[*].id\") all\n"));
+        assert!(formatted.contains("set slug = title | trim | replace(\" \", \"_\") or \"page\" | upper\n"));
+        assert!(formatted.contains("\nforeach link in links\n  open \"{link}\"\n\n"));
+        assert!(formatted.contains("\nforeach name in names\n  wait 1\n\n"));
+        assert!(formatted.contains("\nclick each \".card a\"\n  set name = text\n"));
+        assert!(formatted.contains("\nrepeat 2 as page\n  open \"https://example.com/{page}\"\n\n"));
+        assert!(formatted.contains("where (src ~ \"a\" or src ~ \"b\") and type = \"video/mp4\" or size > 100\n"));
+    }
+}
@@ -1,24 +1,34 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while},
-    character::complete::{char, multispace0, multispace1, none_of},
-    combinator::{map, opt, value},
-    multi::{many0, many1, separated_list0},
-    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    bytes::complete::{tag, take_until, take_while, take_while1},
+    character::complete::{char, digit1, multispace0, multispace1, satisfy},
+    combinator::{all_consuming, map, map_res, not, opt, value},
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
     IResult,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+mod diagnostic;
+mod display;
+
+pub use diagnostic::render_error;
+
 #[derive(Debug, Error)]
 pub enum MslError {
-    #[error("Parse error: {0}")]
-    ParseError(String),
-    #[error("Invalid selector: {0}")]
-    InvalidSelector(String),
+    /// `column` counts characters from 1 on the source line; `text` is the
+    /// line without its indentation.
+    #[error("Parse error: line {line}: {message}: {text}")]
+    ParseError { line: usize, column: usize, message: String, text: String },
+    /// A selector that can't be compiled; `column` is where it starts.
+    #[error("line {line}: {message}")]
+    InvalidSelector { line: usize, column: usize, message: String },
     #[error("Invalid URL: {0}")]
     InvalidUrl(String),
+    #[error("Include error: {0}")]
+    IncludeError(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,18 +39,160 @@ pub struct MslScript {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MslCommand {
     Open { url: String },
-    Click { selector: String, commands: Vec<MslCommand> },
+    /// Follows the first link matching `selector`, or with `each` every
+    /// one in turn, and runs `commands` on the page it leads to.
+    Click { selector: String, each: bool, commands: Vec<MslCommand> },
     Set { variable: String, value: MslValue },
     Media { media_blocks: Vec<MediaBlock> },
     Save { path: String },
+    /// Writes the current page's HTML to `path`.
+    SavePage { path: String },
     Wait { seconds: u64 },
+    /// `wait random(2, 5)`: waits a random time between `min` and `max`
+    /// seconds.
+    WaitRandom { min: u64, max: u64 },
+    /// Runs `commands` once per item of `source`, binding it to `variable`.
+    Foreach { variable: String, source: ForeachSource, commands: Vec<MslCommand> },
+    /// Runs `commands` `times` times, binding the iteration number, counting
+    /// from 1, to `variable`.
+    Repeat { times: u64, variable: String, commands: Vec<MslCommand> },
+    /// Sends these credentials with every later request.
+    Auth { credentials: Credentials },
+    /// Pulls the values at a JSON path out of structured data on the page.
+    Extract { source: ExtractSource, path: String, target: ExtractTarget },
+    /// Fails the run unless the number of elements matching `selector`
+    /// compares to `count` as `operator` says.
+    Expect { selector: String, operator: String, count: usize },
+    /// An `include "file.msl"` line, kept only when parsing with
+    /// [`ParseOptions::resolve_includes`] turned off.
+    Include { path: String },
+    /// Sends `fields` to `url` in a POST, form-encoded or with `json` as a
+    /// JSON object, and makes the response the current page. `{name}`
+    /// placeholders in the URL and values are filled from variables.
+    Post { url: String, fields: Vec<(String, String)>, json: bool },
+}
+
+/// Structured data an `extract` command reads.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtractSource {
+    /// `<script type="application/ld+json">` blocks.
+    JsonLd,
+}
+
+/// Where an `extract` command puts what it finds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtractTarget {
+    /// The first value found is stored in this variable.
+    Variable { name: String },
+    /// Every URL found is downloaded, like the results of a `media` command.
+    Media,
+}
+
+/// Credentials for the `Authorization` header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+impl MslCommand {
+    /// The keyword that starts the command in a script.
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            MslCommand::Open { .. } => "open",
+            MslCommand::Click { .. } => "click",
+            MslCommand::Set { .. } => "set",
+            MslCommand::Media { .. } => "media",
+            MslCommand::Save { .. } => "save",
+            MslCommand::SavePage { .. } => "save page",
+            MslCommand::Wait { .. } | MslCommand::WaitRandom { .. } => "wait",
+            MslCommand::Foreach { .. } => "foreach",
+            MslCommand::Repeat { .. } => "repeat",
+            MslCommand::Auth { .. } => "auth",
+            MslCommand::Extract { .. } => "extract",
+            MslCommand::Expect { .. } => "expect",
+            MslCommand::Include { .. } => "include",
+            MslCommand::Post { .. } => "post",
+        }
+    }
+
+    /// The selector the command matches elements with, if it has one.
+    pub fn selector(&self) -> Option<&str> {
+        match self {
+            MslCommand::Click { selector, .. } | MslCommand::Expect { selector, .. } => Some(selector),
+            _ => None,
+        }
+    }
+}
+
+/// What a `foreach` iterates over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ForeachSource {
+    /// Absolute URLs of every hyperlink on the current page.
+    Links,
+    /// The items of a list variable, e.g. one set by `attr("src") all`.
+    Variable { name: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MslValue {
     Text,
+    /// Markup of the children of the element the enclosing `click`
+    /// followed.
+    Html,
+    /// Markup of the element the enclosing `click` followed, its own tags
+    /// included; written `outerhtml`.
+    OuterHtml,
+    /// URL of the current page.
+    Url,
+    /// Title of the current page.
+    Title,
+    /// An attribute of the element the enclosing `click` followed. Also
+    /// written `data("id")` for `attr("data-id")`.
     Attribute { name: String },
-    Split { delimiter: String, index: i32 },
+    /// `text(".name")`, `html(".body")` or `attr("src", "img")`: the text,
+    /// markup or attribute of the first element matching `selector` inside
+    /// the element of the enclosing `click` or `foreach`, or anywhere on
+    /// the current page outside one.
+    Within { selector: String, value: Box<MslValue> },
+    /// A header of the response the current page came from, e.g.
+    /// `header("etag")`. Names match case-insensitively.
+    Header { name: String },
+    /// The value at a JSON path in the current page when it's a JSON
+    /// response, e.g. `json("$.data.id")`.
+    Json { path: String },
+    Split { source: Box<MslValue>, delimiter: String, index: i32 },
+    /// A quoted string; `{name}` placeholders are filled from variables
+    /// and `${NAME}` placeholders from the environment.
+    Literal { value: String },
+    /// The value of a previously set variable.
+    Variable { name: String },
+    /// The parts joined end to end, written `a + "/" + b`.
+    Concat { parts: Vec<MslValue> },
+    /// `value or fallback`: the fallback is used when the value is empty.
+    Fallback { value: Box<MslValue>, fallback: Box<MslValue> },
+    /// `source | trim | lower`: the transforms applied in order.
+    Transform { source: Box<MslValue>, transforms: Vec<Transform> },
+    /// `source | regex("/user/(\d+)/", 1)`: the text of a capture group in
+    /// the first match of `pattern`, or nothing when it doesn't match.
+    /// Group 0 is the whole match.
+    Regex { source: Box<MslValue>, pattern: String, group: usize },
+    /// `text all` or `attr("src") all`: a list of the value for every
+    /// element the enclosing block matched, rather than just the first.
+    /// `json("$.path") all` lists every value the path selects.
+    All { source: Box<MslValue> },
+}
+
+/// A step in a `set` value's `|` pipeline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transform {
+    Trim,
+    Lower,
+    Upper,
+    Replace { from: String, to: String },
+    /// Lowercase with runs of anything but letters and digits turned into a
+    /// single `-`.
+    Slug,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,6 +200,24 @@ pub struct MediaBlock {
     pub media_type: MediaType,
     pub filters: Vec<MediaFilter>,
     pub save_path: Option<String>,
+    /// Order to download the selected items in; document order when unset.
+    pub sort: Option<MediaSort>,
+    /// Most items to download, taken after sorting.
+    pub limit: Option<usize>,
+}
+
+/// A `sort by` line in a media block, e.g. `sort by size desc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaSort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortKey {
+    /// File size as reported by a HEAD request.
+    Size,
+    Url,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,31 +231,330 @@ pub enum MediaType {
 pub enum MediaFilter {
     Where { field: String, operator: String, value: String },
     Extensions { extensions: Vec<String> },
+    /// Matches when every inner filter matches.
+    And(Vec<MediaFilter>),
+    /// Matches when any inner filter matches.
+    Or(Vec<MediaFilter>),
 }
 
+/// Options controlling how the layout of a script is interpreted.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Column width a tab advances indentation to when measuring nesting.
+    pub tab_width: usize,
+    /// File the script was read from. Includes resolve relative to its
+    /// directory instead of the working directory.
+    pub source_path: Option<PathBuf>,
+    /// Replace top-level `include` lines with the commands of the files
+    /// they name. When off they are kept as [`MslCommand::Include`].
+    pub resolve_includes: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            source_path: None,
+            resolve_includes: true,
+        }
+    }
+}
+
+/// Parses a script. Blank lines and `#` comments are skipped, so a script
+/// with nothing else, including an empty one, has no commands.
 pub fn parse_script(input: &str) -> Result<MslScript, MslError> {
-    let (remaining, commands) = many0(parse_command)(input)
-        .map_err(|e| MslError::ParseError(format!("Failed to parse script: {}", e)))?;
-    
-    if !remaining.trim().is_empty() {
-        return Err(MslError::ParseError(format!("Unparsed content: {}", remaining)));
+    parse_script_with_options(input, &ParseOptions::default())
+}
+
+pub fn parse_script_with_options(input: &str, options: &ParseOptions) -> Result<MslScript, MslError> {
+    let mut include_stack = Vec::new();
+    if let Some(path) = &options.source_path {
+        include_stack.push(path.canonicalize().unwrap_or_else(|_| path.clone()));
     }
+    let commands = parse_top_level(input, options, &mut include_stack)?;
     
     Ok(MslScript { commands })
 }
 
+/// Parses a script's commands, inlining the files named by top-level
+/// `include` lines. `include_stack` holds the files currently being parsed,
+/// innermost last.
+fn parse_top_level(
+    input: &str,
+    options: &ParseOptions,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<Vec<MslCommand>, MslError> {
+    // Editors on Windows may start a UTF-8 file with a byte order mark
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let lines = scan_lines(input, options.tab_width);
+    let mut position = 0;
+    let blocks = parse_blocks(&lines, &mut position, None)?;
+    
+    let mut commands = Vec::new();
+    for block in &blocks {
+        let Ok((_, path)) = all_consuming(parse_include)(block.line.text) else {
+            commands.extend(parse_commands(std::slice::from_ref(block))?);
+            continue;
+        };
+        ensure_no_children(block)?;
+        if options.resolve_includes {
+            commands.extend(include_script(&block.line, &path, options, include_stack)?);
+        } else {
+            commands.push(MslCommand::Include { path });
+        }
+    }
+    
+    Ok(commands)
+}
+
+fn include_script(
+    line: &Line,
+    path: &str,
+    options: &ParseOptions,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<Vec<MslCommand>, MslError> {
+    let base_dir = include_stack.last().and_then(|file| file.parent()).unwrap_or(Path::new(""));
+    let resolved = base_dir.join(path);
+    let file = resolved.canonicalize().map_err(|e| {
+        MslError::IncludeError(format!("line {}: cannot read {}: {}", line.number, resolved.display(), e))
+    })?;
+    
+    if include_stack.contains(&file) {
+        let cycle: Vec<String> = include_stack
+            .iter()
+            .skip_while(|included| **included != file)
+            .chain(std::iter::once(&file))
+            .map(|included| included.display().to_string())
+            .collect();
+        return Err(MslError::IncludeError(format!(
+            "line {}: circular include: {}",
+            line.number,
+            cycle.join(" -> ")
+        )));
+    }
+    
+    let content = std::fs::read_to_string(&file).map_err(|e| {
+        MslError::IncludeError(format!("line {}: cannot read {}: {}", line.number, file.display(), e))
+    })?;
+    
+    include_stack.push(file);
+    let result = parse_top_level(&content, options, include_stack);
+    let file = include_stack.pop().unwrap_or_default();
+    
+    result.map_err(|e| match e {
+        MslError::IncludeError(_) => e,
+        e => MslError::IncludeError(format!("{}: {}", file.display(), e)),
+    })
+}
+
+/// A non-blank, non-comment source line and its indentation width.
+#[derive(Debug, Clone, Copy)]
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    /// Column the text starts at, counting characters from 1.
+    column: usize,
+    text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    fn error(&self, message: &str) -> MslError {
+        self.error_at(message, self.text)
+    }
+
+    /// An error pointing at the start of `rest`, the part of the line a
+    /// parser failed to get past.
+    fn error_at(&self, message: &str, rest: &'a str) -> MslError {
+        let consumed = if self.text.ends_with(rest) { self.text.len() - rest.len() } else { 0 };
+        MslError::ParseError {
+            line: self.number,
+            column: self.column + self.text[..consumed].chars().count(),
+            message: message.to_string(),
+            text: self.text.to_string(),
+        }
+    }
+}
+
+/// A line together with the more deeply indented lines nested under it.
+struct Block<'a> {
+    line: Line<'a>,
+    children: Vec<Block<'a>>,
+}
+
+fn scan_lines(input: &str, tab_width: usize) -> Vec<Line<'_>> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(i, raw)| {
+            let text = raw.trim();
+            if text.is_empty() || text.starts_with('#') {
+                return None;
+            }
+            let leading = &raw[..raw.len() - raw.trim_start().len()];
+            Some(Line {
+                number: i + 1,
+                indent: indent_width(raw, tab_width),
+                column: leading.chars().count() + 1,
+                text,
+            })
+        })
+        .collect()
+}
+
+fn indent_width(line: &str, tab_width: usize) -> usize {
+    let tab_width = tab_width.max(1);
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .fold(0, |width, c| match c {
+            '\t' => (width / tab_width + 1) * tab_width,
+            _ => width + 1,
+        })
+}
+
+/// Groups lines into blocks. Every line of a block shares the indentation of
+/// its first line; anything indented further belongs to the preceding line.
+fn parse_blocks<'a>(
+    lines: &[Line<'a>],
+    position: &mut usize,
+    parent_indent: Option<usize>,
+) -> Result<Vec<Block<'a>>, MslError> {
+    let mut blocks = Vec::new();
+    let Some(indent) = lines.get(*position).map(|line| line.indent) else {
+        return Ok(blocks);
+    };
+    
+    while let Some(&line) = lines.get(*position) {
+        if parent_indent.is_some_and(|parent| line.indent <= parent) {
+            break;
+        }
+        if line.indent != indent {
+            return Err(line.error("indentation does not match any enclosing block"));
+        }
+        
+        *position += 1;
+        let children = match lines.get(*position) {
+            Some(next) if next.indent > line.indent => {
+                parse_blocks(lines, position, Some(line.indent))?
+            }
+            _ => Vec::new(),
+        };
+        blocks.push(Block { line, children });
+    }
+    
+    Ok(blocks)
+}
+
+/// Runs a line parser, requiring it to consume the whole line.
+fn parse_line<'a, T>(
+    line: &Line<'a>,
+    parser: impl FnMut(&'a str) -> IResult<&'a str, T>,
+    expected: &str,
+) -> Result<T, MslError> {
+    all_consuming(parser)(line.text)
+        .map(|(_, parsed)| parsed)
+        .map_err(|e| {
+            let message = format!("expected {}", expected);
+            match e {
+                nom::Err::Error(e) | nom::Err::Failure(e) => line.error_at(&message, e.input),
+                nom::Err::Incomplete(_) => line.error(&message),
+            }
+        })
+}
+
+fn ensure_no_children(block: &Block) -> Result<(), MslError> {
+    match block.children.first() {
+        Some(child) => Err(child.line.error("unexpected indented block")),
+        None => Ok(()),
+    }
+}
+
+fn parse_commands(blocks: &[Block]) -> Result<Vec<MslCommand>, MslError> {
+    let mut commands = Vec::new();
+    
+    for block in blocks {
+        let command = parse_line(&block.line, parse_command, "a command")?;
+        if let Some(selector) = command.selector() {
+            check_selector(&block.line, selector)?;
+        }
+        match command {
+            MslCommand::Click { selector, each, .. } => {
+                commands.push(MslCommand::Click {
+                    selector,
+                    each,
+                    commands: parse_commands(&block.children)?,
+                });
+            }
+            MslCommand::Foreach { variable, source, .. } => {
+                commands.push(MslCommand::Foreach {
+                    variable,
+                    source,
+                    commands: parse_commands(&block.children)?,
+                });
+            }
+            MslCommand::Repeat { times, variable, .. } => {
+                commands.push(MslCommand::Repeat {
+                    times,
+                    variable,
+                    commands: parse_commands(&block.children)?,
+                });
+            }
+            MslCommand::Media { .. } => {
+                let media_blocks = parse_media_blocks(&block.children)?;
+                commands.push(MslCommand::Media { media_blocks });
+            }
+            command => {
+                ensure_no_children(block)?;
+                commands.push(command);
+            }
+        }
+    }
+    
+    Ok(commands)
+}
+
+/// Compiles `selector` so a bad one is reported before the script runs.
+fn check_selector(line: &Line, selector: &str) -> Result<(), MslError> {
+    match crate::scraper::ElementSelector::parse(selector) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let consumed = line.text.find(selector).unwrap_or(0);
+            Err(MslError::InvalidSelector {
+                line: line.number,
+                column: line.column + line.text[..consumed].chars().count(),
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
 fn parse_command(input: &str) -> IResult<&str, MslCommand> {
-    let (input, _) = multispace0(input)?;
     alt((
         parse_open,
         parse_click,
         parse_set,
         parse_media,
         parse_save,
+        parse_save_page,
         parse_wait,
+        parse_foreach,
+        parse_repeat,
+        parse_auth,
+        parse_extract,
+        parse_expect,
+        parse_post,
     ))(input)
 }
 
+/// Parses `include "file.msl"`, also spelled `import`.
+fn parse_include(input: &str) -> IResult<&str, String> {
+    let (input, _) = alt((parse_word("include"), parse_word("import")))(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, path) = parse_quoted(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, path.to_string()))
+}
+
 fn parse_open(input: &str) -> IResult<&str, MslCommand> {
     let (input, _) = tag("open")(input)?;
     let (input, _) = multispace1(input)?;
@@ -95,26 +564,164 @@ fn parse_open(input: &str) -> IResult<&str, MslCommand> {
     Ok((input, MslCommand::Open { url: url.to_string() }))
 }
 
+/// Parses the `click "selector"` or `click each "selector"` line; the
+/// nested commands come from the indented block beneath it.
 fn parse_click(input: &str) -> IResult<&str, MslCommand> {
     let (input, _) = tag("click")(input)?;
     let (input, _) = multispace1(input)?;
+    let (input, each) = opt(terminated(parse_word("each"), multispace1))(input)?;
     let (input, selector) = delimited(char('"'), take_until("\""), char('"'))(input)?;
     let (input, _) = multispace0(input)?;
-    let (input, _) = opt(char('\n'))(input)?;
-    
-    // Parse indented commands
-    let (input, commands) = many0(parse_indented_command)(input)?;
     
     Ok((input, MslCommand::Click { 
         selector: selector.to_string(), 
-        commands 
+        each: each.is_some(),
+        commands: Vec::new(),
     }))
 }
 
-fn parse_indented_command(input: &str) -> IResult<&str, MslCommand> {
+/// Parses the `foreach link in links` line; the loop body comes from the
+/// indented block beneath it.
+fn parse_foreach(input: &str) -> IResult<&str, MslCommand> {
+    let (input, _) = parse_word("foreach")(input)?;
     let (input, _) = multispace1(input)?;
-    let (input, _) = multispace1(input)?; // Double indent
-    parse_command(input)
+    let (input, variable) = parse_identifier(input)?;
+    let (input, _) = parse_keyword("in")(input)?;
+    let (input, source) = alt((
+        value(ForeachSource::Links, parse_word("links")),
+        map(parse_identifier, |name: &str| ForeachSource::Variable { name: name.to_string() }),
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslCommand::Foreach {
+        variable: variable.to_string(),
+        source,
+        commands: Vec::new(),
+    }))
+}
+
+/// Parses the `repeat 5 as i` line; the loop body comes from the indented
+/// block beneath it.
+fn parse_repeat(input: &str) -> IResult<&str, MslCommand> {
+    let (input, _) = parse_word("repeat")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, times) = nom::combinator::map_res(digit1, str::parse)(input)?;
+    let (input, _) = parse_keyword("as")(input)?;
+    let (input, variable) = parse_identifier(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslCommand::Repeat {
+        times,
+        variable: variable.to_string(),
+        commands: Vec::new(),
+    }))
+}
+
+/// Parses `auth basic "user" "password"` or `auth bearer "token"`.
+fn parse_auth(input: &str) -> IResult<&str, MslCommand> {
+    let (input, _) = parse_word("auth")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, credentials) = alt((
+        |input| {
+            let (input, _) = parse_word("basic")(input)?;
+            let (input, username) = preceded(multispace1, parse_quoted)(input)?;
+            let (input, password) = preceded(multispace1, parse_quoted)(input)?;
+            Ok((input, Credentials::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            }))
+        },
+        |input| {
+            let (input, _) = parse_word("bearer")(input)?;
+            let (input, token) = preceded(multispace1, parse_quoted)(input)?;
+            Ok((input, Credentials::Bearer { token: token.to_string() }))
+        },
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslCommand::Auth { credentials }))
+}
+
+/// Parses `extract jsonld "$.path" into name`, where `into media`
+/// downloads the values instead of storing them.
+fn parse_extract(input: &str) -> IResult<&str, MslCommand> {
+    let (input, _) = parse_word("extract")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, source) = value(ExtractSource::JsonLd, parse_word("jsonld"))(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, path) = parse_quoted(input)?;
+    let (input, _) = parse_keyword("into")(input)?;
+    let (input, target) = alt((
+        value(ExtractTarget::Media, parse_word("media")),
+        |input| {
+            let (input, name) = parse_identifier(input)?;
+            Ok((input, ExtractTarget::Variable { name: name.to_string() }))
+        },
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslCommand::Extract {
+        source,
+        path: path.to_string(),
+        target,
+    }))
+}
+
+/// Parses `expect "selector" count >= 1`.
+fn parse_expect(input: &str) -> IResult<&str, MslCommand> {
+    let (input, _) = parse_word("expect")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, selector) = parse_quoted(input)?;
+    let (input, _) = delimited(multispace1, tag("count"), multispace0)(input)?;
+    let (input, operator) = alt((
+        tag("="),
+        tag("!="),
+        tag(">="),
+        tag("<="),
+        tag(">"),
+        tag("<"),
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, count) = nom::combinator::map_res(digit1, str::parse)(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslCommand::Expect {
+        selector: selector.to_string(),
+        operator: operator.to_string(),
+        count,
+    }))
+}
+
+/// Parses `post "url" { name: "value", ... }`, or `post json "url" {..}` to
+/// send the fields as JSON. Field names that aren't identifiers are quoted.
+fn parse_post(input: &str) -> IResult<&str, MslCommand> {
+    let (input, _) = parse_word("post")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, json) = opt(terminated(parse_word("json"), multispace1))(input)?;
+    let (input, url) = parse_quoted(input)?;
+    let (input, _) = multispace0(input)?;
+    let separator = || delimited(multispace0, char(','), multispace0);
+    let field = separated_pair(
+        alt((parse_quoted, parse_identifier)),
+        delimited(multispace0, char(':'), multispace0),
+        parse_quoted,
+    );
+    let (input, fields) = opt(delimited(
+        pair(char('{'), multispace0),
+        terminated(separated_list0(separator(), field), opt(separator())),
+        pair(multispace0, char('}')),
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslCommand::Post {
+        url: url.to_string(),
+        fields: fields
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        json: json.is_some(),
+    }))
 }
 
 fn parse_set(input: &str) -> IResult<&str, MslCommand> {
@@ -124,7 +731,7 @@ fn parse_set(input: &str) -> IResult<&str, MslCommand> {
     let (input, _) = multispace0(input)?;
     let (input, _) = char('=')(input)?;
     let (input, _) = multispace0(input)?;
-    let (input, value) = parse_value(input)?;
+    let (input, value) = alt((parse_list_value, parse_fallback_value))(input)?;
     
     Ok((input, MslCommand::Set { 
         variable: variable.to_string(), 
@@ -132,83 +739,344 @@ fn parse_set(input: &str) -> IResult<&str, MslCommand> {
     }))
 }
 
-fn parse_value(input: &str) -> IResult<&str, MslValue> {
+/// Parses `text all`, `attr("name") all`, `data("name") all` or
+/// `json("$.path") all`.
+fn parse_list_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, source) = alt((parse_text_value, parse_attribute_value, parse_data_value, parse_json_value))(input)?;
+    let (input, _) = preceded(multispace1, parse_word("all"))(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, MslValue::All { source: Box::new(source) }))
+}
+
+/// Parses a value with any `or` fallbacks; `a or b or c` tries each value
+/// in turn.
+fn parse_fallback_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, value) = parse_piped_value(input)?;
+    let (input, fallbacks) = many0(preceded(parse_keyword("or"), parse_piped_value))(input)?;
+    
+    let value = match fallbacks.into_iter().rev().reduce(|fallback, value| MslValue::Fallback {
+        value: Box::new(value),
+        fallback: Box::new(fallback),
+    }) {
+        Some(fallback) => MslValue::Fallback {
+            value: Box::new(value),
+            fallback: Box::new(fallback),
+        },
+        None => value,
+    };
+    
+    Ok((input, value))
+}
+
+/// A step in a `|` pipeline.
+enum PipeStep {
+    Transform(Transform),
+    Regex { pattern: String, group: usize },
+}
+
+/// Parses a value followed by any `| transform` or `| regex(..)` steps.
+fn parse_piped_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, source) = parse_value(input)?;
+    let (input, steps) = many0(preceded(
+        delimited(multispace0, char('|'), multispace0),
+        alt((map(parse_transform, PipeStep::Transform), parse_regex_step)),
+    ))(input)?;
+    
+    let mut value = source;
+    let mut transforms = Vec::new();
+    let flush = |value: MslValue, transforms: &mut Vec<Transform>| {
+        if transforms.is_empty() {
+            return value;
+        }
+        MslValue::Transform { source: Box::new(value), transforms: std::mem::take(transforms) }
+    };
+    for step in steps {
+        match step {
+            PipeStep::Transform(transform) => transforms.push(transform),
+            PipeStep::Regex { pattern, group } => {
+                value = MslValue::Regex { source: Box::new(flush(value, &mut transforms)), pattern, group };
+            }
+        }
+    }
+    Ok((input, flush(value, &mut transforms)))
+}
+
+/// Parses `regex("pattern", group)`, rejecting patterns that don't compile
+/// and groups they don't have.
+fn parse_regex_step(input: &str) -> IResult<&str, PipeStep> {
+    let (input, _) = tag("regex(")(input)?;
+    let (input, (pattern, group)) = nom::combinator::verify(
+        separated_pair(
+            delimited(multispace0, parse_quoted, multispace0),
+            char(','),
+            delimited(multispace0, digit1, multispace0),
+        ),
+        |(pattern, group): &(&str, &str)| {
+            let group = group.parse::<usize>().unwrap_or(usize::MAX);
+            regex::Regex::new(pattern).is_ok_and(|regex| group < regex.captures_len())
+        },
+    )(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, PipeStep::Regex { pattern: pattern.to_string(), group: group.parse().unwrap_or_default() }))
+}
+
+fn parse_transform(input: &str) -> IResult<&str, Transform> {
     alt((
+        value(Transform::Trim, parse_word("trim")),
+        value(Transform::Lower, parse_word("lower")),
+        value(Transform::Upper, parse_word("upper")),
+        value(Transform::Slug, parse_word("slug")),
+        |input| {
+            let (input, _) = tag("replace(")(input)?;
+            let (input, from) = delimited(multispace0, parse_quoted, multispace0)(input)?;
+            let (input, _) = char(',')(input)?;
+            let (input, to) = delimited(multispace0, parse_quoted, multispace0)(input)?;
+            let (input, _) = char(')')(input)?;
+            Ok((input, Transform::Replace { from: from.to_string(), to: to.to_string() }))
+        },
+    ))(input)
+}
+
+/// Parses a value, joining `+`-separated parts into a concatenation.
+fn parse_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, first) = parse_value_term(input)?;
+    let (input, rest) = many0(preceded(
+        delimited(multispace0, char('+'), multispace0),
+        parse_value_term,
+    ))(input)?;
+    
+    if rest.is_empty() {
+        return Ok((input, first));
+    }
+    let mut parts = vec![first];
+    parts.extend(rest);
+    Ok((input, MslValue::Concat { parts }))
+}
+
+fn parse_value_term(input: &str) -> IResult<&str, MslValue> {
+    let (input, source) = alt((
+        parse_literal_value,
         parse_text_value,
+        value(MslValue::Url, parse_word("url")),
+        value(MslValue::Title, parse_word("title")),
         parse_attribute_value,
-        parse_split_value,
-    ))(input)
+        parse_data_value,
+        parse_header_value,
+        parse_json_value,
+        parse_variable_value,
+    ))(input)?;
+    let (input, splits) = many0(parse_split_value)(input)?;
+
+    let value = splits.into_iter().fold(source, |source, (delimiter, index)| {
+        MslValue::Split {
+            source: Box::new(source),
+            delimiter,
+            index,
+        }
+    });
+
+    Ok((input, value))
+}
+
+fn parse_quoted(input: &str) -> IResult<&str, &str> {
+    delimited(char('"'), take_until("\""), char('"'))(input)
 }
 
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn parse_identifier(input: &str) -> IResult<&str, &str> {
+    take_while1(is_identifier_char)(input)
+}
+
+/// Matches `word` only when it isn't the start of a longer identifier.
+fn parse_word<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag(word), not(satisfy(is_identifier_char)))
+}
+
+fn parse_literal_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, value) = parse_quoted(input)?;
+    Ok((input, MslValue::Literal { value: value.to_string() }))
+}
+
+/// Parses `text`, `html` or `outerhtml`, optionally with a selector, as in
+/// `text(".name")`.
 fn parse_text_value(input: &str) -> IResult<&str, MslValue> {
-    let (input, _) = tag("text")(input)?;
-    Ok((input, MslValue::Text))
+    let (input, value) = alt((
+        value(MslValue::Text, parse_word("text")),
+        value(MslValue::Html, parse_word("html")),
+        value(MslValue::OuterHtml, parse_word("outerhtml")),
+    ))(input)?;
+    let (input, selector) = opt(delimited(char('('), parse_selector_argument, char(')')))(input)?;
+    Ok((input, within(selector, value)))
+}
+
+/// A quoted selector argument, rejected when it doesn't compile.
+fn parse_selector_argument(input: &str) -> IResult<&str, &str> {
+    nom::combinator::verify(parse_quoted, |selector: &str| crate::scraper::ElementSelector::parse(selector).is_ok())(input)
+}
+
+/// `value` read from the first element matching `selector`, when given.
+fn within(selector: Option<&str>, value: MslValue) -> MslValue {
+    match selector {
+        Some(selector) => MslValue::Within { selector: selector.to_string(), value: Box::new(value) },
+        None => value,
+    }
+}
+
+fn parse_variable_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, name) = parse_identifier(input)?;
+    Ok((input, MslValue::Variable { name: name.to_string() }))
 }
 
 fn parse_attribute_value(input: &str) -> IResult<&str, MslValue> {
     let (input, _) = tag("attr")(input)?;
-    let (input, _) = delimited(char('('), char('"'), char(')'))(input)?;
-    let (input, attr_name) = take_until("\"")("(\"")?;
-    let (input, _) = char('"')(input)?;
-    let (input, _) = char(')')(input)?;
+    let (input, (attr_name, selector)) = delimited(
+        char('('),
+        pair(parse_quoted, opt(preceded(delimited(multispace0, char(','), multispace0), parse_selector_argument))),
+        char(')'),
+    )(input)?;
     
-    Ok((input, MslValue::Attribute { 
-        name: attr_name.to_string() 
-    }))
+    Ok((input, within(selector, MslValue::Attribute { name: attr_name.to_string() })))
 }
 
-fn parse_split_value(input: &str) -> IResult<&str, MslValue> {
-    let (input, _) = tag("split")(input)?;
-    let (input, _) = delimited(char('('), char('"'), char(')'))(input)?;
-    let (input, delimiter) = take_until("\"")("(\"")?;
-    let (input, _) = char('"')(input)?;
-    let (input, _) = char(')')(input)?;
+/// Parses `data("name")`, short for `attr("data-name")`. A camelCase name
+/// is hyphenated the way `dataset` does, so `data("userId")` reads
+/// `data-user-id`.
+fn parse_data_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, _) = tag("data")(input)?;
+    let (input, name) = delimited(char('('), parse_quoted, char(')'))(input)?;
+
+    let mut attribute = String::from("data-");
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            attribute.push('-');
+        }
+        attribute.push(c.to_ascii_lowercase());
+    }
+    Ok((input, MslValue::Attribute { name: attribute }))
+}
+
+fn parse_header_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, _) = tag("header")(input)?;
+    let (input, name) = delimited(char('('), parse_quoted, char(')'))(input)?;
+    
+    Ok((input, MslValue::Header { name: name.to_string() }))
+}
+
+/// Parses `json("$.path")`, rejecting paths that can't be evaluated.
+fn parse_json_value(input: &str) -> IResult<&str, MslValue> {
+    let (input, _) = tag("json")(input)?;
+    let (input, path) = delimited(
+        char('('),
+        nom::combinator::verify(parse_quoted, |path: &str| crate::scraper::JsonPath::parse(path).is_ok()),
+        char(')'),
+    )(input)?;
+    
+    Ok((input, MslValue::Json { path: path.to_string() }))
+}
+
+/// Parses a `.split("/")[-1]` suffix applied to a preceding value.
+fn parse_split_value(input: &str) -> IResult<&str, (String, i32)> {
     let (input, _) = char('.')(input)?;
     let (input, _) = tag("split")(input)?;
-    let (input, _) = char('(')(input)?;
-    let (input, _) = char('"')(input)?;
-    let (input, split_delimiter) = take_until("\"")("(\"")?;
-    let (input, _) = char('"')(input)?;
-    let (input, _) = char(')')(input)?;
+    let (input, delimiter) = delimited(char('('), parse_quoted, char(')'))(input)?;
     let (input, _) = char('[')(input)?;
     let (input, index_str) = take_while(|c| c != ']')(input)?;
     let (input, _) = char(']')(input)?;
     
     let index = index_str.parse::<i32>().unwrap_or(-1);
     
-    Ok((input, MslValue::Split { 
-        delimiter: split_delimiter.to_string(), 
-        index 
-    }))
+    Ok((input, (delimiter.to_string(), index)))
 }
 
+/// Parses the `media` line; its blocks come from the indented lines beneath it.
 fn parse_media(input: &str) -> IResult<&str, MslCommand> {
     let (input, _) = tag("media")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = opt(char('\n'))(input)?;
     
-    let (input, media_blocks) = many0(parse_media_block)(input)?;
+    Ok((input, MslCommand::Media { media_blocks: Vec::new() }))
+}
+
+/// Parses the body of a `media` command. A `save to` beside the type blocks
+/// is the destination of every block in the command that doesn't name its
+/// own with a `save to` nested beneath it.
+fn parse_media_blocks(blocks: &[Block]) -> Result<Vec<MediaBlock>, MslError> {
+    let mut media_blocks = Vec::new();
+    let mut shared_path = None;
     
-    let (input, _) = opt(char('\n'))(input)?;
+    for block in blocks {
+        if let Ok((_, path)) = all_consuming(parse_save_path)(block.line.text) {
+            ensure_no_children(block)?;
+            shared_path = Some(path);
+            continue;
+        }
+        media_blocks.push(parse_media_block(block)?);
+    }
+    
+    if let Some(path) = shared_path {
+        for media_block in media_blocks.iter_mut().filter(|block| block.save_path.is_none()) {
+            media_block.save_path = Some(path.clone());
+        }
+    }
     
-    Ok((input, MslCommand::Media { media_blocks }))
+    Ok(media_blocks)
 }
 
-fn parse_media_block(input: &str) -> IResult<&str, MediaBlock> {
-    let (input, _) = multispace0(input)?;
-    let (input, media_type) = parse_media_type(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = opt(char('\n'))(input)?;
-    
-    let (input, filters) = many0(parse_media_filter)(input)?;
+fn parse_media_block(block: &Block) -> Result<MediaBlock, MslError> {
+    let media_type = parse_line(&block.line, parse_media_type, "image, video or audio")?;
+    let mut filters = Vec::new();
+    let mut save_path = None;
+    let mut sort = None;
+    let mut limit = None;
     
-    let (input, _) = opt(char('\n'))(input)?;
+    for child in &block.children {
+        ensure_no_children(child)?;
+        if let Ok((_, path)) = all_consuming(parse_save_path)(child.line.text) {
+            save_path = Some(path);
+            continue;
+        }
+        if child.line.text.starts_with("sort") {
+            sort = Some(parse_line(&child.line, parse_media_sort, "sort by size or url, then asc or desc")?);
+            continue;
+        }
+        if child.line.text.starts_with("limit") {
+            limit = Some(parse_line(&child.line, parse_media_limit, "limit followed by a number")?);
+            continue;
+        }
+        filters.push(parse_line(&child.line, parse_media_filter, "a media filter")?);
+    }
     
-    Ok((input, MediaBlock { 
+    Ok(MediaBlock { 
         media_type, 
         filters, 
-        save_path: None 
-    }))
+        save_path,
+        sort,
+        limit,
+    })
+}
+
+/// Parses `sort by size` or `sort by url`, optionally followed by `asc` or
+/// `desc`.
+fn parse_media_sort(input: &str) -> IResult<&str, MediaSort> {
+    let (input, _) = terminated(tag("sort"), multispace1)(input)?;
+    let (input, _) = terminated(tag("by"), multispace1)(input)?;
+    let (input, key) = alt((
+        value(SortKey::Size, tag("size")),
+        value(SortKey::Url, tag("url")),
+    ))(input)?;
+    let (input, descending) = opt(preceded(
+        multispace1,
+        alt((value(false, tag("asc")), value(true, tag("desc")))),
+    ))(input)?;
+    
+    Ok((input, MediaSort { key, descending: descending.unwrap_or(false) }))
+}
+
+fn parse_media_limit(input: &str) -> IResult<&str, usize> {
+    let (input, _) = terminated(tag("limit"), multispace1)(input)?;
+    map_res(digit1, str::parse)(input)
 }
 
 fn parse_media_type(input: &str) -> IResult<&str, MediaType> {
@@ -220,27 +1088,76 @@ fn parse_media_type(input: &str) -> IResult<&str, MediaType> {
 }
 
 fn parse_media_filter(input: &str) -> IResult<&str, MediaFilter> {
-    let (input, _) = multispace0(input)?;
-    
-    let (input, filter) = alt((
+    alt((
         parse_where_filter,
         parse_extensions_filter,
-    ))(input)?;
-    
-    let (input, _) = opt(char('\n'))(input)?;
-    
-    Ok((input, filter))
+    ))(input)
 }
 
 fn parse_where_filter(input: &str) -> IResult<&str, MediaFilter> {
     let (input, _) = tag("where")(input)?;
     let (input, _) = multispace1(input)?;
-    let (input, field) = take_while(|c| c != ' ')(input)?;
+    parse_filter_or(input)
+}
+
+/// Parses `a or b ...`; `or` binds more loosely than `and`.
+fn parse_filter_or(input: &str) -> IResult<&str, MediaFilter> {
+    let (input, first) = parse_filter_and(input)?;
+    let (input, rest) = many0(preceded(parse_keyword("or"), parse_filter_and))(input)?;
+    
+    Ok((input, combine_filters(first, rest, MediaFilter::Or)))
+}
+
+fn parse_filter_and(input: &str) -> IResult<&str, MediaFilter> {
+    let (input, first) = parse_filter_term(input)?;
+    let (input, rest) = many0(preceded(parse_keyword("and"), parse_filter_term))(input)?;
+    
+    Ok((input, combine_filters(first, rest, MediaFilter::And)))
+}
+
+fn parse_filter_term(input: &str) -> IResult<&str, MediaFilter> {
+    alt((
+        delimited(
+            pair(char('('), multispace0),
+            parse_filter_or,
+            pair(multispace0, char(')')),
+        ),
+        parse_filter_condition,
+    ))(input)
+}
+
+fn parse_keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    delimited(multispace1, tag(keyword), multispace1)
+}
+
+fn combine_filters(
+    first: MediaFilter,
+    rest: Vec<MediaFilter>,
+    group: fn(Vec<MediaFilter>) -> MediaFilter,
+) -> MediaFilter {
+    if rest.is_empty() {
+        return first;
+    }
+    let mut filters = vec![first];
+    filters.extend(rest);
+    group(filters)
+}
+
+fn parse_filter_condition(input: &str) -> IResult<&str, MediaFilter> {
+    let (input, field) = take_while1(|c: char| c.is_alphanumeric() || c == '-' || c == '_')(input)?;
     let (input, _) = multispace0(input)?;
-    let (input, operator) = alt((tag("~"), tag("="), tag("!=")))(input)?;
+    let (input, operator) = alt((
+        tag("~"),
+        tag("="),
+        tag("!="),
+        tag("!~"),
+        tag(">="),
+        tag("<="),
+        tag(">"),
+        tag("<"),
+    ))(input)?;
     let (input, _) = multispace0(input)?;
-    let (input, value) = delimited(char('"'), take_until("\""), char('"'))(input)?;
-    let (input, _) = opt(char('\n'))(input)?;
+    let (input, value) = alt((parse_quoted, digit1))(input)?;
     
     Ok((input, MediaFilter::Where { 
         field: field.to_string(), 
@@ -264,32 +1181,42 @@ fn parse_extensions_filter(input: &str) -> IResult<&str, MediaFilter> {
     Ok((input, MediaFilter::Extensions { extensions: extensions_vec }))
 }
 
+/// Parses `save to "path"`. The line's indentation has already been
+/// stripped by the block scanner.
 fn parse_save_path(input: &str) -> IResult<&str, String> {
-    let (input, _) = multispace1(input)?;
-    let (input, _) = multispace1(input)?; // Double indent
     let (input, _) = tag("save")(input)?;
     let (input, _) = multispace1(input)?;
     let (input, _) = tag("to")(input)?;
     let (input, _) = multispace1(input)?;
     let (input, path) = delimited(char('"'), take_until("\""), char('"'))(input)?;
-    let (input, _) = opt(char('\n'))(input)?;
     
     Ok((input, path.to_string()))
 }
 
 fn parse_save(input: &str) -> IResult<&str, MslCommand> {
+    let (input, path) = parse_save_path(input)?;
+    
+    Ok((input, MslCommand::Save { path }))
+}
+
+fn parse_save_page(input: &str) -> IResult<&str, MslCommand> {
     let (input, _) = tag("save")(input)?;
     let (input, _) = multispace1(input)?;
+    let (input, _) = tag("page")(input)?;
+    let (input, _) = multispace1(input)?;
     let (input, _) = tag("to")(input)?;
     let (input, _) = multispace1(input)?;
     let (input, path) = delimited(char('"'), take_until("\""), char('"'))(input)?;
     
-    Ok((input, MslCommand::Save { path: path.to_string() }))
+    Ok((input, MslCommand::SavePage { path: path.to_string() }))
 }
 
 fn parse_wait(input: &str) -> IResult<&str, MslCommand> {
     let (input, _) = tag("wait")(input)?;
     let (input, _) = multispace1(input)?;
+    if let Ok((input, (min, max))) = parse_random_range(input) {
+        return Ok((input, MslCommand::WaitRandom { min, max }));
+    }
     let (input, seconds_str) = take_while(|c: char| c.is_ascii_digit())(input)?;
     let (input, _) = multispace0(input)?;
     
@@ -298,6 +1225,23 @@ fn parse_wait(input: &str) -> IResult<&str, MslCommand> {
     Ok((input, MslCommand::Wait { seconds }))
 }
 
+/// Parses `random(2, 5)`, requiring the minimum not to exceed the maximum.
+fn parse_random_range(input: &str) -> IResult<&str, (u64, u64)> {
+    let number = || nom::combinator::map_res(digit1, str::parse::<u64>);
+    let (input, _) = tag("random")(input)?;
+    let (input, range) = nom::combinator::verify(
+        delimited(
+            pair(char('('), multispace0),
+            separated_pair(number(), delimited(multispace0, char(','), multispace0), number()),
+            pair(multispace0, char(')')),
+        ),
+        |(min, max)| min <= max,
+    )(input)?;
+    let (input, _) = multispace0(input)?;
+    
+    Ok((input, range))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -335,4 +1279,617 @@ click ".user-card a"
         let result = parse_script(script);
         assert!(result.is_ok());
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_parse_extension_globs() {
+        let script = parse_script("media\n  image\n    extensions jp*g, ?if, *\n").unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media, got {:?}", script.commands[0]);
+        };
+        assert!(matches!(
+            &media_blocks[0].filters[0],
+            MediaFilter::Extensions { extensions } if extensions == &["jp*g", "?if", "*"]
+        ));
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let script = r#"
+# Fetch the landing page
+open "https://example.com"
+
+# Wait for lazy content
+wait 2
+# trailing comment
+"#;
+        let result = parse_script(script).unwrap();
+        assert_eq!(result.commands.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_script_without_commands() {
+        for script in ["", "\n\n", "  \t \r\n   \n", "# just a comment\n\n    # indented comment", "\u{feff}", "\u{feff}# comment\n"] {
+            let result = parse_script(script).unwrap_or_else(|e| panic!("{:?}: {}", script, e));
+            assert!(result.commands.is_empty(), "{:?}", script);
+        }
+        assert_eq!(parse_script("\u{feff}wait 1\n").unwrap().commands.len(), 1);
+    }
+
+    fn click_body(script: &MslScript) -> &[MslCommand] {
+        match &script.commands[1] {
+            MslCommand::Click { commands, .. } => commands,
+            other => panic!("expected click, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_click_each() {
+        let script = parse_script("click each \".card a\"\n  set name = text\nclick \"a.next\"\n").unwrap();
+        assert!(matches!(
+            &script.commands[0],
+            MslCommand::Click { selector, each: true, commands } if selector == ".card a" && commands.len() == 1
+        ));
+        assert!(matches!(&script.commands[1], MslCommand::Click { each: false, .. }));
+    }
+
+    #[test]
+    fn test_parse_nested_click_two_spaces() {
+        let script = parse_script(r#"
+open "https://example.com"
+click ".user-card a"
+  set user = text
+  media
+    image
+      extensions jpg
+wait 1
+"#).unwrap();
+        assert_eq!(script.commands.len(), 3);
+        assert_eq!(click_body(&script).len(), 2);
+    }
+
+    #[test]
+    fn test_parse_nested_click_four_spaces() {
+        let script = parse_script("open \"https://example.com\"\nclick \"a\"\n    set user = text\n    click \"b\"\n        set post = text\nwait 1\n").unwrap();
+        assert_eq!(script.commands.len(), 3);
+
+        let body = click_body(&script);
+        assert_eq!(body.len(), 2);
+        match &body[1] {
+            MslCommand::Click { commands, .. } => assert_eq!(commands.len(), 1),
+            other => panic!("expected nested click, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_nested_click_tabs() {
+        let script = parse_script("open \"https://example.com\"\nclick \"a\"\n\tset user = text\n\tmedia\n\t\timage\n\t\t\twhere src ~ \"cdn\"\nwait 1\n").unwrap();
+        assert_eq!(script.commands.len(), 3);
+
+        let body = click_body(&script);
+        assert_eq!(body.len(), 2);
+        match &body[1] {
+            MslCommand::Media { media_blocks } => assert_eq!(media_blocks[0].filters.len(), 1),
+            other => panic!("expected media, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_mixed_tabs_and_spaces() {
+        let options = ParseOptions { tab_width: 2, ..ParseOptions::default() };
+        let script = parse_script_with_options("click \"a\"\n\tset a = text\n  set b = text\n", &options).unwrap();
+        match &script.commands[0] {
+            MslCommand::Click { commands, .. } => assert_eq!(commands.len(), 2),
+            other => panic!("expected click, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_negated_where_filters() {
+        let script = parse_script(r#"
+media
+  image
+    where src != "https://ads.example.com/banner.jpg"
+    where src !~ "ads.example.com"
+"#).unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media command");
+        };
+        let operators: Vec<&str> = media_blocks[0].filters.iter().map(|filter| match filter {
+            MediaFilter::Where { operator, .. } => operator.as_str(),
+            other => panic!("expected where filter, got {:?}", other),
+        }).collect();
+        assert_eq!(operators, vec!["!=", "!~"]);
+    }
+
+    #[test]
+    fn test_parse_size_and_type_filters() {
+        let script = parse_script(r#"
+media
+  image
+    where size > 10000
+    where type = "image/jpeg"
+"#).unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media command");
+        };
+        match &media_blocks[0].filters[0] {
+            MediaFilter::Where { field, operator, value } => {
+                assert_eq!((field.as_str(), operator.as_str(), value.as_str()), ("size", ">", "10000"));
+            }
+            other => panic!("expected where filter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_or_and_grouping() {
+        let script = parse_script(r#"
+media
+  image
+    where src ~ "a.example.com" or (src ~ "b.example.com" and alt = "hero")
+"#).unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media command");
+        };
+        let MediaFilter::Or(alternatives) = &media_blocks[0].filters[0] else {
+            panic!("expected or filter, got {:?}", media_blocks[0].filters[0]);
+        };
+        assert_eq!(alternatives.len(), 2);
+        assert!(matches!(&alternatives[0], MediaFilter::Where { value, .. } if value == "a.example.com"));
+        assert!(matches!(&alternatives[1], MediaFilter::And(conditions) if conditions.len() == 2));
+    }
+
+    #[test]
+    fn test_parse_and_binds_tighter_than_or() {
+        let script = parse_script("media\n  image\n    where src ~ \"a\" and src ~ \"b\" or src ~ \"c\"\n").unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media command");
+        };
+        let MediaFilter::Or(alternatives) = &media_blocks[0].filters[0] else {
+            panic!("expected or filter, got {:?}", media_blocks[0].filters[0]);
+        };
+        assert!(matches!(&alternatives[0], MediaFilter::And(conditions) if conditions.len() == 2));
+    }
+
+    #[test]
+    fn test_parse_concat_value() {
+        let script = parse_script(r#"set path = user + "/" + attr("href").split("/")[-1]"#).unwrap();
+        let MslCommand::Set { value: MslValue::Concat { parts }, .. } = &script.commands[0] else {
+            panic!("expected concatenation, got {:?}", script.commands[0]);
+        };
+        assert_eq!(parts.len(), 3);
+        assert!(matches!(&parts[0], MslValue::Variable { name } if name == "user"));
+        assert!(matches!(&parts[1], MslValue::Literal { value } if value == "/"));
+        assert!(matches!(&parts[2], MslValue::Split { .. }));
+    }
+
+    #[test]
+    fn test_parse_template_and_keyword_prefix() {
+        let script = parse_script("set name = \"{user}-{id}\"\nset copy = texture\n").unwrap();
+        assert!(matches!(&script.commands[0], MslCommand::Set { value: MslValue::Literal { value }, .. } if value == "{user}-{id}"));
+        assert!(matches!(&script.commands[1], MslCommand::Set { value: MslValue::Variable { name }, .. } if name == "texture"));
+    }
+
+    #[test]
+    fn test_parse_set_fallback() {
+        let script = parse_script(r#"set user = text or attr("title") or "anonymous""#).unwrap();
+        let MslCommand::Set { value: MslValue::Fallback { value, fallback }, .. } = &script.commands[0] else {
+            panic!("expected fallback, got {:?}", script.commands[0]);
+        };
+        assert!(matches!(**value, MslValue::Text));
+        let MslValue::Fallback { value, fallback } = &**fallback else {
+            panic!("expected nested fallback, got {:?}", fallback);
+        };
+        assert!(matches!(&**value, MslValue::Attribute { name } if name == "title"));
+        assert!(matches!(&**fallback, MslValue::Literal { value } if value == "anonymous"));
+    }
+
+    #[test]
+    fn test_parse_foreach_links() {
+        let script = parse_script("foreach link in links\n  open \"{link}\"\n  media\n    image\n").unwrap();
+        let MslCommand::Foreach { variable, source: ForeachSource::Links, commands } = &script.commands[0] else {
+            panic!("expected foreach, got {:?}", script.commands[0]);
+        };
+        assert_eq!(variable, "link");
+        assert_eq!(commands.len(), 2);
+
+        let script = parse_script("foreach image in images\n  wait 1\n").unwrap();
+        let MslCommand::Foreach { source: ForeachSource::Variable { name }, .. } = &script.commands[0] else {
+            panic!("expected foreach over a variable, got {:?}", script.commands[0]);
+        };
+        assert_eq!(name, "images");
+
+        assert!(parse_script("foreach link in \"pages\"\n  wait 1\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_wait_random() {
+        let script = parse_script("wait random(2, 5)\nwait random(3,3)\nwait 4\n").unwrap();
+        assert!(matches!(script.commands[0], MslCommand::WaitRandom { min: 2, max: 5 }));
+        assert!(matches!(script.commands[1], MslCommand::WaitRandom { min: 3, max: 3 }));
+        assert!(matches!(script.commands[2], MslCommand::Wait { seconds: 4 }));
+        assert_eq!(script.commands[0].keyword(), "wait");
+
+        assert!(parse_script("wait random(5, 2)").is_err());
+        assert!(parse_script("wait random(2)").is_err());
+        assert!(parse_script("wait random(-1, 2)").is_err());
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        let script = parse_script("repeat 3 as page
+  open \"https://example.com/page/{page}\"
+  wait 1
+wait 2
+").unwrap();
+        let MslCommand::Repeat { times, variable, commands } = &script.commands[0] else {
+            panic!("expected repeat, got {:?}", script.commands[0]);
+        };
+        assert_eq!((*times, variable.as_str()), (3, "page"));
+        assert_eq!(commands.len(), 2);
+        assert_eq!(script.commands.len(), 2);
+
+        assert!(parse_script("repeat many as i
+  wait 1
+").is_err());
+        assert!(parse_script("repeat 3
+  wait 1
+").is_err());
+    }
+
+    #[test]
+    fn test_parse_save_page() {
+        let script = parse_script("save page to \"./pages/{id}.html\"\nsave to \"index.html\"\n").unwrap();
+        assert!(matches!(&script.commands[0], MslCommand::SavePage { path } if path == "./pages/{id}.html"));
+        assert!(matches!(&script.commands[1], MslCommand::Save { path } if path == "index.html"));
+    }
+
+    #[test]
+    fn test_parse_media_save_path() {
+        // Layout of examples/sample.msl
+        let script = parse_script(r#"
+open "https://httpbin.org/html"
+
+click "a"
+  set user = text
+
+media
+  image
+    where src ~ "httpbin.org"
+    extensions jpg, png
+  save to "./media/{user}"
+"#).unwrap();
+
+        assert_eq!(script.commands.len(), 3);
+        let MslCommand::Media { media_blocks } = &script.commands[2] else {
+            panic!("expected media command, got {:?}", script.commands[2]);
+        };
+        assert_eq!(media_blocks.len(), 1);
+        assert_eq!(media_blocks[0].filters.len(), 2);
+        assert_eq!(media_blocks[0].save_path.as_deref(), Some("./media/{user}"));
+    }
+
+    #[test]
+    fn test_parse_media_sort_and_limit() {
+        let script = parse_script(r#"
+media
+  image
+    where src ~ "cdn"
+    sort by size desc
+    limit 5
+  video
+    sort by url
+"#).unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media command, got {:?}", script.commands[0]);
+        };
+        assert_eq!(media_blocks[0].sort, Some(MediaSort { key: SortKey::Size, descending: true }));
+        assert_eq!(media_blocks[0].limit, Some(5));
+        assert_eq!(media_blocks[0].filters.len(), 1);
+        assert_eq!(media_blocks[1].sort, Some(MediaSort { key: SortKey::Url, descending: false }));
+        assert_eq!(media_blocks[1].limit, None);
+
+        let error = parse_script("media\n  image\n    sort by name\n").unwrap_err().to_string();
+        assert!(error.contains("expected sort by size or url"), "{}", error);
+        assert!(parse_script("media\n  image\n    limit many\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_block_save_path_overrides_shared() {
+        let script = parse_script(r#"
+media
+  image
+    save to "./images"
+  video
+  save to "./other"
+"#).unwrap();
+        let MslCommand::Media { media_blocks } = &script.commands[0] else {
+            panic!("expected media command, got {:?}", script.commands[0]);
+        };
+        assert_eq!(media_blocks[0].save_path.as_deref(), Some("./images"));
+        assert_eq!(media_blocks[1].save_path.as_deref(), Some("./other"));
+    }
+
+    #[test]
+    fn test_parse_auth() {
+        let script = parse_script("auth basic \"alice\" \"s3cret\"\nauth bearer \"{token}\"\n").unwrap();
+        assert!(matches!(
+            &script.commands[0],
+            MslCommand::Auth { credentials: Credentials::Basic { username, password } }
+                if username == "alice" && password == "s3cret"
+        ));
+        assert!(matches!(
+            &script.commands[1],
+            MslCommand::Auth { credentials: Credentials::Bearer { token } } if token == "{token}"
+        ));
+
+        assert!(parse_script("auth digest \"x\"").is_err());
+        assert!(parse_script("auth basic \"alice\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("shared")).unwrap();
+        std::fs::write(dir.path().join("shared/auth.msl"), "auth bearer \"token\"\n").unwrap();
+        std::fs::write(dir.path().join("shared/common.msl"), "include \"auth.msl\"\nwait 1\n").unwrap();
+        let script_path = dir.path().join("main.msl");
+        let options = ParseOptions {
+            source_path: Some(script_path),
+            ..ParseOptions::default()
+        };
+
+        let script = parse_script_with_options(
+            "import \"shared/common.msl\"\nopen \"https://example.com\"\n",
+            &options,
+        )
+        .unwrap();
+        let keywords: Vec<&str> = script.commands.iter().map(MslCommand::keyword).collect();
+        assert_eq!(keywords, vec!["auth", "wait", "open"]);
+
+        let unresolved = parse_script_with_options(
+            "include \"shared/common.msl\"\n",
+            &ParseOptions { resolve_includes: false, ..options },
+        )
+        .unwrap();
+        assert!(matches!(&unresolved.commands[0], MslCommand::Include { path } if path == "shared/common.msl"));
+    }
+
+    #[test]
+    fn test_parse_circular_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.msl"), "include \"b.msl\"\n").unwrap();
+        std::fs::write(dir.path().join("b.msl"), "wait 1\ninclude \"a.msl\"\n").unwrap();
+        let options = ParseOptions {
+            source_path: Some(dir.path().join("main.msl")),
+            ..ParseOptions::default()
+        };
+
+        let error = parse_script_with_options("include \"a.msl\"\n", &options).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("circular include"), "{}", message);
+        assert!(message.contains("a.msl -> ") && message.ends_with("a.msl"), "{}", message);
+
+        let error = parse_script_with_options("click \"a\"\n  include \"a.msl\"\n", &options).unwrap_err();
+        assert!(error.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn test_parse_url_and_title_values() {
+        let script = parse_script("set t = title\nset u = url\nset dir = title + \"-\" + url.split(\"/\")[-1]\nset x = titles\n").unwrap();
+        let values: Vec<&MslValue> = script
+            .commands
+            .iter()
+            .map(|command| match command {
+                MslCommand::Set { value, .. } => value,
+                other => panic!("expected set, got {:?}", other),
+            })
+            .collect();
+
+        assert!(matches!(values[0], MslValue::Title));
+        assert!(matches!(values[1], MslValue::Url));
+        assert!(matches!(values[2], MslValue::Concat { parts } if matches!(parts[0], MslValue::Title)));
+        assert!(matches!(values[3], MslValue::Variable { name } if name == "titles"));
+    }
+
+    #[test]
+    fn test_parse_extract() {
+        let script = parse_script("extract jsonld \"$.name\" into product\nextract jsonld \"$.image[*]\" into media\n").unwrap();
+        assert!(matches!(
+            &script.commands[0],
+            MslCommand::Extract { source: ExtractSource::JsonLd, path, target: ExtractTarget::Variable { name } }
+                if path == "$.name" && name == "product"
+        ));
+        assert!(matches!(
+            &script.commands[1],
+            MslCommand::Extract { target: ExtractTarget::Media, .. }
+        ));
+        assert!(parse_script("extract jsonld \"$.name\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_expect() {
+        let script = parse_script("expect \".product img\" count >= 1\nexpect \"h1\" count = 1").unwrap();
+        assert!(matches!(
+            &script.commands[0],
+            MslCommand::Expect { selector, operator, count: 1 } if selector == ".product img" && operator == ">="
+        ));
+        assert!(matches!(&script.commands[1], MslCommand::Expect { operator, .. } if operator == "="));
+
+        assert!(parse_script("expect \"h1\" count ~ 1").is_err());
+        assert!(parse_script("expect \"h1\" count >= many").is_err());
+    }
+
+    #[test]
+    fn test_parse_post() {
+        let script = parse_script(concat!(
+            "post \"https://example.com/search\" { q: \"{term}\", \"page[size]\" : \"20\", }\n",
+            "post json \"https://example.com/api\" {}\n",
+            "post \"https://example.com/ping\"\n",
+        ))
+        .unwrap();
+
+        assert!(matches!(
+            &script.commands[0],
+            MslCommand::Post { url, fields, json: false } if url == "https://example.com/search"
+                && *fields == [("q".to_string(), "{term}".to_string()), ("page[size]".to_string(), "20".to_string())]
+        ));
+        assert!(matches!(&script.commands[1], MslCommand::Post { fields, json: true, .. } if fields.is_empty()));
+        assert!(matches!(&script.commands[2], MslCommand::Post { fields, json: false, .. } if fields.is_empty()));
+
+        assert!(parse_script("post \"https://example.com\" { q \"x\" }").is_err());
+        assert!(parse_script("post \"https://example.com\" { q: \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_selector() {
+        let error = parse_script("open \"https://example.com\"\nclick \".list a\"\n  expect \"div:has(img)\" count >= 1\n").unwrap_err();
+        assert!(matches!(&error, MslError::InvalidSelector { line: 3, column: 11, .. }), "{:?}", error);
+        assert_eq!(error.to_string(), "line 3: Invalid CSS selector \"div:has(img)\": :has is not supported");
+
+        assert!(matches!(parse_script("click \"a >\"").unwrap_err(), MslError::InvalidSelector { line: 1, column: 8, .. }));
+        assert!(matches!(parse_script("click \"xpath:a\"").unwrap_err(), MslError::InvalidSelector { .. }));
+        assert!(parse_script("click \"a:contains('Next')\"\nclick \"xpath://a[@href]\"").is_ok());
+    }
+
+    #[test]
+    fn test_parse_list_values() {
+        let script = parse_script("set users = text all\nset avatars = attr(\"src\")   all\nset user = text\n").unwrap();
+        let values: Vec<&MslValue> = script
+            .commands
+            .iter()
+            .map(|command| match command {
+                MslCommand::Set { value, .. } => value,
+                other => panic!("expected set, got {:?}", other),
+            })
+            .collect();
+        assert!(matches!(values[0], MslValue::All { source } if matches!(**source, MslValue::Text)));
+        assert!(matches!(values[1], MslValue::All { source } if matches!(&**source, MslValue::Attribute { name } if name == "src")));
+        assert!(matches!(values[2], MslValue::Text));
+
+        assert!(parse_script("set users = title all").is_err());
+        assert!(parse_script("set users = text all or \"none\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_data_shorthand() {
+        let script = parse_script("set id = data(\"id\")\nset user = data(\"userId\")\nset ids = data(\"item-id\") all\nset copy = data\n").unwrap();
+        let values: Vec<&MslValue> = script
+            .commands
+            .iter()
+            .map(|command| match command {
+                MslCommand::Set { value, .. } => value,
+                other => panic!("expected set, got {:?}", other),
+            })
+            .collect();
+        assert!(matches!(values[0], MslValue::Attribute { name } if name == "data-id"));
+        assert!(matches!(values[1], MslValue::Attribute { name } if name == "data-user-id"));
+        assert!(matches!(values[2], MslValue::All { source } if matches!(&**source, MslValue::Attribute { name } if name == "data-item-id")));
+        assert!(matches!(values[3], MslValue::Variable { name } if name == "data"));
+    }
+
+    #[test]
+    fn test_parse_header_value() {
+        let script = parse_script("set etag = header(\"ETag\")\nset header = \"x\"\nset copy = header\n").unwrap();
+        assert!(matches!(&script.commands[0], MslCommand::Set { value: MslValue::Header { name }, .. } if name == "ETag"));
+        assert!(matches!(&script.commands[2], MslCommand::Set { value: MslValue::Variable { name }, .. } if name == "header"));
+
+        assert!(parse_script("set etag = header(etag)").is_err());
+    }
+
+    #[test]
+    fn test_parse_json_value() {
+        let script = parse_script("set id = json(\"$.data.items[0].id\")\nset ids = json(\"$.data.items[*].id\") all\nset json = \"x\"\n").unwrap();
+        assert!(matches!(&script.commands[0], MslCommand::Set { value: MslValue::Json { path }, .. } if path == "$.data.items[0].id"));
+        assert!(matches!(&script.commands[1], MslCommand::Set { value: MslValue::All { .. }, .. }));
+
+        assert!(parse_script("set id = json(\"data.id\")").is_err());
+        assert!(parse_script("set id = json(\"$.items[\")").is_err());
+    }
+
+    #[test]
+    fn test_parse_transform_pipeline() {
+        let script = parse_script(r#"set name = text | trim | lower | replace(" ", "_") or "untitled" | upper"#).unwrap();
+        let MslCommand::Set { value: MslValue::Fallback { value, fallback }, .. } = &script.commands[0] else {
+            panic!("expected fallback, got {:?}", script.commands[0]);
+        };
+        let MslValue::Transform { source, transforms } = &**value else {
+            panic!("expected transform, got {:?}", value);
+        };
+        assert!(matches!(**source, MslValue::Text));
+        assert_eq!(
+            transforms,
+            &vec![
+                Transform::Trim,
+                Transform::Lower,
+                Transform::Replace { from: " ".to_string(), to: "_".to_string() },
+            ]
+        );
+        assert!(matches!(&**fallback, MslValue::Transform { transforms, .. } if transforms == &vec![Transform::Upper]));
+
+        assert!(parse_script("set name = text | shout").is_err());
+        assert!(parse_script("set name = text | replace(\"a\")").is_err());
+    }
+
+    #[test]
+    fn test_parse_scoped_values() {
+        let script = parse_script("click each \"a.card\"\n  set name = text(\".name\")\n  set thumb = attr(\"src\", \"img.thumb\")\n  set tags = text(\".tag\") all\n").unwrap();
+        let body = match &script.commands[0] {
+            MslCommand::Click { commands, .. } => commands,
+            other => panic!("expected click, got {:?}", other),
+        };
+        assert!(matches!(
+            &body[0],
+            MslCommand::Set { value: MslValue::Within { selector, value }, .. }
+                if selector == ".name" && matches!(**value, MslValue::Text)
+        ));
+        assert!(matches!(
+            &body[1],
+            MslCommand::Set { value: MslValue::Within { selector, value }, .. }
+                if selector == "img.thumb" && matches!(&**value, MslValue::Attribute { name } if name == "src")
+        ));
+        assert!(matches!(&body[2], MslCommand::Set { value: MslValue::All { source }, .. } if matches!(**source, MslValue::Within { .. })));
+        assert_eq!(body[1].to_string().trim_end(), r#"set thumb = attr("src", "img.thumb")"#);
+        assert_eq!(body[0].to_string().trim_end(), r#"set name = text(".name")"#);
+
+        assert!(parse_script("set name = text(\"..bad\")").is_err());
+        assert!(parse_script("set src = attr(\"src\", \"[\")").is_err());
+    }
+
+    #[test]
+    fn test_parse_html_values() {
+        let script = parse_script("set inner = html\nset outer = outerhtml\nset body = html(\".body\")\nset html_copy = inner\n").unwrap();
+        assert!(matches!(&script.commands[0], MslCommand::Set { value: MslValue::Html, .. }));
+        assert!(matches!(&script.commands[1], MslCommand::Set { value: MslValue::OuterHtml, .. }));
+        assert!(matches!(&script.commands[2], MslCommand::Set { value: MslValue::Within { value, .. }, .. } if matches!(**value, MslValue::Html)));
+        assert!(matches!(&script.commands[3], MslCommand::Set { value: MslValue::Variable { name }, .. } if name == "inner"));
+        assert_eq!(script.commands[2].to_string().trim_end(), r#"set body = html(".body")"#);
+        assert_eq!(script.commands[1].to_string().trim_end(), "set outer = outerhtml");
+        assert_eq!(script.commands[0].to_string().trim_end(), "set inner = html");
+    }
+
+    #[test]
+    fn test_parse_regex_step() {
+        let script = parse_script(r#"set id = text | trim | regex("/user/(\d+)/", 1) | upper"#).unwrap();
+        let MslCommand::Set { value: MslValue::Transform { source, transforms }, .. } = &script.commands[0] else {
+            panic!("expected transform, got {:?}", script.commands[0]);
+        };
+        assert_eq!(transforms, &vec![Transform::Upper]);
+        let MslValue::Regex { source, pattern, group } = &**source else {
+            panic!("expected regex, got {:?}", source);
+        };
+        assert_eq!((pattern.as_str(), *group), (r"/user/(\d+)/", 1));
+        assert!(matches!(&**source, MslValue::Transform { transforms, .. } if transforms == &vec![Transform::Trim]));
+        assert_eq!(script.commands[0].to_string().trim_end(), r#"set id = text | trim | regex("/user/(\d+)/", 1) | upper"#);
+
+        assert!(parse_script(r#"set id = text | regex("(\d+)", 2)"#).is_err());
+        assert!(parse_script(r#"set id = text | regex("(\d+", 1)"#).is_err());
+        assert!(parse_script(r#"set id = text | regex("\d+")"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_inconsistent_dedent() {
+        let result = parse_script("click \"a\"\n    set a = text\n  set b = text\n");
+        let message = result.unwrap_err().to_string();
+        assert!(message.contains("line 3"), "{}", message);
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use reqwest::cookie::CookieStore;
+use reqwest::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Cookies kept in a JSON file, so that a session such as a login carries
+/// over from one run to the next. As the client's [`CookieStore`] it sends
+/// matching cookies with each request and stores those responses set,
+/// including every response along a redirect chain.
+#[derive(Debug)]
+pub struct CookieJar {
+    path: PathBuf,
+    cookies: Mutex<Vec<Cookie>>,
+    /// When the file was last written as of opening it, to notice another
+    /// run writing it in the meantime.
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Host that set the cookie or, unless `host_only`, the domain whose
+    /// hosts all receive it.
+    domain: String,
+    host_only: bool,
+    path: String,
+    /// Seconds since the Unix epoch; `None` for a session cookie, which
+    /// is kept until a response removes it.
+    expires: Option<u64>,
+    secure: bool,
+}
+
+impl CookieJar {
+    /// Opens the cookie file at `path`. A file that doesn't exist yet is an
+    /// empty jar.
+    pub fn open(path: &Path) -> Result<Self> {
+        let cookies = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Invalid cookie file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read cookie file {}", path.display())),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            cookies: Mutex::new(cookies),
+            modified: modified_time(path),
+        })
+    }
+
+    /// Writes the unexpired cookies back to the file. When another run has
+    /// written the file since it was opened, its cookies are overwritten.
+    pub fn save(&self) -> Result<()> {
+        if modified_time(&self.path) != self.modified {
+            tracing::warn!("{} was changed by another run; overwriting it", self.path.display());
+        }
+        let now = unix_now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        let json = serde_json::to_string(&*cookies).context("Failed to serialize cookies")?;
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        // Replace the file in one step so a concurrent reader never sees
+        // half of it
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, json)
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .with_context(|| format!("Failed to write cookie file {}", self.path.display()))
+    }
+
+    /// The `Cookie` header value to send to `url`, if any cookies match.
+    fn header_for(&self, url: &Url) -> Option<String> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let now = unix_now();
+        let cookies = self.cookies.lock().unwrap();
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|cookie| {
+                !cookie.is_expired(now)
+                    && (!cookie.secure || url.scheme() == "https")
+                    && if cookie.host_only { host == cookie.domain } else { domain_matches(&host, &cookie.domain) }
+                    && path_matches(url.path(), &cookie.path)
+            })
+            .collect();
+        // More specific paths first
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+
+        let pairs: Vec<String> = matching.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect();
+        (!pairs.is_empty()).then(|| pairs.join("; "))
+    }
+
+    /// Stores the cookie a `Set-Cookie` header from `url` sets, replacing
+    /// any with the same name, domain and path. An already expired cookie
+    /// only removes the one it replaces.
+    fn store(&self, url: &Url, header: &str) {
+        let now = unix_now();
+        let Some(cookie) = parse_set_cookie(header, url, now) else {
+            return;
+        };
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|stored| {
+            (&stored.name, &stored.domain, &stored.path) != (&cookie.name, &cookie.domain, &cookie.path)
+        });
+        if !cookie.is_expired(now) {
+            cookies.push(cookie);
+        }
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        for header in cookie_headers {
+            if let Ok(header) = header.to_str() {
+                self.store(url, header);
+            }
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        self.header_for(url).and_then(|header| HeaderValue::from_str(&header).ok())
+    }
+}
+
+impl Cookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Parses a `Set-Cookie` header received from `url`. Cookies for a domain
+/// `url` isn't part of are refused.
+fn parse_set_cookie(header: &str, url: &Url, now: u64) -> Option<Cookie> {
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut cookie = Cookie {
+        name: name.trim().to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url),
+        expires: None,
+        secure: false,
+    };
+    if cookie.name.is_empty() {
+        return None;
+    }
+
+    let mut max_age = None;
+    for attribute in parts {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                if !domain_matches(&host, &domain) {
+                    return None;
+                }
+                // A public suffix such as `com` or `co.uk` may only name the
+                // host setting the cookie, which then stays host-only
+                if is_public_suffix(&domain) {
+                    if domain != host {
+                        return None;
+                    }
+                } else {
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "expires" => {
+                cookie.expires = httpdate::parse_http_date(value)
+                    .ok()
+                    .map(|time| time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()))
+                    .or(cookie.expires);
+            }
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "secure" => cookie.secure = true,
+            _ => {}
+        }
+    }
+    // Max-Age wins over Expires; zero or less deletes the cookie
+    if let Some(max_age) = max_age {
+        cookie.expires = Some(if max_age <= 0 { 0 } else { now.saturating_add(max_age as u64) });
+    }
+    Some(cookie)
+}
+
+/// The directory of `url`'s path, which a cookie without a `Path` covers.
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(end) => url.path()[..end].to_string(),
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Whether `domain` is a single label or a suffix under which anyone can
+/// register names, so that no cookie may cover all of it.
+fn is_public_suffix(domain: &str) -> bool {
+    !domain.contains('.') || psl::suffix_str(domain) == Some(domain)
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || request_path.strip_prefix(cookie_path).is_some_and(|rest| cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_matching() {
+        let dir = tempfile::tempdir().unwrap();
+        let jar = CookieJar::open(&dir.path().join("cookies.json")).unwrap();
+        let url = |url: &str| Url::parse(url).unwrap();
+        let login = url("https://www.example.com/account/login");
+
+        jar.store(&login, "session=abc; Path=/; HttpOnly");
+        jar.store(&login, "prefs=dark");
+        jar.store(&login, "shared=1; Domain=.example.com; Path=/; Secure");
+        jar.store(&login, "old=1; Max-Age=0");
+        jar.store(&login, "stolen=1; Domain=other.com");
+
+        assert_eq!(jar.header_for(&url("https://www.example.com/account/settings")).unwrap(), "prefs=dark; session=abc; shared=1");
+        assert_eq!(jar.header_for(&url("https://www.example.com/")).unwrap(), "session=abc; shared=1");
+        assert_eq!(jar.header_for(&url("http://cdn.example.com/")).as_deref(), None);
+        assert_eq!(jar.header_for(&url("https://cdn.example.com/a")).unwrap(), "shared=1");
+        assert_eq!(jar.header_for(&url("https://other.com/")), None);
+
+        let shop = url("https://shop.example.co.uk/");
+        jar.store(&login, "tld=1; Domain=com");
+        jar.store(&shop, "suffix=1; Domain=.co.uk");
+        assert_eq!(jar.header_for(&url("https://other.com/")), None);
+        assert_eq!(jar.header_for(&url("https://other.co.uk/")), None);
+        assert_eq!(jar.header_for(&shop), None);
+
+        jar.store(&login, "session=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(jar.header_for(&url("https://www.example.com/")).unwrap(), "shared=1");
+    }
+}
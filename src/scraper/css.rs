@@ -0,0 +1,209 @@
+//! CSS selectors as the `scraper` crate parses them, plus `:contains("text")`,
+//! which keeps only elements whose text includes `text`. `scraper` can't
+//! match it, so it's taken out before parsing and checked against each
+//! match afterwards. That only works on the last element of a selector, as
+//! in `.card a:contains("Next")`.
+
+use anyhow::{bail, Result};
+use ego_tree::NodeRef;
+use scraper::error::SelectorErrorKind;
+use scraper::{ElementRef, Html, Node, Selector};
+use selectors::parser::SelectorParseErrorKind;
+
+#[derive(Debug, Clone)]
+pub struct CssSelector {
+    /// The comma-separated selectors, any of which may match.
+    alternatives: Vec<Alternative>,
+}
+
+#[derive(Debug, Clone)]
+struct Alternative {
+    selector: Selector,
+    /// Text a match must contain, from each `:contains(..)`.
+    contains: Vec<String>,
+}
+
+impl CssSelector {
+    pub fn parse(selector: &str) -> Result<Self> {
+        let mut alternatives = Vec::new();
+        let mut start = 0;
+        let commas = scan(selector).into_iter().filter(|&(_, c, depth)| c == ',' && depth == 0);
+        for end in commas.map(|(i, _, _)| i).chain([selector.len()]) {
+            alternatives.push(Alternative::parse(&selector[start..end], selector)?);
+            start = end + 1;
+        }
+        Ok(Self { alternatives })
+    }
+
+    /// Matching elements in document order.
+    pub fn select<'a>(&self, document: &'a Html) -> Vec<ElementRef<'a>> {
+        self.matching(document.tree.nodes())
+    }
+
+    /// Matching descendants of `element`, in document order.
+    pub fn select_within<'a>(&self, element: ElementRef<'a>) -> Vec<ElementRef<'a>> {
+        self.matching(element.descendants().skip(1))
+    }
+
+    fn matching<'a>(&self, nodes: impl Iterator<Item = NodeRef<'a, Node>>) -> Vec<ElementRef<'a>> {
+        nodes
+            .filter_map(ElementRef::wrap)
+            .filter(|element| self.alternatives.iter().any(|alternative| alternative.matches(element)))
+            .collect()
+    }
+}
+
+impl Alternative {
+    fn parse(alternative: &str, selector: &str) -> Result<Self> {
+        let mut css = String::new();
+        let mut contains = Vec::new();
+        let mut rest = alternative;
+        while let Some((colon, open, close)) = find_contains(rest) {
+            css.push_str(&rest[..colon]);
+            // `:contains(..)` on its own stands for any element
+            if css.trim_end().is_empty() || css.ends_with(|c: char| c.is_whitespace() || ">+~".contains(c)) {
+                css.push('*');
+            }
+            let text = rest[open + 1..close].trim();
+            contains.push(unquote(text).to_string());
+            rest = &rest[close + 1..];
+            if starts_new_element(rest) {
+                bail!("Invalid CSS selector \"{}\": :contains() only works on the last element", selector);
+            }
+        }
+        css.push_str(rest);
+
+        let selector = Selector::parse(&css)
+            .map_err(|e| anyhow::anyhow!("Invalid CSS selector \"{}\": {}", selector, describe(e)))?;
+        Ok(Self { selector, contains })
+    }
+
+    fn matches(&self, element: &ElementRef) -> bool {
+        if !self.selector.matches(element) {
+            return false;
+        }
+        let text: String = element.text().collect();
+        self.contains.iter().all(|wanted| text.contains(wanted.as_str()))
+    }
+}
+
+/// What's wrong with a selector. `SelectorErrorKind`'s own `Display` isn't
+/// used because it panics on some tokens, such as a misplaced `*`.
+fn describe(error: SelectorErrorKind) -> String {
+    match error {
+        SelectorErrorKind::UnexpectedToken(token)
+        | SelectorErrorKind::ExpectedColonOnPseudoElement(token)
+        | SelectorErrorKind::ExpectedIdentityOnPseudoElement(token) => format!("unexpected {:?}", token),
+        SelectorErrorKind::EndOfLine => "ends too soon".to_string(),
+        SelectorErrorKind::UnexpectedSelectorParseError(SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name)) => {
+            format!(":{} is not supported", name)
+        }
+        SelectorErrorKind::UnexpectedSelectorParseError(kind) => format!("{:?}", kind),
+        error => format!("{:?}", error),
+    }
+}
+
+/// Offsets of the colon and parentheses of the first top-level
+/// `:contains(..)` in `selector`.
+fn find_contains(selector: &str) -> Option<(usize, usize, usize)> {
+    let chars = scan(selector);
+    let colon = chars.iter().find(|&&(i, c, depth)| {
+        c == ':' && depth == 0 && selector[i + 1..].to_ascii_lowercase().starts_with("contains(")
+    })?.0;
+    let open = colon + "contains(".len();
+    let close = chars.iter().find(|&&(i, c, depth)| i > open && c == ')' && depth == 1)?.0;
+    Some((colon, open, close))
+}
+
+/// Whether `rest`, what follows a pseudo-class, goes on to another element
+/// through a combinator.
+fn starts_new_element(rest: &str) -> bool {
+    let trimmed = rest.trim_start();
+    !trimmed.is_empty() && (trimmed.len() < rest.len() || trimmed.starts_with(['>', '+', '~']))
+}
+
+fn unquote(text: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = text.strip_prefix(quote).and_then(|text| text.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    text
+}
+
+/// The characters of `selector` outside quotes, with their byte offsets and
+/// how deeply they're nested in parentheses and brackets. Escaped
+/// characters are left out.
+fn scan(selector: &str) -> Vec<(usize, char, usize)> {
+    let mut chars = Vec::new();
+    let (mut depth, mut quote, mut escaped) = (0usize, None, false);
+    for (i, c) in selector.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (_, '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '[') => {
+                chars.push((i, c, depth));
+                depth += 1;
+            }
+            (None, ')' | ']') => {
+                chars.push((i, c, depth));
+                depth = depth.saturating_sub(1);
+            }
+            (None, c) => chars.push((i, c, depth)),
+        }
+    }
+    chars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(selector: &str, html: &str) -> Vec<String> {
+        let document = Html::parse_document(html);
+        CssSelector::parse(selector)
+            .unwrap()
+            .select(&document)
+            .iter()
+            .map(|element| element.text().collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_contains() {
+        let html = r#"
+            <ul class="pages">
+              <li><a href="/1">Previous</a></li>
+              <li><a href="/3">Next page</a></li>
+              <li><a href="/4" class="last">Last (10)</a></li>
+            </ul>
+            <p>Next time</p>
+        "#;
+
+        assert_eq!(texts(r#".pages a:contains("Next")"#, html), ["Next page"]);
+        assert_eq!(texts(":contains('Next')", html).last().unwrap(), "Next time");
+        assert_eq!(texts("a:contains(Last (10))", html), ["Last (10)"]);
+        assert_eq!(texts(r#"a:contains("e"):contains("v")"#, html), ["Previous"]);
+        assert_eq!(texts(r#"a:contains("Next"), p:contains("Next"), a.last"#, html), ["Next page", "Last (10)", "Next time"]);
+        assert_eq!(texts(r#"a[title=":contains(x)"]"#, html), Vec::<String>::new());
+        assert!(texts(r#"a:contains("next")"#, html).is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_pseudo_classes() {
+        let error = CssSelector::parse("div:has(a)").unwrap_err().to_string();
+        assert_eq!(error, "Invalid CSS selector \"div:has(a)\": :has is not supported");
+
+        let error = CssSelector::parse(r#"li:contains("Next") a"#).unwrap_err().to_string();
+        assert!(error.contains(":contains() only works on the last element"), "{}", error);
+
+        let error = CssSelector::parse("a*").unwrap_err().to_string();
+        assert_eq!(error, "Invalid CSS selector \"a*\": unexpected Delim('*')");
+
+        assert!(CssSelector::parse("a:not(:contains(x))").is_err());
+        assert!(CssSelector::parse("a[").is_err());
+    }
+}
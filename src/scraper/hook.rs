@@ -0,0 +1,13 @@
+use reqwest::{Request, Response};
+
+/// Callbacks around every HTTP request the scraper sends, including page
+/// fetches, HEAD requests and media downloads. Both do nothing by default,
+/// so a hook only implements the ones it needs.
+pub trait RequestHook: Send + Sync {
+    /// Called just before `request` is sent, and again before each retry.
+    /// Changes to it, such as added headers, are sent.
+    fn on_request(&self, _request: &mut Request) {}
+
+    /// Called with each response once its headers have arrived.
+    fn on_response(&self, _response: &Response) {}
+}
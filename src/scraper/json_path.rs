@@ -0,0 +1,121 @@
+//! A small subset of JSONPath: `$`, `.key`, `["key"]`, `[index]` and the
+//! `*` / `[*]` wildcards.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self> {
+        let Some(mut rest) = path.trim().strip_prefix('$') else {
+            bail!("JSON path must start with '$': {}", path);
+        };
+
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after_dot) = rest.strip_prefix('.') {
+                let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+                let key = &after_dot[..end];
+                segments.push(match key {
+                    "" => bail!("empty key in JSON path: {}", path),
+                    "*" => Segment::Wildcard,
+                    key => Segment::Key(key.to_string()),
+                });
+                rest = &after_dot[end..];
+            } else if let Some(after_bracket) = rest.strip_prefix('[') {
+                let Some(end) = after_bracket.find(']') else {
+                    bail!("unclosed '[' in JSON path: {}", path);
+                };
+                let inner = after_bracket[..end].trim();
+                segments.push(if inner == "*" {
+                    Segment::Wildcard
+                } else if let Some(key) = inner
+                    .strip_prefix('"')
+                    .and_then(|key| key.strip_suffix('"'))
+                    .or_else(|| inner.strip_prefix('\'').and_then(|key| key.strip_suffix('\'')))
+                {
+                    Segment::Key(key.to_string())
+                } else if let Ok(index) = inner.parse() {
+                    Segment::Index(index)
+                } else {
+                    bail!("invalid index '{}' in JSON path: {}", inner, path);
+                });
+                rest = &after_bracket[end + 1..];
+            } else {
+                bail!("unexpected '{}' in JSON path: {}", rest, path);
+            }
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Every value the path selects from `root`, in document order.
+    pub fn select<'a>(&self, root: &'a Value) -> Vec<&'a Value> {
+        self.segments.iter().fold(vec![root], |values, segment| {
+            values
+                .into_iter()
+                .flat_map(|value| -> Vec<&'a Value> {
+                    match (segment, value) {
+                        (Segment::Key(key), Value::Object(map)) => map.get(key).into_iter().collect(),
+                        (Segment::Index(index), Value::Array(items)) => items.get(*index).into_iter().collect(),
+                        (Segment::Wildcard, Value::Array(items)) => items.iter().collect(),
+                        (Segment::Wildcard, Value::Object(map)) => map.values().collect(),
+                        _ => Vec::new(),
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+/// Renders a selected value as plain text: strings without quotes, anything
+/// else as JSON.
+pub fn json_value_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select() {
+        let value = json!({
+            "name": "Lamp",
+            "image": ["https://example.com/a.jpg", "https://example.com/b.jpg"],
+            "offers": { "price": 19.5, "@type": "Offer" },
+        });
+        let select = |path: &str| JsonPath::parse(path).unwrap().select(&value).into_iter().cloned().collect::<Vec<_>>();
+
+        assert_eq!(select("$.name"), vec![json!("Lamp")]);
+        assert_eq!(select("$.image[1]"), vec![json!("https://example.com/b.jpg")]);
+        assert_eq!(select("$.image[*]").len(), 2);
+        assert_eq!(select("$.offers['@type']"), vec![json!("Offer")]);
+        assert_eq!(select("$[\"offers\"].price"), vec![json!(19.5)]);
+        assert!(select("$.missing.key").is_empty());
+        assert_eq!(select("$"), vec![value.clone()]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(JsonPath::parse("image").is_err());
+        assert!(JsonPath::parse("$.").is_err());
+        assert!(JsonPath::parse("$.image[0").is_err());
+        assert!(JsonPath::parse("$.image[first]").is_err());
+    }
+}
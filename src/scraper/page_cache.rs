@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Pages fetched by earlier runs, kept in a JSON file so an unchanged page
+/// can be revalidated with a conditional GET instead of downloaded again.
+/// Changes are written back by [`PageCache::flush`], or when the cache is
+/// dropped.
+#[derive(Debug, Default)]
+pub struct PageCache {
+    path: PathBuf,
+    pages: HashMap<String, CachedPage>,
+    /// Whether pages were stored since the file was last written.
+    dirty: bool,
+}
+
+/// A page as last fetched, keyed in the cache by the URL requested.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedPage {
+    /// Final URL after redirects.
+    pub url: String,
+    pub html: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl PageCache {
+    /// Opens the cache file at `path`. A file that doesn't exist yet is an
+    /// empty cache.
+    pub fn open(path: &Path) -> Result<Self> {
+        let pages = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Invalid page cache {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read page cache {}", path.display())),
+        };
+        Ok(Self { path: path.to_path_buf(), pages, dirty: false })
+    }
+
+    pub fn get(&self, url: &str) -> Option<&CachedPage> {
+        self.pages.get(url)
+    }
+
+    /// Stores `page` for `url`, to be written to the file on the next
+    /// flush.
+    pub fn insert(&mut self, url: &str, page: CachedPage) {
+        self.pages.insert(url.to_string(), page);
+        self.dirty = true;
+    }
+
+    /// Writes the cache back to its file if pages were stored since it was
+    /// last written.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let json = serde_json::to_string(&self.pages).context("Failed to serialize page cache")?;
+        // Replace the file in one step so a concurrent reader never sees
+        // half of it
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, json)
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .with_context(|| format!("Failed to write page cache {}", self.path.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for PageCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            tracing::warn!("{:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages.json");
+        let page = CachedPage {
+            url: "https://example.com/".to_string(),
+            html: "<p>hi</p>".to_string(),
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+
+        let mut cache = PageCache::open(&path).unwrap();
+        assert!(cache.get("https://example.com").is_none());
+        cache.insert("https://example.com", page.clone());
+        assert!(!path.exists());
+        cache.flush().unwrap();
+
+        let reopened = PageCache::open(&path).unwrap();
+        assert_eq!(reopened.get("https://example.com"), Some(&page));
+
+        // Dropping the cache writes what wasn't flushed yet
+        cache.insert("https://example.com/other", page.clone());
+        drop(cache);
+        assert_eq!(PageCache::open(&path).unwrap().get("https://example.com/other"), Some(&page));
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(PageCache::open(&path).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! A small subset of XPath for selecting elements: absolute paths of `/` and
+//! `//` steps naming a tag, `*`, `.` or `..`, each with optional predicates
+//! such as `[2]`, `[@href]`, `[@class='x']`, `[text()='x']`,
+//! `[contains(., 'x')]` and `[starts-with(@href, 'x')]`.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use scraper::{ElementRef, Html};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Axis {
+    Child,
+    /// `//`: children of the context node or any of its descendants.
+    Descendant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NodeTest {
+    Name(String),
+    Any,
+    SelfNode,
+    Parent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    /// `@name`
+    Attribute(String),
+    /// `text()`: the element's own text, without its children's.
+    Text,
+    /// `.`: all text inside the element.
+    StringValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equals,
+    NotEquals,
+    Contains,
+    StartsWith,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Predicate {
+    /// `[n]`, counting from 1.
+    Position(usize),
+    Exists(Operand),
+    Compare { operand: Operand, comparison: Comparison, value: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    axis: Axis,
+    test: NodeTest,
+    predicates: Vec<Predicate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct XPath {
+    steps: Vec<Step>,
+}
+
+impl XPath {
+    pub fn parse(path: &str) -> Result<Self> {
+        let mut rest = path.trim();
+        if !rest.starts_with('/') {
+            bail!("XPath must start with '/': {}", path);
+        }
+
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            let axis = if let Some(after) = rest.strip_prefix("//") {
+                rest = after;
+                Axis::Descendant
+            } else if let Some(after) = rest.strip_prefix('/') {
+                rest = after;
+                Axis::Child
+            } else {
+                bail!("unexpected '{}' in XPath: {}", rest, path);
+            };
+
+            let end = rest.find(['/', '[']).unwrap_or(rest.len());
+            let test = match rest[..end].trim() {
+                "" => bail!("empty step in XPath: {}", path),
+                "*" => NodeTest::Any,
+                "." => NodeTest::SelfNode,
+                ".." => NodeTest::Parent,
+                name if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+                    NodeTest::Name(name.to_ascii_lowercase())
+                }
+                step => bail!("unsupported step '{}' in XPath: {}", step, path),
+            };
+            rest = &rest[end..];
+
+            let mut predicates = Vec::new();
+            while let Some(after_bracket) = rest.strip_prefix('[') {
+                let end = closing_bracket(after_bracket)
+                    .with_context(|| format!("unclosed '[' in XPath: {}", path))?;
+                let predicate = parse_predicate(after_bracket[..end].trim())
+                    .with_context(|| format!("in XPath: {}", path))?;
+                predicates.push(predicate);
+                rest = &after_bracket[end + 1..];
+            }
+
+            steps.push(Step { axis, test, predicates });
+        }
+
+        Ok(Self { steps })
+    }
+
+    /// Every element the path selects in `document`, in the order found.
+    pub fn select<'a>(&self, document: &'a Html) -> Vec<ElementRef<'a>> {
+        // `None` stands for the document node above the root element
+        let mut context: Vec<Option<ElementRef<'a>>> = vec![None];
+
+        for step in &self.steps {
+            let mut seen = HashSet::new();
+            let mut next = Vec::new();
+            for node in context {
+                let parents = match step.axis {
+                    Axis::Child => vec![node],
+                    Axis::Descendant => descendants_or_self(document, node),
+                };
+                for parent in parents {
+                    for element in step.apply(document, parent) {
+                        if seen.insert(element.id()) {
+                            next.push(Some(element));
+                        }
+                    }
+                }
+            }
+            context = next;
+        }
+
+        context.into_iter().flatten().collect()
+    }
+}
+
+impl Step {
+    /// The elements this step reaches from `node`, with the predicates
+    /// applied in turn.
+    fn apply<'a>(&self, document: &'a Html, node: Option<ElementRef<'a>>) -> Vec<ElementRef<'a>> {
+        let children = || match node {
+            Some(element) => element.children().filter_map(ElementRef::wrap).collect(),
+            None => vec![document.root_element()],
+        };
+        let mut candidates: Vec<ElementRef<'a>> = match &self.test {
+            NodeTest::Name(name) => children().into_iter().filter(|element| element.value().name() == name).collect(),
+            NodeTest::Any => children(),
+            NodeTest::SelfNode => node.into_iter().collect(),
+            NodeTest::Parent => node.and_then(|element| element.parent()).and_then(ElementRef::wrap).into_iter().collect(),
+        };
+
+        for predicate in &self.predicates {
+            candidates = candidates
+                .into_iter()
+                .enumerate()
+                .filter(|(i, element)| predicate.matches(i + 1, element))
+                .map(|(_, element)| element)
+                .collect();
+        }
+        candidates
+    }
+}
+
+impl Predicate {
+    fn matches(&self, position: usize, element: &ElementRef) -> bool {
+        match self {
+            Predicate::Position(expected) => position == *expected,
+            Predicate::Exists(operand) => operand.value(element).is_some(),
+            Predicate::Compare { operand, comparison, value } => operand.value(element).is_some_and(|actual| match comparison {
+                Comparison::Equals => actual == *value,
+                Comparison::NotEquals => actual != *value,
+                Comparison::Contains => actual.contains(value.as_str()),
+                Comparison::StartsWith => actual.starts_with(value.as_str()),
+            }),
+        }
+    }
+}
+
+impl Operand {
+    fn value(&self, element: &ElementRef) -> Option<String> {
+        match self {
+            Operand::Attribute(name) => element.value().attr(name).map(str::to_string),
+            Operand::Text => {
+                let text: String = element
+                    .children()
+                    .filter_map(|child| child.value().as_text().map(|text| text.to_string()))
+                    .collect();
+                (!text.is_empty()).then_some(text)
+            }
+            Operand::StringValue => Some(element.text().collect()),
+        }
+    }
+}
+
+fn descendants_or_self<'a>(document: &'a Html, node: Option<ElementRef<'a>>) -> Vec<Option<ElementRef<'a>>> {
+    match node {
+        Some(element) => element.descendants().filter_map(ElementRef::wrap).map(Some).collect(),
+        None => std::iter::once(None)
+            .chain(document.root_element().descendants().filter_map(ElementRef::wrap).map(Some))
+            .collect(),
+    }
+}
+
+/// Index of the `]` closing a predicate, skipping any inside quotes.
+fn closing_bracket(input: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ']') => return Some(i),
+            (None, _) => {}
+        }
+    }
+    None
+}
+
+fn parse_predicate(expression: &str) -> Result<Predicate> {
+    if let Ok(position) = expression.parse::<usize>() {
+        if position == 0 {
+            bail!("positions start at 1");
+        }
+        return Ok(Predicate::Position(position));
+    }
+
+    for (function, comparison) in [("contains(", Comparison::Contains), ("starts-with(", Comparison::StartsWith)] {
+        if let Some(arguments) = expression.strip_prefix(function).and_then(|rest| rest.strip_suffix(')')) {
+            let Some((operand, value)) = arguments.split_once(',') else {
+                bail!("{}) takes two arguments", function);
+            };
+            return Ok(Predicate::Compare {
+                operand: parse_operand(operand.trim())?,
+                comparison,
+                value: parse_literal(value.trim())?,
+            });
+        }
+    }
+
+    // The operand never contains '=', so the first one is the operator's
+    if let Some(index) = expression.find('=') {
+        let (operand, comparison) = match expression[..index].strip_suffix('!') {
+            Some(operand) => (operand, Comparison::NotEquals),
+            None => (&expression[..index], Comparison::Equals),
+        };
+        return Ok(Predicate::Compare {
+            operand: parse_operand(operand.trim())?,
+            comparison,
+            value: parse_literal(expression[index + 1..].trim())?,
+        });
+    }
+
+    Ok(Predicate::Exists(parse_operand(expression)?))
+}
+
+fn parse_operand(operand: &str) -> Result<Operand> {
+    match operand {
+        "text()" => Ok(Operand::Text),
+        "." => Ok(Operand::StringValue),
+        operand => match operand.strip_prefix('@') {
+            Some(name) if !name.is_empty() => Ok(Operand::Attribute(name.to_string())),
+            _ => bail!("unsupported predicate operand '{}'", operand),
+        },
+    }
+}
+
+fn parse_literal(literal: &str) -> Result<String> {
+    literal
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+        .or_else(|| literal.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')))
+        .map(str::to_string)
+        .with_context(|| format!("expected a quoted string, got '{}'", literal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"
+        <div id="list">
+          <ul>
+            <li><a href="/a" class="item">First</a></li>
+            <li><a href="/b" class="item featured">Second <b>pick</b></a></li>
+          </ul>
+          <ul>
+            <li><a href="https://other.example/c">Third</a></li>
+          </ul>
+        </div>
+    "#;
+
+    fn select(path: &str) -> Vec<String> {
+        let document = Html::parse_document(HTML);
+        XPath::parse(path)
+            .unwrap()
+            .select(&document)
+            .iter()
+            .map(|element| element.text().collect::<String>())
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(select("//a"), vec!["First", "Second pick", "Third"]);
+        assert_eq!(select("/html/body/div/ul/li/a[@class='item']"), vec!["First"]);
+        assert_eq!(select("//li[1]/a"), vec!["First", "Third"]);
+        assert_eq!(select("//a[contains(., 'pick')]"), vec!["Second pick"]);
+        assert_eq!(select("//a[text()='Second ']"), vec!["Second pick"]);
+        assert_eq!(select("//a[starts-with(@href, 'https:')]"), vec!["Third"]);
+        assert_eq!(select("//a[@class != 'item'][@class]"), vec!["Second pick"]);
+        assert_eq!(select("//b/../.."), vec!["Second pick"]);
+        assert_eq!(select("//ul[2]//*"), vec!["Third", "Third"]);
+        assert!(select("//table").is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(XPath::parse("a/b").is_err());
+        assert!(XPath::parse("//a[").is_err());
+        assert!(XPath::parse("//a[0]").is_err());
+        assert!(XPath::parse("//a/@href").is_err());
+        assert!(XPath::parse("//a[contains(@href)]").is_err());
+        assert!(XPath::parse("//a[@href=unquoted]").is_err());
+        assert!(XPath::parse("//").is_err());
+    }
+}